@@ -1,8 +1,19 @@
 pub mod canonicalize;
 pub mod compiledb;
+pub mod config_error;
 pub mod configfile;
 pub mod cparse;
+pub mod depfile;
 pub mod error;
 pub mod gn;
 pub mod graph;
+pub mod include_path;
+pub mod include_trace;
+pub mod lexer;
+pub mod ninja_deps;
 pub mod path_mapper;
+pub mod preprocessor;
+pub mod scan_cache;
+#[cfg(test)]
+mod test_util;
+pub mod unresolved;
@@ -1,42 +1,352 @@
-use std::borrow::Borrow;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::ffi::OsString;
+use std::fmt::Display;
 use std::fs::canonicalize;
-use std::hash::Hash;
-use std::path::{Path, PathBuf};
-use std::sync::{Arc, LazyLock, RwLock};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::RwLock;
 
-static PATH_CACHE: LazyLock<Arc<RwLock<HashMap<PathBuf, Option<PathBuf>>>>> =
-    LazyLock::new(|| Default::default());
+/// Maximum number of symlinks followed while resolving a single path
+const MAX_SYMLINKS: usize = 40;
+
+/// How paths are canonicalized, which determines the names of graph nodes.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
+pub enum SymlinkMode {
+    /// Resolve all symlinks (physical paths)
+    #[default]
+    Resolve,
+
+    /// Only make paths absolute and resolve `.` and `..` lexically, leaving
+    /// symlinks alone.
+    ///
+    /// Note that `..` following a symlink is resolved relative to the link,
+    /// not to its target.
+    Lexical,
+
+    /// Like [`SymlinkMode::Lexical`], however symlinks located below any of
+    /// the given roots are resolved.
+    ResolveUnder(Vec<PathBuf>),
+}
+
+/// Make `path` absolute and resolve `.` and `..` components without
+/// accessing the filesystem.
+///
+/// ```
+/// # use std::path::Path;
+/// # use include_graph::dependencies::canonicalize::normalize_lexically;
+/// assert_eq!(
+///     normalize_lexically(Path::new("/a/./b/../c")).unwrap(),
+///     Path::new("/a/c")
+/// );
+/// assert_eq!(normalize_lexically(Path::new("/..")).unwrap(), Path::new("/"));
+/// ```
+pub fn normalize_lexically(path: &Path) -> Result<PathBuf, std::io::Error> {
+    let mut result = PathBuf::new();
+    for component in std::path::absolute(path)?.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                result.pop();
+            }
+            other => result.push(other),
+        }
+    }
+    Ok(result)
+}
+
+/// Normalize `path` lexically, resolving symlinks that are located below any of `roots`.
+fn resolve_under(path: &Path, roots: &[PathBuf]) -> Result<PathBuf, std::io::Error> {
+    let mut pending = normalize_lexically(path)?
+        .components()
+        .map(|c| c.as_os_str().to_owned())
+        .collect::<VecDeque<OsString>>();
+    let mut result = PathBuf::new();
+    let mut followed = 0;
+
+    while let Some(component) = pending.pop_front() {
+        result.push(component);
+
+        let is_below_root = roots
+            .iter()
+            .any(|root| result.starts_with(root) && result != *root);
+        if !is_below_root || !result.is_symlink() {
+            continue;
+        }
+
+        followed += 1;
+        if followed > MAX_SYMLINKS {
+            return Err(std::io::Error::other(format!(
+                "Too many levels of symbolic links in {:?}",
+                path
+            )));
+        }
+
+        // continue with the link target, followed by the remaining components
+        let mut target = result
+            .parent()
+            .unwrap_or(Path::new("/"))
+            .join(result.read_link()?);
+        target.extend(pending.iter());
+
+        pending = normalize_lexically(&target)?
+            .components()
+            .map(|c| c.as_os_str().to_owned())
+            .collect();
+        result.clear();
+    }
+
+    Ok(result)
+}
+
+/// Hit/miss counters of a [`PathResolver`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ResolverStats {
+    pub hits: usize,
+    pub misses: usize,
+
+    /// Number of currently cached paths
+    pub entries: usize,
+}
+
+impl Display for ResolverStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} hits, {} misses, {} cached paths",
+            self.hits, self.misses, self.entries
+        )
+    }
+}
 
 /// Wrapper around [`std::fs::canonicalize`] that caches already canonicalized
-/// entries globally (often the case when dealing with includes).
+/// entries (often the case when dealing with includes).
 ///
 /// This is done due to the operation being inherently slow: It has to walk the
 /// entire parent directory tree (especially expensive on FUSE-mounted virtual
 /// repository filesystems).
-pub fn canonicalize_cached<P>(path: P) -> Result<Option<PathBuf>, std::io::Error>
-where
-    P: AsRef<Path>,
-    PathBuf: Borrow<P>,
-    P: Hash + Eq,
-{
-    {
-        // First, try the cache ...
-        let cache = PATH_CACHE.read().unwrap();
-        if let Some(cached) = cache.get(&path) {
-            return Ok(cached.clone());
-        }
-    }
-
-    // ... then look it up ourselves.
-    let result = if path.as_ref().exists() {
-        Some(canonicalize(&path)?)
-    } else {
-        None
-    };
-
-    let mut cache = PATH_CACHE.write().unwrap();
-    cache.insert(path.as_ref().to_path_buf(), result.clone());
+///
+/// Cached results are not updated when the filesystem changes. Long-lived
+/// resolvers should [`invalidate`](PathResolver::invalidate) paths that were
+/// created, deleted or moved.
+///
+/// Files below generated roots (build output directories) may not exist yet.
+/// They are still found by [`resolve`](PathResolver::resolve), as virtual paths.
+#[derive(Debug, Default)]
+pub struct PathResolver {
+    mode: RwLock<SymlinkMode>,
+    cache: RwLock<HashMap<PathBuf, Option<PathBuf>>>,
+    generated_roots: RwLock<Vec<PathBuf>>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
 
-    Ok(result)
+impl PathResolver {
+    pub fn new(mode: SymlinkMode) -> Self {
+        Self {
+            mode: RwLock::new(mode),
+            ..Default::default()
+        }
+    }
+
+    /// How symlinks are treated
+    pub fn mode(&self) -> SymlinkMode {
+        self.mode.read().unwrap().clone()
+    }
+
+    /// Change how symlinks are treated.
+    ///
+    /// Changing the mode clears cached results.
+    pub fn set_mode(&self, mode: SymlinkMode) {
+        let mut current = self.mode.write().unwrap();
+        if *current != mode {
+            *current = mode;
+            self.cache.write().unwrap().clear();
+        }
+    }
+
+    /// Canonicalize `path` according to the [`SymlinkMode`].
+    ///
+    /// Paths that do not exist result in `None`.
+    pub fn canonicalize<P: AsRef<Path>>(&self, path: P) -> Result<Option<PathBuf>, std::io::Error> {
+        let path = path.as_ref();
+
+        {
+            // First, try the cache ...
+            let cache = self.cache.read().unwrap();
+            if let Some(cached) = cache.get(path) {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(cached.clone());
+            }
+        }
+
+        // ... then look it up ourselves.
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let result = if path.exists() {
+            Some(match *self.mode.read().unwrap() {
+                SymlinkMode::Resolve => canonicalize(path)?,
+                SymlinkMode::Lexical => normalize_lexically(path)?,
+                SymlinkMode::ResolveUnder(ref roots) => resolve_under(path, roots)?,
+            })
+        } else {
+            None
+        };
+
+        let mut cache = self.cache.write().unwrap();
+        cache.insert(path.to_path_buf(), result.clone());
+
+        Ok(result)
+    }
+
+    /// Declare a directory containing generated files, which may not exist yet.
+    pub fn add_generated_root<P: AsRef<Path>>(&self, root: P) -> Result<(), std::io::Error> {
+        let root = normalize_lexically(root.as_ref())?;
+        let mut roots = self.generated_roots.write().unwrap();
+        if !roots.contains(&root) {
+            roots.push(root);
+        }
+        Ok(())
+    }
+
+    /// Directories declared via [`add_generated_root`](PathResolver::add_generated_root)
+    pub fn generated_roots(&self) -> Vec<PathBuf> {
+        self.generated_roots.read().unwrap().clone()
+    }
+
+    /// The lexically normalized `path` if it is below a generated root.
+    ///
+    /// Whether the path exists is not checked.
+    pub fn generated<P: AsRef<Path>>(&self, path: P) -> Option<PathBuf> {
+        let path = normalize_lexically(path.as_ref()).ok()?;
+        self.generated_roots
+            .read()
+            .unwrap()
+            .iter()
+            .any(|root| path.starts_with(root))
+            .then_some(path)
+    }
+
+    /// Canonicalize `path`, falling back to a virtual path for files below a
+    /// generated root that do not exist.
+    pub fn resolve<P: AsRef<Path>>(&self, path: P) -> Option<PathBuf> {
+        match self.canonicalize(path.as_ref()) {
+            Ok(Some(path)) => Some(path),
+            _ => self.generated(path),
+        }
+    }
+
+    /// Whether `path` is a virtual path, i.e. a (not yet) generated file.
+    pub fn is_virtual(&self, path: &Path) -> bool {
+        self.generated(path).is_some() && !path.exists()
+    }
+
+    /// Forget the cached result for `path`.
+    pub fn invalidate<P: AsRef<Path>>(&self, path: P) {
+        self.cache.write().unwrap().remove(path.as_ref());
+    }
+
+    /// Forget cached results for `root` and everything below it, both by
+    /// the looked up and by the canonical path.
+    pub fn invalidate_subtree<P: AsRef<Path>>(&self, root: P) {
+        let root = root.as_ref();
+        self.cache.write().unwrap().retain(|path, canonical| {
+            let below_root = path.starts_with(root)
+                || normalize_lexically(path).is_ok_and(|p| p.starts_with(root))
+                || canonical.as_ref().is_some_and(|c| c.starts_with(root));
+            !below_root
+        });
+    }
+
+    /// Forget all cached results.
+    pub fn clear(&self) {
+        self.cache.write().unwrap().clear();
+    }
+
+    pub fn stats(&self) -> ResolverStats {
+        ResolverStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self.cache.read().unwrap().len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dependencies::test_util::TempTree;
+
+    #[test]
+    fn test_invalidate() {
+        let dir = TempTree::new(&[]);
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        let file = dir.join("sub/a.h");
+
+        let resolver = PathResolver::default();
+        assert_eq!(resolver.canonicalize(&file).unwrap(), None);
+        assert_eq!(resolver.canonicalize(&file).unwrap(), None);
+        assert_eq!(
+            resolver.stats(),
+            ResolverStats {
+                hits: 1,
+                misses: 1,
+                entries: 1
+            }
+        );
+
+        // stale until invalidated
+        std::fs::write(&file, "").unwrap();
+        assert_eq!(resolver.canonicalize(&file).unwrap(), None);
+        resolver.invalidate(&file);
+        assert!(resolver.canonicalize(&file).unwrap().is_some());
+
+        std::fs::remove_file(&file).unwrap();
+        resolver.canonicalize(dir.join("sub/../sub/a.h")).unwrap();
+        resolver.canonicalize(&dir).unwrap();
+        resolver.invalidate_subtree(dir.join("sub"));
+        assert_eq!(resolver.stats().entries, 1);
+        assert_eq!(resolver.canonicalize(&file).unwrap(), None);
+    }
+
+    #[test]
+    fn test_generated_roots() {
+        let dir = TempTree::new(&[("src/a.h", "")]);
+
+        let resolver = PathResolver::default();
+        resolver.add_generated_root(dir.join("out/gen")).unwrap();
+        resolver.add_generated_root(dir.join("out/./gen")).unwrap();
+        assert_eq!(resolver.generated_roots(), vec![dir.join("out/gen")]);
+
+        let generated = dir.join("out/gen/proto/x.pb.h");
+        assert_eq!(
+            resolver.resolve(dir.join("out/gen/sub/../proto/x.pb.h")),
+            Some(generated.clone())
+        );
+        assert!(resolver.is_virtual(&generated));
+        assert_eq!(resolver.resolve(dir.join("src/missing.h")), None);
+        assert_eq!(
+            resolver.resolve(dir.join("src/a.h")),
+            Some(dir.join("src/a.h"))
+        );
+        assert!(!resolver.is_virtual(&dir.join("src/a.h")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_resolve_under() {
+        let dir = TempTree::new(&[("real/inc/a.h", "")]);
+        std::fs::create_dir_all(dir.join("root")).unwrap();
+        std::fs::create_dir_all(dir.join("other")).unwrap();
+        std::os::unix::fs::symlink("../real", dir.join("root/link")).unwrap();
+        std::os::unix::fs::symlink("../real", dir.join("other/link")).unwrap();
+
+        let roots = [dir.join("root")];
+        assert_eq!(
+            resolve_under(&dir.join("root/link/inc/./a.h"), &roots).unwrap(),
+            dir.join("real/inc/a.h")
+        );
+        assert_eq!(
+            resolve_under(&dir.join("other/link/inc/a.h"), &roots).unwrap(),
+            dir.join("other/link/inc/a.h")
+        );
+    }
 }
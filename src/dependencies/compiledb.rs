@@ -1,16 +1,35 @@
+use glob::{MatchOptions, Pattern};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
-use std::path::PathBuf;
-use tracing::debug;
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
 
-use super::canonicalize::canonicalize_cached;
+use super::canonicalize::{normalize_lexically, PathResolver};
+use super::cparse::{FileType, FileTypes};
 use super::error::Error;
+use super::include_path::{IncludeDirectory, IncludeDirectoryKind};
+use super::preprocessor::DefineFlag;
 
 #[derive(Debug, PartialEq, PartialOrd, Hash, Serialize, Deserialize)]
 pub struct SourceFileEntry {
     pub file_path: PathBuf,
-    pub include_directories: Vec<PathBuf>,
+
+    /// Include directories, in command line order
+    pub include_directories: Vec<IncludeDirectory>,
+
+    /// `-D` and `-U` flags, in command line order
+    pub defines: Vec<DefineFlag>,
+
+    /// Headers included before the source via `-include`
+    pub forced_includes: Vec<PathBuf>,
+
+    /// Absolute path of the compilation output, if known
+    pub output: Option<PathBuf>,
+
+    /// Compiler arguments, with response files expanded
+    pub arguments: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -31,53 +50,662 @@ pub struct CompileCommandsEntry {
     pub output: Option<String>,
 }
 
-impl TryFrom<CompileCommandsEntry> for SourceFileEntry {
-    type Error = Error;
+/// Replaces the prefix of Windows paths in a compile database, like
+/// `C:\src => /mnt/src`, so that they resolve against a local checkout.
+///
+/// Prefixes match case-insensitively and regardless of the kind of slashes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WindowsPathRemap {
+    pub from: String,
+    pub to: String,
+}
+
+/// Replaces a path prefix in a compile database generated on another machine,
+/// like `/home/ci/work => /home/me/src`.
+///
+/// Prefixes only match whole path components.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathRewrite {
+    pub from: String,
+    pub to: String,
+}
+
+impl PathRewrite {
+    /// Rewrite a path, or the path in an argument like `-I/path`,
+    /// `--sysroot=/path` or `@/path/file.rsp`.
+    ///
+    /// Returns `None` if the prefix does not match.
+    ///
+    /// ```
+    /// # use include_graph::dependencies::compiledb::PathRewrite;
+    /// let rewrite = PathRewrite {
+    ///     from: "/home/ci/work".into(),
+    ///     to: "/src".into(),
+    /// };
+    /// assert_eq!(rewrite.apply("/home/ci/work/a.cpp"), Some("/src/a.cpp".into()));
+    /// assert_eq!(rewrite.apply("-I/home/ci/work/inc"), Some("-I/src/inc".into()));
+    /// assert_eq!(rewrite.apply("--sysroot=/home/ci/work"), Some("--sysroot=/src".into()));
+    /// assert_eq!(rewrite.apply("/home/ci/workspace"), None);
+    /// assert_eq!(rewrite.apply("-DDIR=x/home/ci/work"), None);
+    /// ```
+    pub fn apply(&self, arg: &str) -> Option<String> {
+        let from = match self.from.trim_end_matches('/') {
+            "" => "/",
+            from => from,
+        };
+
+        arg.match_indices(from)
+            .map(|(index, _)| index)
+            .find(|index| {
+                let (head, rest) = (&arg[..*index], &arg[index + from.len()..]);
+                let at_boundary = from.ends_with('/') || rest.is_empty() || rest.starts_with('/');
+                // the path is the whole argument or the value of a (joined) flag
+                let is_path = head.is_empty()
+                    || head.ends_with(['=', '@'])
+                    || (head.starts_with('-')
+                        && head.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'));
+                at_boundary && is_path
+            })
+            .map(|index| {
+                let to = match from.ends_with('/') {
+                    true => format!("{}/", self.to.trim_end_matches('/')),
+                    false => self.to.trim_end_matches('/').to_string(),
+                };
+                format!("{}{}{}", &arg[..index], to, &arg[index + from.len()..])
+            })
+    }
+}
+
+/// Apply the first matching rewrite to `arg`
+fn rewrite(arg: String, rewrites: &[PathRewrite]) -> String {
+    rewrites.iter().find_map(|r| r.apply(&arg)).unwrap_or(arg)
+}
+
+/// How compile database entries are interpreted
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompileDbOptions {
+    /// Remapping of Windows paths, the first matching one is used
+    pub windows_remaps: Vec<WindowsPathRemap>,
+
+    /// Prefix rewrites of paths and arguments, the first matching one is used
+    pub rewrites: Vec<PathRewrite>,
+
+    /// Fail on invalid entries instead of skipping them
+    pub strict: bool,
+
+    /// Only entries matching all filters are loaded
+    pub filters: Vec<EntryFilter>,
+
+    /// What to do with several entries for the same source file
+    pub duplicates: DuplicatePolicy,
+}
+
+/// Selects the compile database entries to load
+#[derive(Debug, Clone, PartialEq)]
+pub enum EntryFilter {
+    /// The absolute path of the source matches a glob
+    FileMatches(String),
+
+    /// The absolute path of the output matches a glob
+    OutputMatches(String),
+
+    /// One of the arguments is exactly the given flag
+    ArgContains(String),
+}
+
+/// How entries for the same source file (e.g. compiled by several toolchains)
+/// are handled
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum DuplicatePolicy {
+    /// Keep all entries
+    #[default]
+    Keep,
+
+    /// Only keep the first entry
+    First,
+
+    /// Keep the first entry, adding the include directories and forced
+    /// includes of all others
+    Union,
+
+    /// Keep the first entry with an output below the given directory (or the
+    /// first entry if there is none)
+    Output(String),
+}
+
+/// An [`EntryFilter`] with its glob compiled
+enum CompiledFilter<'a> {
+    File(Pattern),
+    Output(Pattern),
+    Arg(&'a str),
+}
+
+impl<'a> CompiledFilter<'a> {
+    fn new(filter: &'a EntryFilter) -> Result<Self, Error> {
+        let pattern = |pattern: &String| {
+            Pattern::new(pattern).map_err(|source| Error::InvalidPattern {
+                source,
+                pattern: pattern.clone(),
+            })
+        };
 
-    fn try_from(value: CompileCommandsEntry) -> Result<Self, Self::Error> {
+        Ok(match filter {
+            EntryFilter::FileMatches(p) => CompiledFilter::File(pattern(p)?),
+            EntryFilter::OutputMatches(p) => CompiledFilter::Output(pattern(p)?),
+            EntryFilter::ArgContains(flag) => CompiledFilter::Arg(flag),
+        })
+    }
+
+    fn matches(&self, entry: &SourceFileEntry) -> bool {
+        // like file system globs, `*` does not match `/`
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..Default::default()
+        };
+
+        match self {
+            CompiledFilter::File(p) => p.matches_path_with(&entry.file_path, options),
+            CompiledFilter::Output(p) => entry
+                .output
+                .as_ref()
+                .is_some_and(|o| p.matches_path_with(o, options)),
+            CompiledFilter::Arg(flag) => entry.arguments.iter().any(|a| a == flag),
+        }
+    }
+}
+
+/// Apply a [`DuplicatePolicy`] to entries, keeping their order
+fn deduplicate(entries: Vec<SourceFileEntry>, policy: &DuplicatePolicy) -> Vec<SourceFileEntry> {
+    let output_dir = match policy {
+        DuplicatePolicy::Keep => return entries,
+        DuplicatePolicy::Output(dir) => normalize_lexically(Path::new(dir)).ok(),
+        _ => None,
+    };
+    let in_output_dir = |entry: &SourceFileEntry| match (&entry.output, &output_dir) {
+        (Some(output), Some(dir)) => output.starts_with(dir),
+        _ => false,
+    };
+
+    let mut result: Vec<SourceFileEntry> = Vec::new();
+    let mut positions = HashMap::new();
+
+    for entry in entries {
+        let existing = match positions.get(&entry.file_path) {
+            Some(index) => &mut result[*index],
+            None => {
+                positions.insert(entry.file_path.clone(), result.len());
+                result.push(entry);
+                continue;
+            }
+        };
+
+        debug!(target: "compile-db", "Duplicate entry for {:?}", entry.file_path);
+        match policy {
+            DuplicatePolicy::Union => {
+                for dir in entry.include_directories {
+                    if !existing.include_directories.contains(&dir) {
+                        existing.include_directories.push(dir);
+                    }
+                }
+                for path in entry.forced_includes {
+                    if !existing.forced_includes.contains(&path) {
+                        existing.forced_includes.push(path);
+                    }
+                }
+            }
+            DuplicatePolicy::Output(_) if !in_output_dir(existing) && in_output_dir(&entry) => {
+                *existing = entry;
+            }
+            _ => {}
+        }
+    }
+
+    result
+}
+
+/// The output of a compilation, as given by `-o` (or `/Fo` for MSVC)
+fn output_argument(args: &[String], msvc: bool) -> Option<&str> {
+    args.iter()
+        .enumerate()
+        .find_map(|(index, arg)| match arg.as_str() {
+            "-o" => args.get(index + 1).map(String::as_str),
+            _ if msvc => arg
+                .strip_prefix("/Fo")
+                .or_else(|| arg.strip_prefix("-Fo"))
+                .map(|o| o.trim_start_matches(':'))
+                .filter(|o| !o.is_empty()),
+            _ => None,
+        })
+}
+
+/// Response files including each other more deeply than this are assumed to
+/// be recursive
+const MAX_RESPONSE_FILE_DEPTH: usize = 16;
+
+/// Interprets the command line of a compile database entry
+struct CommandLine<'a> {
+    /// Describes the entry in errors
+    entry: String,
+    windows: bool,
+    options: &'a CompileDbOptions,
+}
+
+impl CommandLine<'_> {
+    fn error(&self, message: String) -> Error {
+        Error::InvalidCompileCommand {
+            entry: self.entry.clone(),
+            message,
+        }
+    }
+
+    /// Convert a path of the entry to a local one
+    fn local(&self, path: &str) -> String {
+        match self.windows {
+            true => windows_to_local(path, &self.options.windows_remaps),
+            false => path.to_string(),
+        }
+    }
+
+    /// Split a command (or response file content) into rewritten arguments
+    fn split(&self, command: &str, origin: &str) -> Result<Vec<String>, Error> {
+        let args = match self.windows {
+            true => Some(split_windows_command(command)),
+            false => shlex::split(command),
+        }
+        .ok_or_else(|| self.error(format!("cannot split {} (unbalanced quotes?)", origin)))?;
+
+        Ok(args
+            .into_iter()
+            .map(|arg| rewrite(arg, &self.options.rewrites))
+            .collect())
+    }
+
+    /// Replace `@file` arguments with the arguments in `file`, recursively.
+    ///
+    /// Relative response file paths are relative to `start_dir`.
+    fn expand_response_files(
+        &self,
+        args: Vec<String>,
+        start_dir: &Path,
+        depth: usize,
+    ) -> Result<Vec<String>, Error> {
+        let mut expanded = Vec::with_capacity(args.len());
+
+        for arg in args {
+            let path = match arg.strip_prefix('@') {
+                Some(path) => start_dir.join(self.local(path)),
+                None => {
+                    expanded.push(arg);
+                    continue;
+                }
+            };
+            if depth >= MAX_RESPONSE_FILE_DEPTH {
+                return Err(self.error(format!(
+                    "response files nested too deeply at {}",
+                    path.display()
+                )));
+            }
+
+            let content =
+                std::fs::read_to_string(&path).map_err(|source| Error::ResponseFileError {
+                    source,
+                    entry: self.entry.clone(),
+                    path: path.clone(),
+                })?;
+            let args = self.split(&content, &format!("response file {}", path.display()))?;
+            expanded.extend(self.expand_response_files(args, start_dir, depth + 1)?);
+        }
+
+        Ok(expanded)
+    }
+}
+
+/// Whether `path` is an absolute Windows path (`C:\foo` or `\\server\foo`)
+fn is_windows_path(path: &str) -> bool {
+    match path.as_bytes() {
+        [drive, b':', b'\\' | b'/', ..] => drive.is_ascii_alphabetic(),
+        [b'\\', b'\\', ..] => true,
+        _ => false,
+    }
+}
+
+/// Convert a path of a Windows compile database to a local path using forward
+/// slashes.
+///
+/// ```
+/// # use include_graph::dependencies::compiledb::{windows_to_local, WindowsPathRemap};
+/// let remaps = [WindowsPathRemap {
+///     from: "C:\\src".into(),
+///     to: "/mnt/src".into(),
+/// }];
+/// assert_eq!(windows_to_local("c:\\Src\\a\\b.h", &remaps), "/mnt/src/a/b.h");
+/// assert_eq!(windows_to_local("C:\\srcfoo\\b.h", &remaps), "C:/srcfoo/b.h");
+/// assert_eq!(windows_to_local("..\\b.h", &remaps), "../b.h");
+/// ```
+pub fn windows_to_local(path: &str, remaps: &[WindowsPathRemap]) -> String {
+    let path = path.replace('\\', "/");
+
+    for remap in remaps {
+        let from = remap.from.replace('\\', "/");
+        let from = from.trim_end_matches('/');
+        let rest = match path.get(..from.len()) {
+            Some(prefix) if prefix.eq_ignore_ascii_case(from) => &path[from.len()..],
+            _ => continue,
+        };
+        if rest.is_empty() || rest.starts_with('/') {
+            return format!("{}{}", remap.to.trim_end_matches('/'), rest);
+        }
+    }
+
+    path
+}
+
+/// Split a command line the way Windows programs do (`CommandLineToArgvW`).
+///
+/// Backslashes are only special before a double quote.
+///
+/// ```
+/// # use include_graph::dependencies::compiledb::split_windows_command;
+/// assert_eq!(
+///     split_windows_command(r#"cl.exe /IC:\inc "/IC:\my dir" /DX=\"a\" /c a.cpp"#),
+///     vec!["cl.exe", r"/IC:\inc", r"/IC:\my dir", r#"/DX="a""#, "/c", "a.cpp"]
+/// );
+/// ```
+pub fn split_windows_command(command: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_arg = false;
+    let mut quoted = false;
+    let mut chars = command.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                let mut count = 1;
+                while chars.peek() == Some(&'\\') {
+                    chars.next();
+                    count += 1;
+                }
+                if chars.peek() == Some(&'"') {
+                    // 2n (+1) backslashes before a quote are n backslashes (and a literal quote)
+                    current.extend(std::iter::repeat_n('\\', count / 2));
+                    if count % 2 == 1 {
+                        current.push('"');
+                        chars.next();
+                    }
+                } else {
+                    current.extend(std::iter::repeat_n('\\', count));
+                }
+                in_arg = true;
+            }
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                current.push('"');
+            }
+            '"' => {
+                quoted = !quoted;
+                in_arg = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_arg {
+                    args.push(std::mem::take(&mut current));
+                    in_arg = false;
+                }
+            }
+            c => {
+                current.push(c);
+                in_arg = true;
+            }
+        }
+    }
+
+    if in_arg {
+        args.push(current);
+    }
+    args
+}
+
+/// A compiler flag relevant for include processing
+#[derive(Debug, Clone, PartialEq)]
+enum CompilerFlag<'a> {
+    IncludeDirectory(IncludeDirectoryKind, &'a str),
+    ForcedInclude(&'a str),
+    Sysroot(&'a str),
+    Define(DefineFlag),
+}
+
+/// Flags taking an argument either joined (`-Ifoo`) or as the next argument (`-I foo`).
+///
+/// Flags that are prefixes of other flags are listed after them. Some flags
+/// are irrelevant for include processing, however are listed so that they
+/// (and their argument) are not mistaken for one of the others.
+const ARGUMENT_FLAGS: &[&str] = &[
+    "--include-directory=",
+    "--include-directory",
+    "--sysroot=",
+    "--sysroot",
+    "-isysroot",
+    "-iquote",
+    "-isystem",
+    "-imsvc",
+    "-idirafter",
+    "-include-pch",
+    "-imacros",
+    "-include",
+    "-I",
+    "-D",
+    "-U",
+];
+
+/// Additional flags of MSVC (`cl`/`clang-cl`), which may start with `/` or `-`
+const MSVC_ARGUMENT_FLAGS: &[&str] =
+    &["/external:I", "-external:I", "/FI", "-FI", "/I", "/D", "/U"];
+
+/// Extract the flags relevant for include processing from compiler arguments.
+///
+/// `msvc` enables MSVC style flags (like `/I` and `/FI`).
+fn parse_flags(args: &[String], msvc: bool) -> Vec<CompilerFlag<'_>> {
+    let mut flags = Vec::new();
+    let mut args = args.iter();
+    let msvc_flags = if msvc { MSVC_ARGUMENT_FLAGS } else { &[] };
+
+    while let Some(arg) = args.next() {
+        let (flag, value) = match ARGUMENT_FLAGS
+            .iter()
+            .chain(msvc_flags)
+            .find(|f| arg.starts_with(*f))
+        {
+            Some(flag) if arg == flag => match args.next() {
+                Some(value) => (*flag, value.as_str()),
+                None => continue,
+            },
+            // `--include-directory`/`--sysroot` are only joined with a `=`
+            Some(&"--include-directory") | Some(&"--sysroot") => continue,
+            Some(flag) => (*flag, &arg[flag.len()..]),
+            None => continue,
+        };
+
+        flags.push(match flag.trim_end_matches('=') {
+            "--include-directory" | "-I" | "/I" => {
+                CompilerFlag::IncludeDirectory(IncludeDirectoryKind::Regular, value)
+            }
+            "-iquote" => CompilerFlag::IncludeDirectory(IncludeDirectoryKind::Quote, value),
+            "-isystem" | "-imsvc" | "/external:I" | "-external:I" => {
+                CompilerFlag::IncludeDirectory(IncludeDirectoryKind::System, value)
+            }
+            "-idirafter" => CompilerFlag::IncludeDirectory(IncludeDirectoryKind::After, value),
+            "-include" | "/FI" | "-FI" => CompilerFlag::ForcedInclude(value),
+            "--sysroot" | "-isysroot" => CompilerFlag::Sysroot(value),
+            // a precompiled header, or a header only read for its macros
+            "-include-pch" | "-imacros" => continue,
+            "-D" | "/D" => CompilerFlag::Define(DefineFlag::define(value)),
+            _ => CompilerFlag::Define(DefineFlag::Undefine(value.into())),
+        });
+    }
+
+    flags
+}
+
+/// Make a path given on the command line absolute.
+///
+/// Paths starting with `=` are relative to the sysroot (if one is set).
+fn absolute_path(
+    path: &str,
+    start_dir: &Path,
+    sysroot: Option<&Path>,
+    resolver: &PathResolver,
+) -> Option<PathBuf> {
+    let path = match (path.strip_prefix('='), sysroot) {
+        (Some(rest), Some(sysroot)) => sysroot.join(rest.trim_start_matches('/')),
+        _ => PathBuf::from(path),
+    };
+
+    if path.is_relative() {
+        // include directories of generated files may not exist yet
+        resolver.resolve(start_dir.join(path))
+    } else {
+        Some(path)
+    }
+}
+
+impl SourceFileEntry {
+    /// Interpret a compile database entry.
+    ///
+    /// The `rewrites` of `options` are applied to the directory, file and all
+    /// arguments (including the output) first. Response files (`@file`) are
+    /// expanded relative to the entry directory.
+    ///
+    /// Entries with a Windows `directory` use Windows paths, command line
+    /// splitting and MSVC flags. Their paths are converted to local ones
+    /// using the `windows_remaps` of `options`.
+    pub fn try_from(
+        value: CompileCommandsEntry,
+        options: &CompileDbOptions,
+        resolver: &PathResolver,
+    ) -> Result<Self, Error> {
         // trace!("Generating SourceFileEntry {:#?}", value);
 
-        let start_dir = PathBuf::from(value.directory);
+        let windows = is_windows_path(&value.directory);
+        let command_line = CommandLine {
+            entry: format!("{:?} in {:?}", value.file, value.directory),
+            windows,
+            options,
+        };
+        let local = |path: &str| command_line.local(path);
 
-        let source_file = PathBuf::from(value.file);
+        let args = match (value.arguments, value.command) {
+            (Some(args), _) => args
+                .into_iter()
+                .map(|arg| rewrite(arg, &options.rewrites))
+                .collect(),
+            (None, Some(command)) => command_line.split(&command, "command")?,
+            (None, None) => {
+                return Err(command_line.error("neither `arguments` nor `command` is set".into()))
+            }
+        };
+        let directory = rewrite(value.directory, &options.rewrites);
+        let file = rewrite(value.file, &options.rewrites);
+
+        let start_dir = PathBuf::from(local(&directory));
+        let args = command_line.expand_response_files(args, &start_dir, 0)?;
+
+        let output = value
+            .output
+            .map(|output| rewrite(output, &options.rewrites))
+            .or_else(|| output_argument(&args, windows).map(String::from))
+            .and_then(|output| normalize_lexically(&start_dir.join(local(&output))).ok());
+
+        let source_file = PathBuf::from(local(&file));
         let file_path = if source_file.is_relative() {
             start_dir.join(source_file)
         } else {
             source_file
         };
 
-        let file_path = canonicalize_cached(file_path)
-            .map_err(|source| Error::IOError {
+        let file_path = resolver
+            .canonicalize(&file_path)
+            .map_err(|source| Error::FileIOError {
                 source,
+                path: file_path.clone(),
                 message: "canonicalize",
             })?
-            .ok_or(Error::FileNotFound)?;
+            .ok_or_else(|| Error::CompileDbSourceNotFound {
+                entry: command_line.entry.clone(),
+            })?;
 
-        let args = value
-            .arguments
-            .unwrap_or_else(|| shlex::split(&value.command.unwrap()).unwrap());
+        let flags = parse_flags(&args, windows);
 
-        let include_directories = args
+        let sysroot = flags
             .iter()
-            .filter_map(|a| a.strip_prefix("-I"))
-            .map(PathBuf::from)
-            .filter_map(|p| {
-                if p.is_relative() {
-                    canonicalize_cached(start_dir.join(p)).ok()?
-                } else {
-                    Some(p)
+            .filter_map(|f| match f {
+                CompilerFlag::Sysroot(path) => {
+                    absolute_path(&local(path), &start_dir, None, resolver)
+                }
+                _ => None,
+            })
+            .next_back();
+
+        let mut include_directories = Vec::new();
+        let mut defines = Vec::new();
+        let mut forced_includes = Vec::new();
+
+        for flag in flags {
+            match flag {
+                CompilerFlag::IncludeDirectory(kind, path) => {
+                    if let Some(path) =
+                        absolute_path(&local(path), &start_dir, sysroot.as_deref(), resolver)
+                    {
+                        include_directories.push(IncludeDirectory { path, kind });
+                    }
                 }
+                CompilerFlag::ForcedInclude(path) => forced_includes.push(local(path)),
+                CompilerFlag::Define(flag) => defines.push(flag),
+                CompilerFlag::Sysroot(_) => {}
+            }
+        }
+
+        // default system directories of the sysroot are searched after `-isystem`
+        if let Some(ref sysroot) = sysroot {
+            for dir in ["usr/local/include", "usr/include"] {
+                include_directories.push(IncludeDirectory {
+                    path: sysroot.join(dir),
+                    kind: IncludeDirectoryKind::System,
+                });
+            }
+        }
+
+        // Forced includes are looked up in the working directory first, then
+        // in the include directories.
+        let forced_includes = forced_includes
+            .into_iter()
+            .filter_map(|path| {
+                std::iter::once(start_dir.as_path())
+                    .chain(include_directories.iter().map(|d| d.path.as_path()))
+                    .find_map(|dir| resolver.canonicalize(dir.join(&path)).ok()?)
+                    .or_else(|| {
+                        debug!(target: "compile-db", "Forced include {:?} not found", path);
+                        None
+                    })
             })
             .collect();
 
         Ok(SourceFileEntry {
             file_path,
             include_directories,
+            defines,
+            forced_includes,
+            output,
+            arguments: args,
         })
     }
 }
 
-pub fn parse_compile_database(path: &str) -> Result<Vec<SourceFileEntry>, Error> {
+pub fn parse_compile_database(
+    path: &str,
+    file_types: &FileTypes,
+    options: &CompileDbOptions,
+    resolver: &PathResolver,
+) -> Result<Vec<SourceFileEntry>, Error> {
     let mut file = File::open(path).map_err(|source| Error::FileIOError {
         source,
         path: path.into(),
@@ -95,22 +723,379 @@ pub fn parse_compile_database(path: &str) -> Result<Vec<SourceFileEntry>, Error>
     let raw_items: Vec<CompileCommandsEntry> =
         serde_json::from_str(&json_string).map_err(Error::JsonParseError)?;
 
-    Ok(raw_items
+    let filters = options
+        .filters
+        .iter()
+        .map(CompiledFilter::new)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let entries = raw_items
+        .into_iter()
+        .filter(|e| file_types.of(Path::new(&e.file)) != FileType::Unknown)
+        .map(|e| SourceFileEntry::try_from(e, options, resolver));
+
+    let entries = match options.strict {
+        true => entries.collect::<Result<Vec<_>, _>>()?,
+        false => entries
+            .inspect(|r| match r {
+                Err(e @ Error::CompileDbSourceNotFound { .. }) => {
+                    debug!(target: "compile-db", "Skipping entry: {}", e)
+                }
+                Err(e) => warn!(target: "compile-db", "Skipping entry: {}", e),
+                Ok(_) => {}
+            })
+            .filter_map(|r| r.ok())
+            .collect(),
+    };
+
+    let entries = entries
         .into_iter()
         .filter(|e| {
-            e.file.ends_with(".cpp")
-                || e.file.ends_with(".cc")
-                || e.file.ends_with(".cxx")
-                || e.file.ends_with(".c")
-                || e.file.ends_with(".h")
-                || e.file.ends_with(".hpp")
-        })
-        .map(SourceFileEntry::try_from)
-        .inspect(|r| {
-            if let Err(e) = r {
-                debug!(target: "compile-db", "Failed to parse: {:?}", e);
+            let selected = filters.iter().all(|f| f.matches(e));
+            if !selected {
+                debug!(target: "compile-db", "Entry for {:?} filtered out", e.file_path);
             }
+            selected
         })
-        .filter_map(|r| r.ok())
-        .collect())
+        .collect();
+
+    Ok(deduplicate(entries, &options.duplicates))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dependencies::test_util::TempTree;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_parse_flags() {
+        let args = args(
+            "clang++ -c -Ia -I b --include-directory=c --include-directory d -iquote e \
+             -isystemf -isystem g -idirafter h -include i.h --sysroot=/sr -isysroot /sr2 \
+             -include-pch foo.pch -imacros m.h -imacrosn.h -DX=1 -D Y -UZ -o out.o -Wall",
+        );
+
+        assert_eq!(
+            parse_flags(&args, false),
+            vec![
+                CompilerFlag::IncludeDirectory(IncludeDirectoryKind::Regular, "a"),
+                CompilerFlag::IncludeDirectory(IncludeDirectoryKind::Regular, "b"),
+                CompilerFlag::IncludeDirectory(IncludeDirectoryKind::Regular, "c"),
+                CompilerFlag::IncludeDirectory(IncludeDirectoryKind::Regular, "d"),
+                CompilerFlag::IncludeDirectory(IncludeDirectoryKind::Quote, "e"),
+                CompilerFlag::IncludeDirectory(IncludeDirectoryKind::System, "f"),
+                CompilerFlag::IncludeDirectory(IncludeDirectoryKind::System, "g"),
+                CompilerFlag::IncludeDirectory(IncludeDirectoryKind::After, "h"),
+                CompilerFlag::ForcedInclude("i.h"),
+                CompilerFlag::Sysroot("/sr"),
+                CompilerFlag::Sysroot("/sr2"),
+                CompilerFlag::Define(DefineFlag::Define {
+                    name: "X".into(),
+                    value: "1".into()
+                }),
+                CompilerFlag::Define(DefineFlag::Define {
+                    name: "Y".into(),
+                    value: "1".into()
+                }),
+                CompilerFlag::Define(DefineFlag::Undefine("Z".into())),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_flags_missing_argument() {
+        assert_eq!(parse_flags(&args("-I"), false), vec![]);
+        assert_eq!(parse_flags(&args("--sysrootfoo -isystem"), false), vec![]);
+    }
+
+    #[test]
+    fn test_parse_msvc_flags() {
+        let args = split_windows_command(
+            r#"cl.exe /c /IC:\a "/I" C:\b /external:IC:\c -imsvc C:\d /FIpch.h /DX=1 /UY /Fo:a.obj"#,
+        );
+
+        assert_eq!(
+            parse_flags(&args, true),
+            vec![
+                CompilerFlag::IncludeDirectory(IncludeDirectoryKind::Regular, r"C:\a"),
+                CompilerFlag::IncludeDirectory(IncludeDirectoryKind::Regular, r"C:\b"),
+                CompilerFlag::IncludeDirectory(IncludeDirectoryKind::System, r"C:\c"),
+                CompilerFlag::IncludeDirectory(IncludeDirectoryKind::System, r"C:\d"),
+                CompilerFlag::ForcedInclude("pch.h"),
+                CompilerFlag::Define(DefineFlag::Define {
+                    name: "X".into(),
+                    value: "1".into()
+                }),
+                CompilerFlag::Define(DefineFlag::Undefine("Y".into())),
+            ]
+        );
+        // `-imsvc` is also understood by clang
+        assert_eq!(
+            parse_flags(&args, false),
+            vec![CompilerFlag::IncludeDirectory(
+                IncludeDirectoryKind::System,
+                r"C:\d"
+            )]
+        );
+    }
+
+    #[test]
+    fn test_split_windows_command() {
+        assert_eq!(
+            split_windows_command(r#"  a\\\\"b c"  "x""y" \\\"z d\\e  "" "#),
+            vec![r"a\\b c", r#"x"y"#, r#"\"z"#, r"d\\e", ""]
+        );
+    }
+
+    #[test]
+    fn test_windows_entry() {
+        let dir = TempTree::new(&[("src/a.cpp", "")]);
+        std::fs::create_dir_all(dir.join("src/inc")).unwrap();
+
+        let options = CompileDbOptions {
+            windows_remaps: vec![WindowsPathRemap {
+                from: r"C:\Work\".into(),
+                to: dir.to_string_lossy().into(),
+            }],
+            ..Default::default()
+        };
+        let entry = SourceFileEntry::try_from(
+            CompileCommandsEntry {
+                directory: r"c:\work\src".into(),
+                file: "a.cpp".into(),
+                arguments: None,
+                command: Some(r#"cl.exe /c /Iinc "/IC:\Work\src\missing" a.cpp"#.into()),
+                output: None,
+            },
+            &options,
+            &PathResolver::default(),
+        )
+        .unwrap();
+
+        assert_eq!(entry.file_path, dir.join("src/a.cpp"));
+        assert_eq!(
+            entry
+                .include_directories
+                .iter()
+                .map(|d| d.path.clone())
+                .collect::<Vec<_>>(),
+            vec![dir.join("src/inc"), dir.join("src/missing")]
+        );
+    }
+
+    #[test]
+    fn test_rewritten_entry() {
+        let dir = TempTree::new(&[("src/a.cpp", ""), ("src/inc/pch.h", "")]);
+
+        let options = CompileDbOptions {
+            rewrites: vec![PathRewrite {
+                from: "/home/ci/work".into(),
+                to: dir.to_string_lossy().into(),
+            }],
+            ..Default::default()
+        };
+        let entry = SourceFileEntry::try_from(
+            CompileCommandsEntry {
+                directory: "/home/ci/work/out".into(),
+                file: "/home/ci/work/src/a.cpp".into(),
+                arguments: Some(args(
+                    "c++ -I/home/ci/work/src/inc -include /home/ci/work/src/inc/pch.h -c a.cpp",
+                )),
+                command: None,
+                output: Some("/home/ci/work/out/obj/a.o".into()),
+            },
+            &options,
+            &PathResolver::default(),
+        )
+        .unwrap();
+
+        assert_eq!(entry.file_path, dir.join("src/a.cpp"));
+        assert_eq!(entry.include_directories[0].path, dir.join("src/inc"));
+        assert_eq!(entry.forced_includes, vec![dir.join("src/inc/pch.h")]);
+        assert_eq!(entry.output, Some(dir.join("out/obj/a.o")));
+
+        // outputs given as `-o` are rewritten like any other argument
+        let entry = SourceFileEntry::try_from(
+            CompileCommandsEntry {
+                directory: "/home/ci/work/out".into(),
+                file: "/home/ci/work/src/a.cpp".into(),
+                arguments: Some(args("c++ -o /home/ci/work/out/obj/b.o -c a.cpp")),
+                command: None,
+                output: None,
+            },
+            &options,
+            &PathResolver::default(),
+        )
+        .unwrap();
+        assert_eq!(entry.output, Some(dir.join("out/obj/b.o")));
+    }
+
+    #[test]
+    fn test_response_files() {
+        let dir = TempTree::new(&[
+            ("src/a.cpp", ""),
+            ("src/a.rsp", "-Iinc1\n@nested.rsp"),
+            ("src/nested.rsp", "'-Iinc2' -DX"),
+            ("src/loop.rsp", "@loop.rsp"),
+            ("src/bad.rsp", "-I'inc1"),
+        ]);
+        std::fs::create_dir_all(dir.join("src/inc1")).unwrap();
+        std::fs::create_dir_all(dir.join("src/inc2")).unwrap();
+
+        let entry = |command: Option<&str>| CompileCommandsEntry {
+            directory: dir.join("src").to_string_lossy().into(),
+            file: "a.cpp".into(),
+            arguments: None,
+            command: command.map(String::from),
+            output: None,
+        };
+        let options = CompileDbOptions::default();
+        let resolver = PathResolver::default();
+
+        let parsed =
+            SourceFileEntry::try_from(entry(Some("c++ @a.rsp -c a.cpp")), &options, &resolver)
+                .unwrap();
+        assert_eq!(
+            parsed
+                .include_directories
+                .iter()
+                .map(|d| d.path.clone())
+                .collect::<Vec<_>>(),
+            vec![dir.join("src/inc1"), dir.join("src/inc2")]
+        );
+        assert_eq!(parsed.defines.len(), 1);
+
+        for (command, expected) in [
+            (None, "neither `arguments` nor `command` is set"),
+            (Some("c++ 'a.cpp"), "cannot split command"),
+            (Some("c++ @bad.rsp"), "cannot split response file"),
+            (Some("c++ @loop.rsp"), "response files nested too deeply"),
+        ] {
+            match SourceFileEntry::try_from(entry(command), &options, &resolver) {
+                Err(Error::InvalidCompileCommand { entry, message }) => {
+                    assert!(entry.starts_with("\"a.cpp\" in "));
+                    assert!(message.starts_with(expected), "{}", message);
+                }
+                other => panic!("Unexpected result {:?}", other.map(|e| e.file_path)),
+            }
+        }
+        assert!(matches!(
+            SourceFileEntry::try_from(entry(Some("c++ @missing.rsp")), &options, &resolver),
+            Err(Error::ResponseFileError { .. })
+        ));
+    }
+
+    fn entry(file: &str, output: &str, includes: &[&str], arguments: &str) -> SourceFileEntry {
+        SourceFileEntry {
+            file_path: PathBuf::from(file),
+            include_directories: includes
+                .iter()
+                .map(|i| IncludeDirectory {
+                    path: PathBuf::from(i),
+                    kind: IncludeDirectoryKind::Regular,
+                })
+                .collect(),
+            defines: vec![],
+            forced_includes: vec![],
+            output: Some(PathBuf::from(output)),
+            arguments: args(arguments),
+        }
+    }
+
+    #[test]
+    fn test_filters() {
+        let a = entry(
+            "/src/a.cpp",
+            "/out/arm/a.o",
+            &[],
+            "c++ --target=arm -c a.cpp",
+        );
+        let b = entry("/src/tests/b.cpp", "/out/host/b.o", &[], "c++ -c b.cpp");
+
+        let selected = |filter: EntryFilter| {
+            let filter = CompiledFilter::new(&filter).unwrap();
+            [&a, &b]
+                .into_iter()
+                .filter(|e| filter.matches(e))
+                .map(|e| e.file_path.clone())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            selected(EntryFilter::FileMatches("/src/tests/**".into())),
+            vec![PathBuf::from("/src/tests/b.cpp")]
+        );
+        assert_eq!(
+            selected(EntryFilter::OutputMatches("**/arm/*.o".into())),
+            vec![PathBuf::from("/src/a.cpp")]
+        );
+        assert_eq!(
+            selected(EntryFilter::ArgContains("--target=arm".into())),
+            vec![PathBuf::from("/src/a.cpp")]
+        );
+        assert!(selected(EntryFilter::ArgContains("--target".into())).is_empty());
+        assert!(CompiledFilter::new(&EntryFilter::FileMatches("a**b".into())).is_err());
+    }
+
+    #[test]
+    fn test_deduplicate() {
+        let entries = || {
+            vec![
+                entry("/src/a.cpp", "/out/host/a.o", &["/inc/host", "/inc"], ""),
+                entry("/src/b.cpp", "/out/host/b.o", &[], ""),
+                entry("/src/a.cpp", "/out/arm/a.o", &["/inc/arm", "/inc"], ""),
+            ]
+        };
+        let includes = |e: &SourceFileEntry| {
+            e.include_directories
+                .iter()
+                .map(|d| d.path.to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(deduplicate(entries(), &DuplicatePolicy::Keep).len(), 3);
+
+        let first = deduplicate(entries(), &DuplicatePolicy::First);
+        assert_eq!(first.len(), 2);
+        assert_eq!(includes(&first[0]), vec!["/inc/host", "/inc"]);
+
+        let union = deduplicate(entries(), &DuplicatePolicy::Union);
+        assert_eq!(includes(&union[0]), vec!["/inc/host", "/inc", "/inc/arm"]);
+
+        let arm = deduplicate(entries(), &DuplicatePolicy::Output("/out/arm".into()));
+        assert_eq!(arm.len(), 2);
+        assert_eq!(arm[0].output, Some(PathBuf::from("/out/arm/a.o")));
+        assert_eq!(arm[1].file_path, PathBuf::from("/src/b.cpp"));
+    }
+
+    #[test]
+    fn test_output_argument() {
+        assert_eq!(
+            output_argument(&args("c++ -o a.o -c a.cpp"), false),
+            Some("a.o")
+        );
+        assert_eq!(
+            output_argument(&args("cl /Fo:a.obj /c a.cpp"), true),
+            Some("a.obj")
+        );
+        assert_eq!(output_argument(&args("cl /Foa.obj /c a.cpp"), false), None);
+    }
+
+    #[test]
+    fn test_sysroot_paths() {
+        let start = Path::new("/build");
+        let resolver = PathResolver::default();
+        assert_eq!(
+            absolute_path("=/usr/include", start, Some(Path::new("/sr")), &resolver),
+            Some(PathBuf::from("/sr/usr/include"))
+        );
+        assert_eq!(
+            absolute_path("/abs", start, Some(Path::new("/sr")), &resolver),
+            Some(PathBuf::from("/abs"))
+        );
+    }
 }
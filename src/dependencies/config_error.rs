@@ -0,0 +1,255 @@
+use nom::error::ErrorKind;
+use nom_supreme::error::{BaseErrorKind, ErrorTree, Expectation, StackContext};
+use std::ops::Range;
+
+/// A failure at the end of one branch of an error tree
+struct Leaf<'a> {
+    /// Remaining input where the failure happened
+    location: &'a str,
+
+    /// What would have been accepted there, if describable
+    expected: Option<String>,
+
+    /// Context labels (outermost first) the failure happened inside of
+    contexts: Vec<&'static str>,
+}
+
+fn describe(
+    kind: &BaseErrorKind<&'static str, Box<dyn std::error::Error + Send + Sync>>,
+) -> Option<String> {
+    match kind {
+        BaseErrorKind::Expected(Expectation::Tag(tag)) => Some(format!("`{}`", tag)),
+        BaseErrorKind::Expected(Expectation::Eof) | BaseErrorKind::Kind(ErrorKind::Eof) => {
+            Some("end of file".into())
+        }
+        BaseErrorKind::Expected(Expectation::Multispace)
+        | BaseErrorKind::Kind(ErrorKind::MultiSpace) => Some("whitespace".into()),
+        BaseErrorKind::Kind(ErrorKind::IsNot) => Some("a value".into()),
+        BaseErrorKind::Kind(ErrorKind::Digit) => Some("a number".into()),
+        _ => None,
+    }
+}
+
+fn collect_leaves<'a>(
+    tree: &ErrorTree<&'a str>,
+    contexts: &mut Vec<&'static str>,
+    leaves: &mut Vec<Leaf<'a>>,
+) {
+    match tree {
+        ErrorTree::Base { location, kind } => leaves.push(Leaf {
+            location,
+            expected: describe(kind),
+            contexts: contexts.clone(),
+        }),
+        ErrorTree::Stack {
+            base,
+            contexts: stack,
+        } => {
+            // the stack lists the innermost context first
+            let depth = contexts.len();
+            contexts.extend(stack.iter().rev().filter_map(|(_, c)| match c {
+                StackContext::Context(label) => Some(*label),
+                StackContext::Kind(_) => None,
+            }));
+            collect_leaves(base, contexts, leaves);
+            contexts.truncate(depth);
+        }
+        ErrorTree::Alt(siblings) => {
+            for sibling in siblings {
+                collect_leaves(sibling, contexts, leaves);
+            }
+        }
+    }
+}
+
+/// Render a config parse error the way rustc renders compile errors.
+///
+/// Only the failures that got furthest into `source` are reported, together
+/// with everything that would have been accepted there.
+///
+/// ```
+/// # use include_graph::dependencies::config_error::render_parse_error;
+/// # use nom_supreme::error::ErrorTree;
+/// # use nom_supreme::tag::TagError;
+///
+/// let source = "graph {\n  mpa {\n";
+/// let error = ErrorTree::from_tag(&source[10..], "map");
+/// assert_eq!(
+///     render_parse_error(source, "config.txt", &error),
+///     "error: expected `map`, found `mpa`\n --> config.txt:2:3\n  |\n2 |   mpa {\n  |   ^^^\n"
+/// );
+/// ```
+pub fn render_parse_error(source: &str, file_name: &str, error: &ErrorTree<&str>) -> String {
+    let mut leaves = Vec::new();
+    collect_leaves(error, &mut Vec::new(), &mut leaves);
+
+    let remaining = leaves.iter().map(|l| l.location.len()).min().unwrap_or(0);
+    let furthest = leaves
+        .iter()
+        .filter(|l| l.location.len() == remaining)
+        .collect::<Vec<_>>();
+
+    let mut expected: Vec<&str> = Vec::new();
+    for leaf in furthest.iter() {
+        if let Some(ref e) = leaf.expected {
+            if !expected.contains(&e.as_str()) {
+                expected.push(e);
+            }
+        }
+    }
+    let contexts = furthest
+        .iter()
+        .map(|l| &l.contexts)
+        .max_by_key(|c| c.len())
+        .cloned()
+        .unwrap_or_default();
+
+    let offset = source.len() - remaining.min(source.len());
+    let found = source[offset..]
+        .split(|c: char| c.is_whitespace())
+        .next()
+        .unwrap_or_default();
+
+    let mut headline = match expected.len() {
+        0 => "unexpected input".to_string(),
+        1 => format!("expected {}", expected[0]),
+        _ => format!("expected one of {}", expected.join(", ")),
+    };
+    if found.is_empty() {
+        headline.push_str(", found end of file");
+    } else {
+        headline.push_str(&format!(", found `{}`", found));
+    }
+
+    let mut notes = Vec::new();
+    if !contexts.is_empty() {
+        let inside = contexts
+            .iter()
+            .rev()
+            .fold("...".to_string(), |inner, label| {
+                format!("{} {{ {} }}", label, inner)
+            });
+        notes.push(format!("note: inside {}", inside));
+    }
+
+    render_error(
+        source,
+        file_name,
+        offset..offset + found.len(),
+        &headline,
+        &notes,
+    )
+}
+
+/// Render an error about the text at `span` (byte offsets) of `source` the
+/// way rustc renders compile errors, followed by `notes`.
+///
+/// ```
+/// # use include_graph::dependencies::config_error::render_error;
+///
+/// assert_eq!(
+///     render_error("a = b\nc = ${d}\n", "x.cfg", 10..14, "undefined variable `d`", &[]),
+///     "error: undefined variable `d`\n --> x.cfg:2:5\n  |\n2 | c = ${d}\n  |     ^^^^\n"
+/// );
+/// ```
+pub fn render_error(
+    source: &str,
+    file_name: &str,
+    span: Range<usize>,
+    headline: &str,
+    notes: &[String],
+) -> String {
+    let offset = span.start;
+    let line_start = source[..offset].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let line_end = source[offset..]
+        .find(['\n', '\r'])
+        .map(|i| offset + i)
+        .unwrap_or(source.len());
+    let line_number = source[..offset].matches('\n').count() + 1;
+    let column = source[line_start..offset].chars().count() + 1;
+    let underlined = source[offset..span.end.min(line_end).max(offset)]
+        .chars()
+        .count();
+
+    let gutter = " ".repeat(line_number.to_string().len());
+    let indent = source[line_start..offset]
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect::<String>();
+
+    let mut message = format!("error: {}\n", headline);
+    message.push_str(&format!(
+        "{}--> {}:{}:{}\n",
+        gutter, file_name, line_number, column
+    ));
+    message.push_str(&format!("{} |\n", gutter));
+    let snippet = format!("{} | {}", line_number, &source[line_start..line_end]);
+    message.push_str(snippet.trim_end());
+    message.push('\n');
+    message.push_str(&format!(
+        "{} | {}{}\n",
+        gutter,
+        indent,
+        "^".repeat(underlined.max(1))
+    ));
+    for note in notes {
+        message.push_str(&format!("{} = {}\n", gutter, note));
+    }
+
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nom::error::{ContextError, ParseError};
+    use nom_supreme::tag::TagError;
+
+    #[test]
+    fn test_furthest_alternatives() {
+        let source = "graph {\n  map {\n    kep a\n  }\n}\n";
+        let at = &source[20..];
+        let near = &source[8..];
+
+        let error = ErrorTree::from_tag(near, "group")
+            .or(ErrorTree::from_tag(at, "keep"))
+            .or(ErrorTree::from_tag(at, "drop"))
+            .or(ErrorTree::from_tag(at, "}"));
+        let error = ErrorTree::add_context(near, "map", error);
+        let error = ErrorTree::add_context(source, "graph", error);
+
+        assert_eq!(
+            render_parse_error(source, "test.cfg", &error),
+            concat!(
+                "error: expected one of `keep`, `drop`, `}`, found `kep`\n",
+                " --> test.cfg:3:5\n",
+                "  |\n",
+                "3 |     kep a\n",
+                "  |     ^^^\n",
+                "  = note: inside graph { map { ... } }\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_end_of_file() {
+        let source = "input {\n";
+        let error = ErrorTree::from_tag(&source[8..], "}");
+
+        assert_eq!(
+            render_parse_error(source, "x", &error),
+            "error: expected `}`, found end of file\n --> x:2:1\n  |\n2 |\n  | ^\n"
+        );
+    }
+
+    #[test]
+    fn test_trailing_input() {
+        let source = "graph {}\nextra";
+        let error = ErrorTree::from_error_kind(&source[9..], ErrorKind::Eof);
+
+        assert_eq!(
+            render_parse_error(source, "x", &error),
+            "error: expected end of file, found `extra`\n --> x:2:1\n  |\n2 | extra\n  | ^^^^^\n"
+        );
+    }
+}
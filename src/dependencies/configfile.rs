@@ -1,31 +1,85 @@
 use crate::dependencies::{
-    compiledb::parse_compile_database,
-    cparse::{all_sources_and_includes, extract_includes, SourceWithIncludes},
+    canonicalize::{normalize_lexically, PathResolver, SymlinkMode},
+    compiledb::{
+        parse_compile_database, CompileDbOptions, DuplicatePolicy, EntryFilter, PathRewrite,
+        SourceFileEntry, WindowsPathRemap,
+    },
+    cparse::{
+        all_sources_and_includes, macros_from_includes, FileType, FileTypes, Include,
+        IncludeDirective, IncludeStyle, SourceWithIncludes,
+    },
+    depfile::load_depfiles,
     gn::load_gn_targets,
     graph::GraphBuilder,
+    include_path::{IncludeDirectory, IncludeDirectoryKind, IncludePath},
+    include_trace::{load_include_traces, TraceFormat},
+    ninja_deps::load_ninja_deps,
     path_mapper::{PathMapper, PathMapping},
+    preprocessor::{DefineFlag, PreprocessorConfig, UnknownConditionPolicy},
+    scan_cache::ScanCache,
+    unresolved::{ReportFormat, SystemHeaders, UnresolvedReport},
 };
 use color_eyre::Result;
 use color_eyre::{eyre::WrapErr, Report};
 use nom::{
     branch::alt,
-    bytes::complete::{is_not, tag_no_case},
-    character::complete::{char as parsed_char, multispace1},
-    combinator::{opt, value},
-    multi::{many0, many1, separated_list0},
+    bytes::complete::is_not,
+    character::complete::{char as parsed_char, multispace1, u32 as parse_u32},
+    combinator::{consumed, eof, not, opt, peek, value},
+    error::{ErrorKind, ParseError},
+    multi::{many0, many1, separated_list0, separated_list1},
     sequence::{pair, separated_pair, tuple},
-    IResult, Parser,
+    IResult, Offset, Parser,
 };
-use nom_supreme::ParserExt;
+use nom_supreme::{
+    error::ErrorTree,
+    tag::{complete::tag_no_case, TagError},
+    ParserExt,
+};
+use rayon::{prelude::*, ThreadPool, ThreadPoolBuilder};
 
 use std::{
-    collections::{HashMap, HashSet},
-    path::PathBuf,
+    cell::RefCell,
+    collections::{BTreeSet, HashMap, HashSet},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use tracing::{debug, error, error_span, info, warn};
+
+use super::{
+    config_error::{render_error, render_parse_error},
+    error::Error,
+    graph::Graph,
 };
 
-use tracing::{debug, error, info, warn};
+/// Result of the config file parsers, whose errors keep enough detail for
+/// [render_parse_error]
+type ParseResult<'a, T> = IResult<&'a str, T, ErrorTree<&'a str>>;
+
+/// Options for building a graph that are not part of the configuration file
+#[derive(Debug, Default, Clone)]
+pub struct BuildOptions {
+    /// How many files to parse in parallel. Defaults to the number of CPUs.
+    pub jobs: Option<usize>,
+
+    /// Where to persist scan results across runs (if anywhere)
+    pub cache: Option<PathBuf>,
 
-use super::{error::Error, graph::Graph};
+    /// Where to write a report of includes that could not be resolved (if anywhere)
+    pub unresolved_report: Option<PathBuf>,
+
+    /// Format of the report of unresolved includes
+    pub report_format: ReportFormat,
+
+    /// Where the configuration was read from, for error messages
+    pub config_path: Option<PathBuf>,
+
+    /// Variables (name and value) that override or add to the ones assigned
+    /// in the configuration
+    pub variables: Vec<(String, String)>,
+}
 
 /// Defines an instruction regarding name mapping
 #[derive(Debug, PartialEq, Clone)]
@@ -98,98 +152,810 @@ enum InputCommand {
         path: String,
         load_include_directories: bool,
         load_sources: bool,
+        options: CompileDbOptions,
+    },
+    LoadDepfiles {
+        glob: String,
+        root: String,
+    },
+    LoadNinjaDeps(String),
+    LoadIncludeTrace {
+        glob: String,
+        format: TraceFormat,
+        root: Option<String>,
     },
     IncludeDirectory(String),
     Glob(String),
+    Define(DefineFlag),
+    UnknownConditions(UnknownConditionPolicy),
+    FollowIncludes {
+        depth: Option<usize>,
+    },
+    SystemHeaders(Vec<String>),
+    GeneratedRoot {
+        dir: String,
+        display: String,
+    },
+}
+
+/// The value assigned to a variable, as written
+#[derive(Debug, PartialEq, Clone)]
+enum VariableValue {
+    Single(String),
+
+    /// `[ a b c ]`: expands into several items where a list is accepted
+    List(Vec<String>),
 }
 
 #[derive(Debug, PartialEq, Clone)]
 struct VariableAssignment {
     name: String,
-    value: String,
+    value: VariableValue,
 }
 
+/// An item of a configuration file, with the text it was parsed from
+type Parsed<'a, T> = (&'a str, T);
+
 #[derive(Debug, PartialEq, Default, Clone)]
-struct GraphInstructions {
-    map_instructions: Vec<MapInstruction>,
-    group_instructions: Vec<GroupInstruction>,
-    color_instructions: Vec<ColorInstruction>,
-    zoom_items: Vec<ZoomItem>,
+struct GraphInstructions<'a> {
+    map_instructions: Vec<Parsed<'a, MapInstruction>>,
+    group_instructions: Vec<Parsed<'a, GroupInstruction>>,
+    color_instructions: Vec<Parsed<'a, ColorInstruction>>,
+    zoom_items: Vec<Parsed<'a, ZoomItem>>,
 }
 
-/// Defines a full configuration file, with components
-/// resolved as much as possible
+/// Defines a single configuration file, as written. Variables are expanded
+/// only once all imports are merged.
+///
+/// Items keep the text they were parsed from, so that expansion errors can
+/// point at them.
 #[derive(Debug, PartialEq, Clone, Default)]
-struct ConfigurationFile {
-    /// Fully resolved variables
-    variable_map: HashMap<String, String>,
+struct ConfigurationFile<'a> {
+    /// Other configuration files to merge in before this one
+    imports: Vec<&'a str>,
+
+    /// Variable assignments, in order
+    variables: Vec<VariableAssignment>,
+
+    /// Extensions to classify as headers or sources (on top of the defaults)
+    file_types: Vec<(FileType, &'a str)>,
 
     /// What inputs are to be processed
-    input_commands: Vec<InputCommand>,
+    input_commands: Vec<Parsed<'a, InputCommand>>,
 
     /// Should symlinks be resolved, or left alone? Enabling symlink resolution
     /// can be significantly slower on large code bases.
+    symlinks: Option<Parsed<'a, SymlinkMode>>,
 
     /// Instructions to build a braph
-    graph: GraphInstructions,
+    graph: GraphInstructions<'a>,
 }
 
-fn expand_variable(value: &str, variable_map: &HashMap<String, String>) -> String {
-    // expand any occurences of "${name}"
-    let mut value = value.to_string();
+/// An instruction, together with the configuration file it was written in
+#[derive(Debug, PartialEq, Clone)]
+struct Sourced<T> {
+    file: Arc<Path>,
+    instruction: T,
+}
 
-    loop {
-        let replacements = variable_map
-            .iter()
-            .map(|(k, v)| (format!("${{{}}}", k), v))
-            .filter(|(k, _v)| value.contains(k))
-            .collect::<Vec<_>>();
+/// An item as written, together with where it is written
+#[derive(Debug, Clone)]
+struct Located<T> {
+    file: Arc<Path>,
 
-        if replacements.is_empty() {
-            break;
+    /// Byte offsets of the item in `file`
+    span: Range<usize>,
+
+    item: T,
+}
+
+impl<T> Located<T> {
+    /// An item parsed from `text`, a slice of `source` (the content of `file`)
+    fn new(file: &Arc<Path>, source: &str, (text, item): Parsed<'_, T>) -> Self {
+        // point at the item itself, not at the whitespace before it
+        let text = opt(parse_whitespace)
+            .parse(text)
+            .map_or(text, |(rest, _)| rest);
+        let start = source.offset(text);
+        Located {
+            file: file.clone(),
+            span: start..start + text.len(),
+            item,
+        }
+    }
+}
+
+/// Expand `items`, recording the references each of them failed to expand
+/// in `errors` (with the location of the item)
+fn expand_located<T>(
+    items: Vec<Located<T>>,
+    variable_map: &VariableMap,
+    errors: &mut Vec<Located<FailedExpansion>>,
+) -> Vec<Sourced<T>>
+where
+    T: Expanded,
+{
+    let mut expanded = Vec::with_capacity(items.len());
+    for Located { file, span, item } in items {
+        let mut instructions = Vec::new();
+        item.expanded_into(variable_map, &mut instructions);
+        errors.extend(
+            variable_map
+                .take_errors()
+                .into_iter()
+                .map(|failure| Located {
+                    file: file.clone(),
+                    span: span.clone(),
+                    item: failure,
+                }),
+        );
+        expanded.extend(instructions.into_iter().map(|instruction| Sourced {
+            file: file.clone(),
+            instruction,
+        }));
+    }
+    expanded
+}
+
+/// The instructions of `items`, without where they are written
+fn instructions<T>(items: Vec<Sourced<T>>) -> Vec<T> {
+    items.into_iter().map(|item| item.instruction).collect()
+}
+
+/// Defines a full configuration: a configuration file merged with everything
+/// it imports, with components resolved as much as possible
+#[derive(Debug, Default)]
+struct Configuration {
+    /// Fully resolved variables
+    variable_map: VariableMap,
+
+    /// What extensions are headers and sources
+    file_types: FileTypes,
+
+    /// What inputs are to be processed
+    input_commands: Vec<Sourced<InputCommand>>,
+
+    /// Should symlinks be resolved, or left alone?
+    symlinks: SymlinkMode,
+
+    map_instructions: Vec<MapInstruction>,
+    group_instructions: Vec<Sourced<GroupInstruction>>,
+    color_instructions: Vec<ColorInstruction>,
+    zoom_items: Vec<ZoomItem>,
+}
+
+/// Merges configuration files with the files they import.
+///
+/// Imports are merged before the importing file, so its variables and
+/// symlink mode take precedence. A file imported more than once is merged
+/// only the first time.
+#[derive(Debug, Default)]
+struct ConfigLoader {
+    /// Canonical paths of the files being loaded, outermost first
+    stack: Vec<PathBuf>,
+
+    /// Canonical paths of all files loaded so far
+    loaded: HashSet<PathBuf>,
+
+    /// Contents of all files loaded so far, for diagnostics
+    sources: Vec<(Arc<Path>, String)>,
+
+    /// Variables (name and value) that take precedence over assignments
+    overrides: Vec<(String, String)>,
+
+    variables: Vec<VariableAssignment>,
+    file_types: Vec<Located<(FileType, String)>>,
+    symlinks: Option<Located<SymlinkMode>>,
+    input_commands: Vec<Located<InputCommand>>,
+    map_instructions: Vec<Located<MapInstruction>>,
+    group_instructions: Vec<Located<GroupInstruction>>,
+    color_instructions: Vec<Located<ColorInstruction>>,
+    zoom_items: Vec<Located<ZoomItem>>,
+}
+
+impl ConfigLoader {
+    /// A loader where `overrides` take precedence over variables assigned in
+    /// the configuration files
+    fn new(overrides: &[(String, String)]) -> Self {
+        ConfigLoader {
+            overrides: overrides.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    /// Load the configuration in `source`, read from `file`.
+    ///
+    /// `file` does not have to exist (e.g. for configurations that are not
+    /// read from disk): imports are then relative to the current directory.
+    fn load_root(&mut self, file: &Path, source: &str) -> Result<(), Report> {
+        if let Ok(canonical) = std::fs::canonicalize(file) {
+            self.loaded.insert(canonical.clone());
+            self.stack.push(canonical);
+        }
+        self.load(file, source)
+    }
+
+    fn load(&mut self, file: &Path, source: &str) -> Result<(), Report> {
+        let config = match parse_config(source) {
+            Ok((_, config)) => config,
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                return Err(Error::ConfigParseError {
+                    message: render_parse_error(source, &file.display().to_string(), &e),
+                }
+                .into())
+            }
+            Err(nom::Err::Incomplete(_)) => {
+                return Err(Error::Internal {
+                    message: "Incomplete config parse".into(),
+                }
+                .into())
+            }
+        };
+
+        let origin: Arc<Path> = file.into();
+
+        // imports are merged before any assignment, so they may only use
+        // environment variables and overrides
+        let environment = VariableMap {
+            values: self.overrides.iter().cloned().collect(),
+            strict: true,
+            ..Default::default()
+        };
+        let mut errors = Vec::new();
+        let imports = instructions(expand_located(
+            config
+                .imports
+                .into_iter()
+                .map(|import| Located::new(&origin, source, (import, import.to_string())))
+                .collect(),
+            &environment,
+            &mut errors,
+        ));
+        check_expansion_errors(&errors, &[(origin.clone(), source.to_string())])?;
+
+        let directory = file.parent().unwrap_or(Path::new(""));
+        for import in imports.iter() {
+            let path = directory.join(import);
+            let canonical = std::fs::canonicalize(&path)
+                .map_err(|source| Error::FileIOError {
+                    source,
+                    path: path.clone(),
+                    message: "Failed to find imported config",
+                })
+                .wrap_err_with(|| format!("Imported from {}", file.display()))?;
+
+            if let Some(start) = self.stack.iter().position(|p| *p == canonical) {
+                let cycle = self.stack[start..]
+                    .iter()
+                    .chain(std::iter::once(&canonical))
+                    .map(|p| p.display().to_string())
+                    .collect::<Vec<_>>();
+                return Err(Error::ImportCycle {
+                    cycle: cycle.join(" -> "),
+                }
+                .into());
+            }
+            if !self.loaded.insert(canonical.clone()) {
+                debug!("{} is already imported, skipping", path.display());
+                continue;
+            }
+
+            let imported = std::fs::read_to_string(&path)
+                .map_err(|source| Error::FileIOError {
+                    source,
+                    path: path.clone(),
+                    message: "Failed to read imported config",
+                })
+                .wrap_err_with(|| format!("Imported from {}", file.display()))?;
+
+            self.stack.push(canonical);
+            self.load(&path, &imported).wrap_err_with(|| {
+                format!("Failed to import {:?} from {}", import, file.display())
+            })?;
+            self.stack.pop();
+        }
+
+        self.sources.push((origin.clone(), source.to_string()));
+
+        self.variables.extend(config.variables);
+        self.file_types
+            .extend(config.file_types.into_iter().map(|(kind, extensions)| {
+                Located::new(
+                    &origin,
+                    source,
+                    (extensions, (kind, extensions.to_string())),
+                )
+            }));
+        if let Some(symlinks) = config.symlinks {
+            self.symlinks = Some(Located::new(&origin, source, symlinks));
         }
+        self.input_commands.extend(
+            config
+                .input_commands
+                .into_iter()
+                .map(|item| Located::new(&origin, source, item)),
+        );
+        self.map_instructions.extend(
+            config
+                .graph
+                .map_instructions
+                .into_iter()
+                .map(|item| Located::new(&origin, source, item)),
+        );
+        self.group_instructions.extend(
+            config
+                .graph
+                .group_instructions
+                .into_iter()
+                .map(|item| Located::new(&origin, source, item)),
+        );
+        self.color_instructions.extend(
+            config
+                .graph
+                .color_instructions
+                .into_iter()
+                .map(|item| Located::new(&origin, source, item)),
+        );
+        self.zoom_items.extend(
+            config
+                .graph
+                .zoom_items
+                .into_iter()
+                .map(|item| Located::new(&origin, source, item)),
+        );
+
+        Ok(())
+    }
 
-        for (k, v) in replacements {
-            value = value.replace(&k, v);
+    /// Combine everything loaded, expanding variables.
+    ///
+    /// Fails if any variable used is not defined, or if a list variable is
+    /// used where a single value is expected.
+    fn finish(self) -> Result<Configuration, Report> {
+        let variable_map = VariableMap::resolve(self.variables, &self.overrides);
+        let mut errors = Vec::new();
+
+        let mut file_types = FileTypes::default();
+        for (kind, extension) in
+            instructions(expand_located(self.file_types, &variable_map, &mut errors))
+        {
+            file_types.add(kind, &extension);
         }
+
+        let configuration = Configuration {
+            file_types,
+            symlinks: instructions(expand_located(
+                self.symlinks.into_iter().collect(),
+                &variable_map,
+                &mut errors,
+            ))
+            .pop()
+            .unwrap_or_default(),
+            input_commands: expand_located(self.input_commands, &variable_map, &mut errors),
+            map_instructions: instructions(expand_located(
+                self.map_instructions,
+                &variable_map,
+                &mut errors,
+            )),
+            group_instructions: expand_located(self.group_instructions, &variable_map, &mut errors),
+            color_instructions: instructions(expand_located(
+                self.color_instructions,
+                &variable_map,
+                &mut errors,
+            )),
+            zoom_items: instructions(expand_located(self.zoom_items, &variable_map, &mut errors)),
+            variable_map,
+        };
+
+        check_expansion_errors(&errors, &self.sources)?;
+        Ok(configuration)
     }
+}
 
-    value
+/// Fail if any reference could not be expanded, describing all of them
+fn check_expansion_errors(
+    errors: &[Located<FailedExpansion>],
+    sources: &[(Arc<Path>, String)],
+) -> Result<(), Report> {
+    if errors.is_empty() {
+        return Ok(());
+    }
+    let message = errors
+        .iter()
+        .map(|error| expansion_error_message(error, sources))
+        .collect::<Vec<_>>()
+        .join("\n");
+    Err(Error::UndefinedVariables { message }.into())
 }
 
-/// Something that changes by self-expanding variables
+/// Describe an expansion error, pointing at the reference written in the
+/// item that failed to expand
+fn expansion_error_message(
+    error: &Located<FailedExpansion>,
+    sources: &[(Arc<Path>, String)],
+) -> String {
+    let FailedExpansion { error: kind, used } = &error.item;
+    let (reference, headline, help) = match kind {
+        ExpansionError::Undefined(reference) => {
+            let name = &reference[2..reference.len() - 1];
+            match name.strip_prefix("env:") {
+                Some(env) => (
+                    reference,
+                    format!("environment variable `{}` is not set", env),
+                    format!(
+                        "help: set it, or use a default like `${{{}:-default}}`",
+                        name
+                    ),
+                ),
+                None => (
+                    reference,
+                    format!("undefined variable `{}`", name),
+                    format!(
+                        "help: define it (e.g. with `-D {}=...`), or use a default like `${{{}:-default}}`",
+                        name, name
+                    ),
+                ),
+            }
+        }
+        ExpansionError::ListAsValue(reference) => (
+            reference,
+            format!(
+                "list variable `{}` used where a single value is expected",
+                &reference[2..reference.len() - 1]
+            ),
+            "help: lists only expand into several items in places that take several \
+             values (e.g. `manual` group items, `zoom` items, `keep`/`drop`, `glob`)"
+                .to_string(),
+        ),
+        ExpansionError::Cycle(reference) => (
+            reference,
+            format!(
+                "variable `{}` refers to itself",
+                &reference[2..reference.len() - 1]
+            ),
+            "help: variables may not refer to themselves, directly or through other variables"
+                .to_string(),
+        ),
+    };
+
+    let mut notes = Vec::new();
+    if used != reference {
+        notes.push(format!(
+            "note: `{}` is reached by expanding `{}`",
+            reference, used
+        ));
+    }
+    notes.push(help);
+
+    let file = error.file.display().to_string();
+    let Some((_, source)) = sources.iter().find(|(f, _)| Arc::ptr_eq(f, &error.file)) else {
+        return format!(
+            "error: {} (in {})\n = {}\n",
+            headline,
+            file,
+            notes.join("\n = ")
+        );
+    };
+
+    // the use is written in the item, unless it comes from a list element:
+    // point at the whole item then
+    let mut span = error.span.clone();
+    let mut line_start = span.start;
+    for line in source[error.span.clone()].split_inclusive('\n') {
+        let code = line.split('#').next().unwrap_or_default();
+        if let Some(column) = code.find(used.as_str()) {
+            let offset = line_start + column;
+            span = offset..offset + used.len();
+            break;
+        }
+        line_start += line.len();
+    }
+    render_error(source, &file, span, &headline, &notes)
+}
+
+/// How many levels of variables may refer to each other when expanding a
+/// value (deeper nesting is considered a cycle)
+const MAX_EXPANSION_DEPTH: usize = 32;
+
+/// A reference (as written) that could not be expanded
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+enum ExpansionError {
+    /// The variable is not defined and the reference has no default
+    Undefined(String),
+
+    /// A list variable was used where only a single value makes sense
+    ListAsValue(String),
+
+    /// Expanding the variable does not terminate: it refers to itself,
+    /// directly or through other variables
+    Cycle(String),
+}
+
+/// An expansion error, with the reference written in the expanded value that
+/// led to it: the failing reference itself, or a reference whose name or value
+/// uses it
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+struct FailedExpansion {
+    error: ExpansionError,
+    used: String,
+}
+
+/// Variables that `${...}` references expand to.
 ///
-/// Variable expansion is replacing "${name}" with the content of the
-/// key `name` in the variable_map hashmap
-trait Expanded {
-    fn expanded_from(self, variable_map: &HashMap<String, String>) -> Self;
+/// Supported references are:
+///   - `${name}`: the value of a variable
+///   - `${env:NAME}`: the value of an environment variable
+///   - `${name:-default}`: the value of a variable, or `default` if the
+///     variable is undefined or empty
+///
+/// References may be nested (`${a${b}}` expands `${b}` first).
+///
+/// Variables assigned a list (`name = [ a b ]`) expand into one item per
+/// element when used in a value that accepts several items (see
+/// [VariableMap::expand_list]).
+#[derive(Debug, Default)]
+struct VariableMap {
+    values: HashMap<String, String>,
+    lists: HashMap<String, Vec<String>>,
+
+    /// Report undefined references instead of leaving them for later
+    strict: bool,
+
+    /// References that could not be expanded in strict mode
+    errors: RefCell<Vec<FailedExpansion>>,
 }
 
-trait ResolveVariables<O> {
-    fn resolve_variables(self) -> O;
+/// Length of the `${...}` reference at the start of `value`, including
+/// references nested in it. `None` if it is not terminated.
+fn reference_length(value: &str) -> Option<usize> {
+    let bytes = value.as_bytes();
+    let mut depth = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i..].starts_with(b"${") {
+            depth += 1;
+            i += 2;
+            continue;
+        }
+        if bytes[i] == b'}' {
+            depth -= 1;
+            if depth == 0 {
+                return Some(i + 1);
+            }
+        }
+        i += 1;
+    }
+    None
 }
 
-impl ResolveVariables<HashMap<String, String>> for Vec<VariableAssignment> {
-    fn resolve_variables(self) -> HashMap<String, String> {
-        let mut variable_map = HashMap::new();
-        for VariableAssignment { name, value } in self {
-            variable_map.insert(name, value.expanded_from(&variable_map));
+impl VariableMap {
+    /// Resolve `assignments` in order, each one seeing the variables assigned
+    /// before it. `overrides` take precedence over any assignment.
+    ///
+    /// References to variables that are not assigned (yet) and have no
+    /// default are kept, as they may be defined by a later file.
+    fn resolve(assignments: Vec<VariableAssignment>, overrides: &[(String, String)]) -> Self {
+        let mut variables = VariableMap {
+            values: overrides.iter().cloned().collect(),
+            ..Default::default()
+        };
+        for VariableAssignment { name, value } in assignments {
+            if overrides.iter().any(|(n, _)| *n == name) {
+                continue;
+            }
+            match value {
+                VariableValue::Single(value) => {
+                    let value = variables.expand(&value);
+                    variables.lists.remove(&name);
+                    variables.values.insert(name, value);
+                }
+                VariableValue::List(items) => {
+                    let items = items
+                        .iter()
+                        .flat_map(|item| variables.expand_list(item))
+                        .collect();
+                    variables.values.remove(&name);
+                    variables.lists.insert(name, items);
+                }
+            }
+        }
+        variables.strict = true;
+        variables
+    }
+
+    fn lookup(&self, name: &str) -> Option<String> {
+        match name.strip_prefix("env:") {
+            Some(name) => std::env::var(name).ok(),
+            None => self.values.get(name).cloned(),
+        }
+    }
+
+    /// Expand all references in `value`
+    fn expand(&self, value: &str) -> String {
+        self.expand_to_depth(value, 0, None)
+    }
+
+    /// Expand all references in `value`. Errors are reported as reached from
+    /// `used` if `value` is not written in the expanded value, but is the name
+    /// or the value of the reference `used`
+    fn expand_to_depth(&self, value: &str, depth: usize, used: Option<&str>) -> String {
+        let mut expanded = String::new();
+        let mut rest = value;
+
+        while let Some(start) = rest.find("${") {
+            expanded.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let Some(length) = reference_length(rest) else {
+                break;
+            };
+
+            let used = used.unwrap_or(&rest[..length]);
+            let body = self.expand_to_depth(&rest[2..length - 1], depth, Some(used));
+            let (name, default) = match body.split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (body.as_str(), None),
+            };
+
+            // an empty value is replaced by the default, if there is one
+            let value = self
+                .lookup(name)
+                .filter(|value| default.is_none() || !value.is_empty());
+            let reference = format!("${{{}}}", body);
+            match (value, default) {
+                (Some(_), _) if depth >= MAX_EXPANSION_DEPTH => {
+                    self.fail(ExpansionError::Cycle(reference.clone()), used);
+                    expanded.push_str(&reference);
+                }
+                (Some(value), _) => {
+                    expanded.push_str(&self.expand_to_depth(&value, depth + 1, Some(used)))
+                }
+                (None, _) if self.lists.contains_key(name) => {
+                    self.fail(ExpansionError::ListAsValue(reference.clone()), used);
+                    expanded.push_str(&reference);
+                }
+                (None, Some(default)) => expanded.push_str(default),
+                (None, None) => {
+                    self.fail(ExpansionError::Undefined(reference.clone()), used);
+                    expanded.push_str(&reference);
+                }
+            }
+            rest = &rest[length..];
+        }
+        expanded.push_str(rest);
+
+        expanded
+    }
+
+    /// Expand all references in `value` into as many items as the list
+    /// variables it uses have elements (all combinations if it uses several
+    /// lists). Values that do not use lists expand into a single item.
+    ///
+    /// A list with no elements expands into no items, unless the reference
+    /// has a default.
+    fn expand_list(&self, value: &str) -> Vec<String> {
+        self.expand_list_to_depth(value, 0, None)
+    }
+
+    /// Like [VariableMap::expand_to_depth], into several items
+    fn expand_list_to_depth(&self, value: &str, depth: usize, used: Option<&str>) -> Vec<String> {
+        let mut offset = 0;
+
+        while let Some(start) = value[offset..].find("${").map(|i| offset + i) {
+            let Some(length) = reference_length(&value[start..]) else {
+                break;
+            };
+            let end = start + length;
+            offset = end;
+
+            let reference_used = used.unwrap_or(&value[start..end]);
+            let body =
+                self.expand_to_depth(&value[start + 2..end - 1], depth, Some(reference_used));
+            let (name, default) = match body.split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (body.as_str(), None),
+            };
+            let Some(items) = self.lists.get(name) else {
+                continue;
+            };
+            if depth >= MAX_EXPANSION_DEPTH {
+                self.fail(
+                    ExpansionError::Cycle(format!("${{{}}}", body)),
+                    reference_used,
+                );
+                return vec![value.to_string()];
+            }
+
+            let items = match default {
+                Some(default) if items.is_empty() => vec![default.to_string()],
+                _ => items.clone(),
+            };
+            return items
+                .into_iter()
+                .flat_map(|item| {
+                    let value = format!("{}{}{}", &value[..start], item, &value[end..]);
+                    // the references left are written in `value`, or in the list element
+                    self.expand_list_to_depth(&value, depth + 1, used)
+                })
+                .collect();
+        }
+
+        vec![self.expand_to_depth(value, depth, used)]
+    }
+
+    /// Record an `error` reached from the reference `used`, if references
+    /// must all be expanded
+    fn fail(&self, error: ExpansionError, used: &str) {
+        if self.strict {
+            self.errors.borrow_mut().push(FailedExpansion {
+                error,
+                used: used.to_string(),
+            });
         }
-        variable_map
+    }
+
+    /// Take the references that could not be expanded so far
+    fn take_errors(&self) -> Vec<FailedExpansion> {
+        let mut errors = self.errors.take();
+        let mut seen = HashSet::new();
+        errors.retain(|e| seen.insert(e.clone()));
+        errors
+    }
+}
+
+/// Something that changes by self-expanding variables
+///
+/// Variable expansion is replacing "${name}" with the content of the
+/// variable `name` (see [VariableMap] for all supported references)
+trait Expanded: Sized {
+    fn expanded_from(self, variable_map: &VariableMap) -> Self;
+
+    /// Expand into `out`, as one item of a list. Values using list variables
+    /// may expand into several (or no) items.
+    fn expanded_into(self, variable_map: &VariableMap, out: &mut Vec<Self>) {
+        out.push(self.expanded_from(variable_map));
     }
 }
 
 impl Expanded for ZoomItem {
-    fn expanded_from(self, variable_map: &HashMap<String, String>) -> Self {
+    fn expanded_from(self, variable_map: &VariableMap) -> Self {
         Self {
             name: self.name.expanded_from(variable_map),
             ..self
         }
     }
+
+    fn expanded_into(self, variable_map: &VariableMap, out: &mut Vec<Self>) {
+        out.extend(
+            variable_map
+                .expand_list(&self.name)
+                .into_iter()
+                .map(|name| ZoomItem {
+                    name,
+                    focused: self.focused,
+                }),
+        );
+    }
+}
+
+/// Extensions of a file type
+impl Expanded for (FileType, String) {
+    fn expanded_from(self, variable_map: &VariableMap) -> Self {
+        (self.0, self.1.expanded_from(variable_map))
+    }
+
+    fn expanded_into(self, variable_map: &VariableMap, out: &mut Vec<Self>) {
+        let (kind, extensions) = self;
+        out.extend(
+            variable_map
+                .expand_list(&extensions)
+                .into_iter()
+                .map(|extension| (kind, extension)),
+        );
+    }
 }
 
 impl Expanded for GroupEdgeEnd {
-    fn expanded_from(self, variable_map: &HashMap<String, String>) -> Self {
+    fn expanded_from(self, variable_map: &VariableMap) -> Self {
         match self {
             GroupEdgeEnd::From(v) => GroupEdgeEnd::From(v.expanded_from(variable_map)),
             GroupEdgeEnd::To(v) => GroupEdgeEnd::To(v.expanded_from(variable_map)),
@@ -198,7 +964,7 @@ impl Expanded for GroupEdgeEnd {
 }
 
 impl Expanded for EdgeColor {
-    fn expanded_from(self, variable_map: &HashMap<String, String>) -> Self {
+    fn expanded_from(self, variable_map: &VariableMap) -> Self {
         match self {
             EdgeColor::Regular(c) => EdgeColor::Regular(c.expanded_from(variable_map)),
             EdgeColor::Bold(c) => EdgeColor::Bold(c.expanded_from(variable_map)),
@@ -207,7 +973,7 @@ impl Expanded for EdgeColor {
 }
 
 impl Expanded for ColorInstruction {
-    fn expanded_from(self, variable_map: &HashMap<String, String>) -> Self {
+    fn expanded_from(self, variable_map: &VariableMap) -> Self {
         Self {
             end: self.end.expanded_from(variable_map),
             color: self.color.expanded_from(variable_map),
@@ -215,30 +981,173 @@ impl Expanded for ColorInstruction {
     }
 }
 
+impl Expanded for SymlinkMode {
+    fn expanded_from(self, variable_map: &VariableMap) -> Self {
+        match self {
+            SymlinkMode::ResolveUnder(roots) => SymlinkMode::ResolveUnder(
+                roots
+                    .into_iter()
+                    .map(|r| r.to_string_lossy().into_owned())
+                    .collect::<Vec<_>>()
+                    .expanded_from(variable_map)
+                    .into_iter()
+                    .map(PathBuf::from)
+                    .collect(),
+            ),
+            SymlinkMode::Resolve | SymlinkMode::Lexical => self,
+        }
+    }
+}
+
 impl Expanded for InputCommand {
-    fn expanded_from(self, variable_map: &HashMap<String, String>) -> Self {
+    fn expanded_from(self, variable_map: &VariableMap) -> Self {
         match self {
             InputCommand::LoadCompileDb {
                 path,
                 load_include_directories,
                 load_sources,
+                options,
             } => InputCommand::LoadCompileDb {
                 path: path.expanded_from(variable_map),
                 load_include_directories,
                 load_sources,
+                options: CompileDbOptions {
+                    windows_remaps: options
+                        .windows_remaps
+                        .into_iter()
+                        .map(|r| WindowsPathRemap {
+                            from: r.from.expanded_from(variable_map),
+                            to: r.to.expanded_from(variable_map),
+                        })
+                        .collect(),
+                    rewrites: options
+                        .rewrites
+                        .into_iter()
+                        .map(|r| PathRewrite {
+                            from: r.from.expanded_from(variable_map),
+                            to: r.to.expanded_from(variable_map),
+                        })
+                        .collect(),
+                    strict: options.strict,
+                    filters: options
+                        .filters
+                        .into_iter()
+                        .map(|f| match f {
+                            EntryFilter::FileMatches(p) => {
+                                EntryFilter::FileMatches(p.expanded_from(variable_map))
+                            }
+                            EntryFilter::OutputMatches(p) => {
+                                EntryFilter::OutputMatches(p.expanded_from(variable_map))
+                            }
+                            EntryFilter::ArgContains(a) => {
+                                EntryFilter::ArgContains(a.expanded_from(variable_map))
+                            }
+                        })
+                        .collect(),
+                    duplicates: match options.duplicates {
+                        DuplicatePolicy::Output(dir) => {
+                            DuplicatePolicy::Output(dir.expanded_from(variable_map))
+                        }
+                        other => other,
+                    },
+                },
+            },
+            InputCommand::LoadDepfiles { glob, root } => InputCommand::LoadDepfiles {
+                glob: glob.expanded_from(variable_map),
+                root: root.expanded_from(variable_map),
             },
+            InputCommand::LoadNinjaDeps(p) => {
+                InputCommand::LoadNinjaDeps(p.expanded_from(variable_map))
+            }
+            InputCommand::LoadIncludeTrace { glob, format, root } => {
+                InputCommand::LoadIncludeTrace {
+                    glob: glob.expanded_from(variable_map),
+                    format,
+                    root: root.map(|r| r.expanded_from(variable_map)),
+                }
+            }
             InputCommand::IncludeDirectory(p) => {
                 InputCommand::IncludeDirectory(p.expanded_from(variable_map))
             }
             InputCommand::Glob(p) => InputCommand::Glob(p.expanded_from(variable_map)),
+            InputCommand::SystemHeaders(names) => {
+                InputCommand::SystemHeaders(names.expanded_from(variable_map))
+            }
+            InputCommand::GeneratedRoot { dir, display } => InputCommand::GeneratedRoot {
+                dir: dir.expanded_from(variable_map),
+                display: display.expanded_from(variable_map),
+            },
+            InputCommand::Define(DefineFlag::Define { name, value }) => {
+                InputCommand::Define(DefineFlag::Define {
+                    name: name.expanded_from(variable_map),
+                    value: value.expanded_from(variable_map),
+                })
+            }
+            InputCommand::Define(DefineFlag::Undefine(name)) => {
+                InputCommand::Define(DefineFlag::Undefine(name.expanded_from(variable_map)))
+            }
+            InputCommand::UnknownConditions(_) | InputCommand::FollowIncludes { .. } => self,
+        }
+    }
+
+    fn expanded_into(self, variable_map: &VariableMap, out: &mut Vec<Self>) {
+        match self {
+            InputCommand::IncludeDirectory(p) => out.extend(
+                variable_map
+                    .expand_list(&p)
+                    .into_iter()
+                    .map(InputCommand::IncludeDirectory),
+            ),
+            InputCommand::Glob(p) => out.extend(
+                variable_map
+                    .expand_list(&p)
+                    .into_iter()
+                    .map(InputCommand::Glob),
+            ),
+            // a list item may hold a whole `NAME=value`, which then takes
+            // precedence over the (default) value
+            InputCommand::Define(DefineFlag::Define { name, value }) => {
+                let value = value.expanded_from(variable_map);
+                out.extend(variable_map.expand_list(&name).into_iter().map(|name| {
+                    InputCommand::Define(match name.contains('=') {
+                        true => DefineFlag::define(&name),
+                        false => DefineFlag::Define {
+                            name,
+                            value: value.clone(),
+                        },
+                    })
+                }))
+            }
+            other => out.push(other.expanded_from(variable_map)),
         }
     }
 }
 
+/// The include path and preprocessor configuration a file was parsed with.
+///
+/// Headers reached through the file (see `follow_includes`) are parsed with
+/// the same settings.
+#[derive(Debug)]
+struct ParseSettings {
+    include_path: IncludePath,
+    preprocessor: PreprocessorConfig,
+}
+
 #[derive(Debug, Default)]
 struct DependencyData {
-    includes: HashSet<PathBuf>,
+    /// Include directories used for all sources (after their own ones, if any)
+    includes: IncludePath,
+    preprocessor: PreprocessorConfig,
     files: Vec<SourceWithIncludes>,
+
+    /// The settings each of the `files` was parsed with
+    parse_settings: HashMap<PathBuf, Arc<ParseSettings>>,
+
+    /// How many levels of not-yet-loaded includes to parse (if any)
+    follow_includes: Option<usize>,
+
+    /// Extra names (or `dir/` prefixes) of headers considered system headers
+    system_headers: Vec<String>,
 }
 
 /// Pretty-print dependency data.
@@ -264,19 +1173,62 @@ impl<'a> std::fmt::Display for FullFileList<'a> {
     }
 }
 
-/// Parse an individual comment.
-///
-/// ```
-/// # use include_graph::dependencies::configfile::parse_comment;
+/// Pretty-print computed includes that could not be resolved.
 ///
-/// assert_eq!(parse_comment("# foo"), Ok(("", " foo")));
-/// assert_eq!(parse_comment("# foo\ntest"), Ok(("\ntest", " foo")));
-/// assert_eq!(parse_comment("# foo\n#bar"), Ok(("\n#bar", " foo")));
-/// assert!(parse_comment("blah").is_err());
-/// assert!(parse_comment("blah # rest").is_err());
+/// Wrapped as a separate struct to support lazy formatting
+struct UnresolvedComputedIncludes<'a> {
+    dependencies: &'a DependencyData,
+}
+
+impl<'a> UnresolvedComputedIncludes<'a> {
+    pub fn new(dependencies: &'a DependencyData) -> Self {
+        Self { dependencies }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dependencies
+            .files
+            .iter()
+            .all(|f| f.unresolved_computed.is_empty())
+    }
+}
+
+impl<'a> std::fmt::Display for UnresolvedComputedIncludes<'a> {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt.write_str("Unresolved computed includes:\n")?;
+        for f in self.dependencies.files.iter() {
+            for include in f.unresolved_computed.iter() {
+                fmt.write_fmt(format_args!(
+                    "  {}:{}: #include {}",
+                    f.path.display(),
+                    include.line,
+                    include.expression
+                ))?;
+                match include.expansion {
+                    Some(ref expansion) => {
+                        fmt.write_fmt(format_args!(" (expands to {})\n", expansion))?
+                    }
+                    None => fmt.write_str(" (cannot be expanded)\n")?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parse an individual comment.
 ///
 /// ```
-pub fn parse_comment(input: &str) -> IResult<&str, &str> {
+/// # use include_graph::dependencies::configfile::parse_comment;
+///
+/// assert_eq!(parse_comment("# foo").ok(), Some(("", " foo")));
+/// assert_eq!(parse_comment("# foo\ntest").ok(), Some(("\ntest", " foo")));
+/// assert_eq!(parse_comment("# foo\n#bar").ok(), Some(("\n#bar", " foo")));
+/// assert!(parse_comment("blah").is_err());
+/// assert!(parse_comment("blah # rest").is_err());
+///
+/// ```
+pub fn parse_comment(input: &str) -> ParseResult<'_, &str> {
     pair(parsed_char('#'), opt(is_not("\n\r")))
         .map(|(_, r)| r.unwrap_or_default())
         .parse(input)
@@ -291,19 +1243,19 @@ pub fn parse_comment(input: &str) -> IResult<&str, &str> {
 /// ```
 /// # use include_graph::dependencies::configfile::parse_whitespace;
 ///
-/// assert_eq!(parse_whitespace("  \n  foo"), Ok(("foo", ())));
-/// assert_eq!(parse_whitespace(" # test"), Ok(("", ())));
-/// assert_eq!(parse_whitespace("# rest"), Ok(("", ())));
-/// assert_eq!(parse_whitespace("  # test\n  \t# more\n  last\nthing"), Ok(("last\nthing", ())));
+/// assert_eq!(parse_whitespace("  \n  foo").ok(), Some(("foo", ())));
+/// assert_eq!(parse_whitespace(" # test").ok(), Some(("", ())));
+/// assert_eq!(parse_whitespace("# rest").ok(), Some(("", ())));
+/// assert_eq!(parse_whitespace("  # test\n  \t# more\n  last\nthing").ok(), Some(("last\nthing", ())));
 ///
 /// assert!(parse_whitespace("blah").is_err());
 ///
 /// ```
-pub fn parse_whitespace(input: &str) -> IResult<&str, ()> {
+pub fn parse_whitespace(input: &str) -> ParseResult<'_, ()> {
     value((), many1(alt((multispace1, parse_comment)))).parse(input)
 }
 
-fn parse_variable_name(input: &str) -> IResult<&str, &str> {
+fn parse_variable_name(input: &str) -> ParseResult<'_, &str> {
     is_not("= \t\r\n{}[]()#").parse(input)
 }
 
@@ -313,19 +1265,207 @@ fn parse_variable_name(input: &str) -> IResult<&str, &str> {
 /// ```
 /// # use include_graph::dependencies::configfile::parse_until_whitespace;
 ///
-/// assert_eq!(parse_until_whitespace("x  \n  foo"), Ok(("  \n  foo", "x")));
-/// assert_eq!(parse_until_whitespace("foo bar"), Ok((" bar", "foo")));
-/// assert_eq!(parse_until_whitespace("foo"), Ok(("", "foo")));
-/// assert_eq!(parse_until_whitespace("foo# comment"), Ok(("# comment", "foo")));
-/// assert_eq!(parse_until_whitespace("foo then a # comment"), Ok((" then a # comment", "foo")));
+/// assert_eq!(parse_until_whitespace("x  \n  foo").ok(), Some(("  \n  foo", "x")));
+/// assert_eq!(parse_until_whitespace("foo bar").ok(), Some((" bar", "foo")));
+/// assert_eq!(parse_until_whitespace("foo").ok(), Some(("", "foo")));
+/// assert_eq!(parse_until_whitespace("foo# comment").ok(), Some(("# comment", "foo")));
+/// assert_eq!(parse_until_whitespace("foo then a # comment").ok(), Some((" then a # comment", "foo")));
 /// assert!(parse_until_whitespace("\n  foo").is_err());
 ///
 /// ```
-pub fn parse_until_whitespace(input: &str) -> IResult<&str, &str> {
+pub fn parse_until_whitespace(input: &str) -> ParseResult<'_, &str> {
     is_not("#\n\r \t").parse(input)
 }
 
-fn parse_compiledb(input: &str) -> IResult<&str, InputCommand> {
+/// Parse a value up to whitespace, a comment or the `}` closing a block.
+/// Unlike the closing `}`, the ones ending `${name}` references are part of
+/// the value.
+///
+/// ```
+/// # use include_graph::dependencies::configfile::parse_block_value;
+///
+/// assert_eq!(parse_block_value("a.h}").ok(), Some(("}", "a.h")));
+/// assert_eq!(parse_block_value("${x}/a.h}").ok(), Some(("}", "${x}/a.h")));
+/// assert_eq!(parse_block_value("${a${b}} c").ok(), Some((" c", "${a${b}}")));
+/// assert!(parse_block_value("}").is_err());
+/// ```
+pub fn parse_block_value(input: &str) -> ParseResult<'_, &str> {
+    let bytes = input.as_bytes();
+    let mut depth = 0;
+    let mut end = 0;
+    while end < bytes.len() {
+        match bytes[end] {
+            b'$' if bytes.get(end + 1) == Some(&b'{') => {
+                depth += 1;
+                end += 2;
+                continue;
+            }
+            b'}' if depth > 0 => depth -= 1,
+            b'}' | b'#' | b'\n' | b'\r' | b' ' | b'\t' => break,
+            _ => {}
+        }
+        end += 1;
+    }
+
+    match end {
+        0 => Err(nom::Err::Error(ErrorTree::from_error_kind(
+            input,
+            ErrorKind::IsNot,
+        ))),
+        _ => Ok((&input[end..], &input[..end])),
+    }
+}
+
+/// Parse an item of a `{ ... }` block, which may contain variables like `${name}`.
+/// The closing `}` must be preceded by whitespace.
+fn parse_block_item(input: &str) -> ParseResult<'_, &str> {
+    parse_until_whitespace
+        .preceded_by(not(tag_no_case("}")))
+        .terminated(opt(parse_whitespace))
+        .parse(input)
+}
+
+/// Parse `item`s until `end` matches.
+///
+/// Unlike `many0` followed by `end`, a failure reports what both `item` and
+/// `end` would have accepted.
+fn block_items<'a, O, E, F, G>(
+    mut item: F,
+    mut end: G,
+) -> impl FnMut(&'a str) -> ParseResult<'a, Vec<O>>
+where
+    F: Parser<&'a str, O, ErrorTree<&'a str>>,
+    G: Parser<&'a str, E, ErrorTree<&'a str>>,
+{
+    move |mut input| {
+        let mut items = Vec::new();
+        loop {
+            let end_error = match end.parse(input) {
+                Ok((rest, _)) => return Ok((rest, items)),
+                Err(nom::Err::Error(e)) => e,
+                Err(e) => return Err(e),
+            };
+            match item.parse(input) {
+                // no progress: only `end` can move on from here
+                Ok((rest, _)) if rest.len() == input.len() => {
+                    return Err(nom::Err::Error(end_error))
+                }
+                Ok((rest, value)) => {
+                    items.push(value);
+                    input = rest;
+                }
+                Err(nom::Err::Error(e)) => return Err(nom::Err::Error(end_error.or(e))),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Run `parser`, reporting `keywords` as also accepted if it fails.
+///
+/// Used after optional sections, whose own errors are dropped by `opt`.
+fn or_expecting<'a, O, P>(
+    mut parser: P,
+    keywords: &'static [&'static str],
+) -> impl FnMut(&'a str) -> ParseResult<'a, O>
+where
+    P: Parser<&'a str, O, ErrorTree<&'a str>>,
+{
+    move |input| {
+        parser.parse(input).map_err(|e| {
+            e.map(|e| {
+                let at = parse_whitespace(input)
+                    .map(|(rest, _)| rest)
+                    .unwrap_or(input);
+                keywords
+                    .iter()
+                    .fold(e, |e, keyword| e.or(ErrorTree::from_tag(at, *keyword)))
+            })
+        })
+    }
+}
+
+/// The optional `sections` that may still appear after the last one that was
+/// `present`, for reporting what was expected instead.
+fn sections_after(sections: &'static [&'static str], present: &[bool]) -> &'static [&'static str] {
+    match present.iter().rposition(|p| *p) {
+        Some(last) => &sections[last + 1..],
+        None => sections,
+    }
+}
+
+/// Optional clauses of `from compiledb`, after the `load` list
+#[derive(Debug, PartialEq, Clone)]
+enum CompileDbClause {
+    Remap(WindowsPathRemap),
+    Rewrite(PathRewrite),
+    Filter(EntryFilter),
+    Duplicates(DuplicatePolicy),
+    Strict,
+}
+
+fn parse_compiledb_clause(input: &str) -> ParseResult<'_, CompileDbClause> {
+    let substitution = || {
+        separated_pair(
+            parse_until_whitespace,
+            tag_no_case("=>").delimited_by(parse_whitespace),
+            parse_until_whitespace,
+        )
+        .map(|(from, to): (&str, &str)| (from.to_string(), to.to_string()))
+    };
+
+    alt((
+        substitution()
+            .preceded_by(tuple((tag_no_case("remap"), parse_whitespace)))
+            .map(|(from, to)| CompileDbClause::Remap(WindowsPathRemap { from, to })),
+        substitution()
+            .preceded_by(tuple((tag_no_case("rewrite"), parse_whitespace)))
+            .map(|(from, to)| CompileDbClause::Rewrite(PathRewrite { from, to })),
+        alt((
+            parse_until_whitespace
+                .preceded_by(tuple((
+                    tag_no_case("file"),
+                    parse_whitespace,
+                    tag_no_case("matches"),
+                    parse_whitespace,
+                )))
+                .map(|p| EntryFilter::FileMatches(p.into())),
+            parse_until_whitespace
+                .preceded_by(tuple((
+                    tag_no_case("output"),
+                    parse_whitespace,
+                    tag_no_case("matches"),
+                    parse_whitespace,
+                )))
+                .map(|p| EntryFilter::OutputMatches(p.into())),
+            parse_until_whitespace
+                .preceded_by(tuple((
+                    tag_no_case("arg"),
+                    parse_whitespace,
+                    tag_no_case("contains"),
+                    parse_whitespace,
+                )))
+                .map(|a| EntryFilter::ArgContains(a.into())),
+        ))
+        .preceded_by(tuple((tag_no_case("where"), parse_whitespace)))
+        .map(CompileDbClause::Filter),
+        alt((
+            value(DuplicatePolicy::Keep, tag_no_case("keep")),
+            value(DuplicatePolicy::First, tag_no_case("first")),
+            value(DuplicatePolicy::Union, tag_no_case("union")),
+            parse_until_whitespace
+                .preceded_by(tuple((tag_no_case("output"), parse_whitespace)))
+                .map(|dir| DuplicatePolicy::Output(dir.into())),
+        ))
+        .preceded_by(tuple((tag_no_case("duplicates"), parse_whitespace)))
+        .map(CompileDbClause::Duplicates),
+        value(CompileDbClause::Strict, tag_no_case("strict")),
+    ))
+    .terminated(opt(parse_whitespace))
+    .parse(input)
+}
+
+fn parse_compiledb(input: &str) -> ParseResult<'_, InputCommand> {
     #[derive(Clone, Copy, PartialEq)]
     enum Type {
         Includes,
@@ -341,7 +1481,8 @@ fn parse_compiledb(input: &str) -> IResult<&str, InputCommand> {
                 parse_whitespace,
             )))
             .terminated(parse_whitespace),
-        separated_list0(
+        // at least one target, so a typo reports what `load` accepts
+        separated_list1(
             tuple((
                 opt(parse_whitespace),
                 tag_no_case(","),
@@ -352,20 +1493,177 @@ fn parse_compiledb(input: &str) -> IResult<&str, InputCommand> {
                 value(Type::Sources, tag_no_case("sources")),
             )),
         )
+        .cut()
         .preceded_by(tuple((tag_no_case("load"), opt(parse_whitespace))))
         .terminated(opt(parse_whitespace)),
+        many0(parse_compiledb_clause),
+    ))
+    .map(|(path, selections, clauses)| {
+        let mut options = CompileDbOptions::default();
+        for clause in clauses {
+            match clause {
+                CompileDbClause::Remap(remap) => options.windows_remaps.push(remap),
+                CompileDbClause::Rewrite(rewrite) => options.rewrites.push(rewrite),
+                CompileDbClause::Filter(filter) => options.filters.push(filter),
+                CompileDbClause::Duplicates(policy) => options.duplicates = policy,
+                CompileDbClause::Strict => options.strict = true,
+            }
+        }
+        InputCommand::LoadCompileDb {
+            path: path.into(),
+            load_include_directories: selections.contains(&Type::Includes),
+            load_sources: selections.contains(&Type::Sources),
+            options,
+        }
+    })
+    .parse(input)
+}
+
+fn parse_depfiles(input: &str) -> ParseResult<'_, InputCommand> {
+    tuple((
+        parse_until_whitespace.preceded_by(tuple((
+            tag_no_case("from"),
+            parse_whitespace,
+            tag_no_case("depfiles"),
+            parse_whitespace,
+        ))),
+        // the compiler's working directory, which cannot be derived
+        parse_until_whitespace
+            .preceded_by(tuple((
+                parse_whitespace,
+                tag_no_case("root"),
+                parse_whitespace,
+            )))
+            .cut(),
+    ))
+    .terminated(opt(parse_whitespace))
+    .map(|(glob, root)| InputCommand::LoadDepfiles {
+        glob: glob.into(),
+        root: root.into(),
+    })
+    .parse(input)
+}
+
+fn parse_ninja_deps(input: &str) -> ParseResult<'_, InputCommand> {
+    parse_until_whitespace
+        .preceded_by(tuple((
+            tag_no_case("from"),
+            parse_whitespace,
+            tag_no_case("ninja_deps"),
+            parse_whitespace,
+        )))
+        .terminated(opt(parse_whitespace))
+        .map(|s| InputCommand::LoadNinjaDeps(s.into()))
+        .parse(input)
+}
+
+fn parse_include_trace(input: &str) -> ParseResult<'_, InputCommand> {
+    tuple((
+        parse_until_whitespace.preceded_by(tuple((
+            tag_no_case("from"),
+            parse_whitespace,
+            tag_no_case("include_trace"),
+            parse_whitespace,
+        ))),
+        opt(alt((
+            value(TraceFormat::DotDepth, tag_no_case("h")),
+            value(TraceFormat::ShowIncludes, tag_no_case("show_includes")),
+            value(TraceFormat::LineMarkers, tag_no_case("line_markers")),
+        ))
+        .preceded_by(tuple((
+            parse_whitespace,
+            tag_no_case("format"),
+            parse_whitespace,
+        )))),
+        opt(parse_until_whitespace.preceded_by(tuple((
+            parse_whitespace,
+            tag_no_case("root"),
+            parse_whitespace,
+        )))),
+    ))
+    .terminated(opt(parse_whitespace))
+    .map(|(glob, format, root)| InputCommand::LoadIncludeTrace {
+        glob: glob.into(),
+        format: format.unwrap_or_default(),
+        root: root.map(Into::into),
+    })
+    .parse(input)
+}
+
+fn parse_define(input: &str) -> ParseResult<'_, InputCommand> {
+    parse_until_whitespace
+        .preceded_by(tuple((tag_no_case("define"), parse_whitespace)))
+        .terminated(opt(parse_whitespace))
+        .map(|s| InputCommand::Define(DefineFlag::define(s)))
+        .parse(input)
+}
+
+fn parse_unknown_conditions(input: &str) -> ParseResult<'_, InputCommand> {
+    alt((
+        value(UnknownConditionPolicy::NotTaken, tag_no_case("not_taken")),
+        value(UnknownConditionPolicy::Taken, tag_no_case("taken")),
+        value(UnknownConditionPolicy::Both, tag_no_case("both")),
     ))
-    .map(|(path, selections)| InputCommand::LoadCompileDb {
-        path: path.into(),
-        load_include_directories: selections.contains(&Type::Includes),
-        load_sources: selections.contains(&Type::Sources),
+    .preceded_by(tuple((tag_no_case("unknown_conditions"), parse_whitespace)))
+    .terminated(opt(parse_whitespace))
+    .map(InputCommand::UnknownConditions)
+    .parse(input)
+}
+
+fn parse_follow_includes(input: &str) -> ParseResult<'_, InputCommand> {
+    opt(parse_u32.preceded_by(tuple((
+        parse_whitespace,
+        tag_no_case("depth"),
+        parse_whitespace,
+    ))))
+    .preceded_by(tag_no_case("follow_includes"))
+    .terminated(opt(parse_whitespace))
+    .map(|depth| InputCommand::FollowIncludes {
+        depth: depth.map(|d| d as usize),
+    })
+    .parse(input)
+}
+
+fn parse_system_headers(input: &str) -> ParseResult<'_, InputCommand> {
+    block_items(parse_block_item, tag_no_case("}"))
+        .context("system_headers")
+        .preceded_by(tuple((
+            tag_no_case("system_headers"),
+            opt(parse_whitespace),
+            tag_no_case("{"),
+            opt(parse_whitespace),
+        )))
+        .terminated(opt(parse_whitespace))
+        .map(|names| InputCommand::SystemHeaders(names.into_iter().map(String::from).collect()))
+        .parse(input)
+}
+
+fn parse_generated_root(input: &str) -> ParseResult<'_, InputCommand> {
+    separated_pair(
+        parse_until_whitespace,
+        tag_no_case("=>").delimited_by(parse_whitespace),
+        parse_until_whitespace,
+    )
+    .preceded_by(tuple((tag_no_case("generated_root"), parse_whitespace)))
+    .terminated(opt(parse_whitespace))
+    .map(|(dir, display)| InputCommand::GeneratedRoot {
+        dir: dir.into(),
+        display: display.into(),
     })
     .parse(input)
 }
 
-fn parse_input_command(input: &str) -> IResult<&str, InputCommand> {
+fn parse_input_command(input: &str) -> ParseResult<'_, InputCommand> {
     alt((
         parse_compiledb,
+        parse_depfiles,
+        parse_ninja_deps,
+        parse_include_trace,
+        parse_define,
+        parse_unknown_conditions,
+        parse_follow_includes,
+        parse_system_headers,
+        parse_generated_root,
         parse_until_whitespace
             .preceded_by(tuple((tag_no_case("glob"), parse_whitespace)))
             .terminated(opt(parse_whitespace))
@@ -378,15 +1676,17 @@ fn parse_input_command(input: &str) -> IResult<&str, InputCommand> {
     .parse(input)
 }
 
-fn parse_input(input: &str) -> IResult<&str, Vec<InputCommand>> {
-    many0(parse_input_command)
+fn parse_input(input: &str) -> ParseResult<'_, Vec<Parsed<'_, InputCommand>>> {
+    block_items(consumed(parse_input_command), tag_no_case("}"))
+        .context("input")
         .preceded_by(tuple((
-            tag_no_case("input"),
             parse_whitespace,
             tag_no_case("{"),
             opt(parse_whitespace),
         )))
-        .terminated(tuple((tag_no_case("}"), opt(parse_whitespace))))
+        .cut()
+        .preceded_by(tag_no_case("input"))
+        .terminated(opt(parse_whitespace))
         .parse(input)
 }
 
@@ -394,21 +1694,35 @@ impl<T> Expanded for Vec<T>
 where
     T: Expanded,
 {
-    fn expanded_from(self, variable_map: &HashMap<String, String>) -> Self {
+    fn expanded_from(self, variable_map: &VariableMap) -> Self {
+        let mut expanded = Vec::with_capacity(self.len());
+        for v in self {
+            v.expanded_into(variable_map, &mut expanded);
+        }
+        expanded
+    }
+}
+
+impl Expanded for HashSet<String> {
+    fn expanded_from(self, variable_map: &VariableMap) -> Self {
         self.into_iter()
-            .map(|v| v.expanded_from(variable_map))
+            .flat_map(|v| variable_map.expand_list(&v))
             .collect()
     }
 }
 
 impl Expanded for String {
-    fn expanded_from(self, variable_map: &HashMap<String, String>) -> Self {
-        expand_variable(&self, variable_map)
+    fn expanded_from(self, variable_map: &VariableMap) -> Self {
+        variable_map.expand(&self)
+    }
+
+    fn expanded_into(self, variable_map: &VariableMap, out: &mut Vec<Self>) {
+        out.extend(variable_map.expand_list(&self));
     }
 }
 
 impl Expanded for MapInstruction {
-    fn expanded_from(self, variable_map: &HashMap<String, String>) -> Self {
+    fn expanded_from(self, variable_map: &VariableMap) -> Self {
         match self {
             MapInstruction::DisplayMap { from, to } => MapInstruction::DisplayMap {
                 from: from.expanded_from(variable_map),
@@ -418,10 +1732,28 @@ impl Expanded for MapInstruction {
             MapInstruction::Drop(v) => MapInstruction::Drop(v.expanded_from(variable_map)),
         }
     }
+
+    fn expanded_into(self, variable_map: &VariableMap, out: &mut Vec<Self>) {
+        match self {
+            MapInstruction::Keep(v) => out.extend(
+                variable_map
+                    .expand_list(&v)
+                    .into_iter()
+                    .map(MapInstruction::Keep),
+            ),
+            MapInstruction::Drop(v) => out.extend(
+                variable_map
+                    .expand_list(&v)
+                    .into_iter()
+                    .map(MapInstruction::Drop),
+            ),
+            other => out.push(other.expanded_from(variable_map)),
+        }
+    }
 }
 
 impl Expanded for GroupInstruction {
-    fn expanded_from(self, variable_map: &HashMap<String, String>) -> Self {
+    fn expanded_from(self, variable_map: &VariableMap) -> Self {
         match self {
             GroupInstruction::GroupSourceHeader => self,
             GroupInstruction::GroupFromGn {
@@ -431,31 +1763,22 @@ impl Expanded for GroupInstruction {
                 ignore_targets,
             } => GroupInstruction::GroupFromGn {
                 gn_root: gn_root.expanded_from(variable_map),
-                target,
+                target: target.expanded_from(variable_map),
                 source_root: source_root.expanded_from(variable_map),
-                ignore_targets,
+                ignore_targets: ignore_targets.expanded_from(variable_map),
+            },
+            GroupInstruction::ManualGroup { name, color, items } => GroupInstruction::ManualGroup {
+                name: name.expanded_from(variable_map),
+                color: color.map(|c| c.expanded_from(variable_map)),
+                items: items.expanded_from(variable_map),
             },
-            GroupInstruction::ManualGroup { name, color, items } => {
-                GroupInstruction::ManualGroup { name, color, items }
-            }
-        }
-    }
-}
-
-impl Expanded for GraphInstructions {
-    fn expanded_from(self, variable_map: &HashMap<String, String>) -> Self {
-        Self {
-            map_instructions: self.map_instructions.expanded_from(variable_map),
-            group_instructions: self.group_instructions.expanded_from(variable_map),
-            color_instructions: self.color_instructions.expanded_from(variable_map),
-            zoom_items: self.zoom_items.expanded_from(variable_map),
         }
     }
 }
 
-fn parse_map_instructions(input: &str) -> IResult<&str, Vec<MapInstruction>> {
-    many0(
-        alt((
+fn parse_map_instructions(input: &str) -> ParseResult<'_, Vec<Parsed<'_, MapInstruction>>> {
+    block_items(
+        consumed(alt((
             separated_pair(
                 parse_until_whitespace,
                 tuple((parse_whitespace, tag_no_case("=>"), parse_whitespace)),
@@ -479,24 +1802,23 @@ fn parse_map_instructions(input: &str) -> IResult<&str, Vec<MapInstruction>> {
                     parse_whitespace,
                 )))
                 .map(|s| MapInstruction::Drop(s.into())),
-        ))
+        )))
         .terminated(parse_whitespace),
+        tag_no_case("}"),
     )
+    .context("map")
     .preceded_by(tuple((
-        tag_no_case("map"),
         parse_whitespace,
         tag_no_case("{"),
         parse_whitespace,
     )))
-    .terminated(tuple((
-        opt(parse_whitespace),
-        tag_no_case("}"),
-        opt(parse_whitespace),
-    )))
+    .cut()
+    .preceded_by(tag_no_case("map"))
+    .terminated(opt(parse_whitespace))
     .parse(input)
 }
 
-fn parse_gn_target(input: &str) -> IResult<&str, GroupInstruction> {
+fn parse_gn_target(input: &str) -> ParseResult<'_, GroupInstruction> {
     tuple((
         parse_until_whitespace.preceded_by(tuple((
             tag_no_case("gn"),
@@ -543,7 +1865,7 @@ fn parse_gn_target(input: &str) -> IResult<&str, GroupInstruction> {
     .parse(input)
 }
 
-fn parse_manual_group(input: &str) -> IResult<&str, GroupInstruction> {
+fn parse_manual_group(input: &str) -> ParseResult<'_, GroupInstruction> {
     tuple((
         tuple((
             parse_until_whitespace,
@@ -560,7 +1882,7 @@ fn parse_manual_group(input: &str) -> IResult<&str, GroupInstruction> {
         )))
         .terminated(tuple((opt(parse_whitespace), tag_no_case("{")))),
         many0(
-            is_not("\n\r \t#}")
+            parse_block_value
                 .preceded_by(opt(parse_whitespace))
                 .map(String::from),
         ),
@@ -578,13 +1900,13 @@ fn parse_manual_group(input: &str) -> IResult<&str, GroupInstruction> {
     .parse(input)
 }
 
-fn parse_target_list(input: &str) -> IResult<&str, Vec<&str>> {
-    many0(is_not("\n\r \t#}").preceded_by(opt(parse_whitespace)))
+fn parse_target_list(input: &str) -> ParseResult<'_, Vec<&str>> {
+    many0(parse_block_value.preceded_by(opt(parse_whitespace)))
         .terminated(opt(parse_whitespace))
         .parse(input)
 }
 
-fn parse_group_by_extension(input: &str) -> IResult<&str, GroupInstruction> {
+fn parse_group_by_extension(input: &str) -> ParseResult<'_, GroupInstruction> {
     // TODO: in the future consider if we should allow a "group these extensions"
     //       instead of automatic
     //
@@ -598,28 +1920,28 @@ fn parse_group_by_extension(input: &str) -> IResult<&str, GroupInstruction> {
     .parse(input)
 }
 
-fn parse_group(input: &str) -> IResult<&str, Vec<GroupInstruction>> {
-    many0(alt((
-        parse_group_by_extension,
-        parse_gn_target,
-        parse_manual_group,
-    )))
+fn parse_group(input: &str) -> ParseResult<'_, Vec<Parsed<'_, GroupInstruction>>> {
+    block_items(
+        consumed(alt((
+            parse_group_by_extension,
+            parse_gn_target,
+            parse_manual_group,
+        ))),
+        tag_no_case("}").preceded_by(opt(parse_whitespace)),
+    )
+    .context("group")
     .preceded_by(tuple((
-        opt(parse_whitespace),
-        tag_no_case("group"),
         opt(parse_whitespace),
         tag_no_case("{"),
         opt(parse_whitespace),
     )))
-    .terminated(tuple((
-        opt(parse_whitespace),
-        tag_no_case("}"),
-        opt(parse_whitespace),
-    )))
+    .cut()
+    .preceded_by(tuple((opt(parse_whitespace), tag_no_case("group"))))
+    .terminated(opt(parse_whitespace))
     .parse(input)
 }
 
-fn parse_color_instruction(input: &str) -> IResult<&str, ColorInstruction> {
+fn parse_color_instruction(input: &str) -> ParseResult<'_, ColorInstruction> {
     #[derive(Copy, Clone, PartialEq)]
     enum Direction {
         From,
@@ -651,182 +1973,589 @@ fn parse_color_instruction(input: &str) -> IResult<&str, ColorInstruction> {
     .parse(input)
 }
 
-fn parse_color_instructions(input: &str) -> IResult<&str, Vec<ColorInstruction>> {
-    separated_list0(parse_whitespace, parse_color_instruction)
-        .preceded_by(tuple((
-            opt(parse_whitespace),
-            tag_no_case("color"),
-            parse_whitespace,
-            tag_no_case("edges"),
-            opt(parse_whitespace),
-            tag_no_case("{"),
-            opt(parse_whitespace),
-        )))
-        .terminated(tuple((
-            opt(parse_whitespace),
-            tag_no_case("}"),
-            opt(parse_whitespace),
-        )))
-        .parse(input)
+fn parse_color_instructions(input: &str) -> ParseResult<'_, Vec<Parsed<'_, ColorInstruction>>> {
+    // once `color` is seen, errors are reported instead of skipping the section
+    block_items(
+        consumed(parse_color_instruction),
+        tag_no_case("}").preceded_by(opt(parse_whitespace)),
+    )
+    .context("color edges")
+    .preceded_by(tuple((
+        parse_whitespace,
+        tag_no_case("edges"),
+        opt(parse_whitespace),
+        tag_no_case("{"),
+        opt(parse_whitespace),
+    )))
+    .cut()
+    .preceded_by(tuple((opt(parse_whitespace), tag_no_case("color"))))
+    .terminated(opt(parse_whitespace))
+    .parse(input)
 }
 
-fn parse_zoom(input: &str) -> IResult<&str, Vec<ZoomItem>> {
-    many0(
-        tuple((
+fn parse_zoom(input: &str) -> ParseResult<'_, Vec<Parsed<'_, ZoomItem>>> {
+    block_items(
+        consumed(tuple((
             opt(tag_no_case("focus:").terminated(parse_whitespace)),
-            is_not("\n\r \t#}").terminated(opt(parse_whitespace)),
-        ))
-        .map(|(focus, name)| ZoomItem {
-            name: name.into(),
-            focused: focus.is_some(),
+            parse_block_value,
+        )))
+        .terminated(opt(parse_whitespace))
+        .map(|(text, (focus, name))| {
+            (
+                text,
+                ZoomItem {
+                    name: name.into(),
+                    focused: focus.is_some(),
+                },
+            )
         }),
+        tag_no_case("}").preceded_by(opt(parse_whitespace)),
     )
+    .context("zoom")
     .preceded_by(tuple((
-        opt(parse_whitespace),
-        tag_no_case("zoom"),
         opt(parse_whitespace),
         tag_no_case("{"),
         opt(parse_whitespace),
     )))
-    .terminated(tuple((
-        opt(parse_whitespace),
-        tag_no_case("}"),
-        opt(parse_whitespace),
-    )))
+    .cut()
+    .preceded_by(tuple((opt(parse_whitespace), tag_no_case("zoom"))))
+    .terminated(opt(parse_whitespace))
     .parse(input)
 }
 
-fn parse_graph(input: &str) -> IResult<&str, GraphInstructions> {
-    tuple((
-        parse_map_instructions,
-        parse_group,
-        opt(parse_color_instructions),
-        opt(parse_zoom),
-    ))
+fn parse_graph(input: &str) -> ParseResult<'_, GraphInstructions<'_>> {
+    const SECTIONS: &[&str] = &["map", "group", "color", "zoom"];
+
+    fn parse_body(input: &str) -> ParseResult<'_, GraphInstructions<'_>> {
+        let (input, map_instructions) = opt(parse_map_instructions).parse(input)?;
+        let (input, group_instructions) = opt(parse_group).parse(input)?;
+        let (input, color_instructions) = opt(parse_color_instructions).parse(input)?;
+        let (input, zoom) = opt(parse_zoom).parse(input)?;
+
+        let (input, _) = or_expecting(
+            tag_no_case("}").preceded_by(opt(parse_whitespace)),
+            sections_after(
+                SECTIONS,
+                &[
+                    map_instructions.is_some(),
+                    group_instructions.is_some(),
+                    color_instructions.is_some(),
+                    zoom.is_some(),
+                ],
+            ),
+        )
+        .parse(input)?;
+
+        Ok((
+            input,
+            GraphInstructions {
+                map_instructions: map_instructions.unwrap_or_default(),
+                group_instructions: group_instructions.unwrap_or_default(),
+                color_instructions: color_instructions.unwrap_or_default(),
+                zoom_items: zoom.unwrap_or_default(),
+            },
+        ))
+    }
+
+    parse_body
+        .context("graph")
+        .preceded_by(tuple((
+            parse_whitespace,
+            tag_no_case("{"),
+            opt(parse_whitespace),
+        )))
+        .cut()
+        .preceded_by(tuple((opt(parse_whitespace), tag_no_case("graph"))))
+        .terminated(opt(parse_whitespace))
+        .parse(input)
+}
+
+/// Parse a `[ a b c ]` list value. The `[` has to be followed by whitespace,
+/// so that values like `[a-z]*` are not lists.
+fn parse_list_value(input: &str) -> ParseResult<'_, Vec<&str>> {
+    block_items(
+        parse_until_whitespace
+            .preceded_by(not(tag_no_case("]")))
+            .terminated(opt(parse_whitespace)),
+        tag_no_case("]"),
+    )
     .preceded_by(tuple((
-        opt(parse_whitespace),
-        tag_no_case("graph"),
-        parse_whitespace,
-        tag_no_case("{"),
-        opt(parse_whitespace),
-    )))
-    .terminated(tuple((
-        opt(parse_whitespace),
-        tag_no_case("}"),
-        opt(parse_whitespace),
+        tag_no_case("["),
+        alt((parse_whitespace, peek(tag_no_case("]")).map(|_| ()))),
     )))
-    .map(
-        |(map_instructions, group_instructions, color_instructions, zoom)| GraphInstructions {
-            map_instructions,
-            group_instructions,
-            color_instructions: color_instructions.unwrap_or_default(),
-            zoom_items: zoom.unwrap_or_default(),
-        },
-    )
     .parse(input)
 }
 
-fn parse_variable_assignment(input: &str) -> IResult<&str, VariableAssignment> {
+fn parse_variable_assignment(input: &str) -> ParseResult<'_, VariableAssignment> {
     separated_pair(
         parse_variable_name,
         tag_no_case("=")
             .preceded_by(opt(parse_whitespace))
             .terminated(opt(parse_whitespace)),
-        parse_until_whitespace,
+        alt((
+            parse_list_value
+                .map(|items| VariableValue::List(items.into_iter().map(String::from).collect())),
+            parse_until_whitespace.map(|v| VariableValue::Single(v.into())),
+        )),
     )
     .map(|(name, value)| VariableAssignment {
         name: name.into(),
-        value: value.into(),
+        value,
     })
     .parse(input)
 }
 
-fn parse_variable_assignments(input: &str) -> IResult<&str, HashMap<String, String>> {
+fn parse_variable_assignment_list(input: &str) -> ParseResult<'_, Vec<VariableAssignment>> {
     separated_list0(parse_whitespace, parse_variable_assignment)
         .preceded_by(opt(parse_whitespace))
         .terminated(opt(parse_whitespace))
-        .map(|v| v.resolve_variables())
         .parse(input)
 }
 
-fn parse_config(input: &str) -> IResult<&str, ConfigurationFile> {
-    tuple((parse_variable_assignments, parse_input, parse_graph))
-        .map(|(variable_map, input_commands, graph)| ConfigurationFile {
-            input_commands: input_commands.expanded_from(&variable_map),
-            graph: graph.expanded_from(&variable_map),
-            variable_map,
-        })
+#[cfg(test)]
+fn parse_variable_assignments(input: &str) -> ParseResult<'_, HashMap<String, String>> {
+    parse_variable_assignment_list
+        .map(|v| VariableMap::resolve(v, &[]).values)
         .parse(input)
 }
 
-pub fn build_graph(input: &str) -> Result<Graph, Report> {
-    let (input, config) = parse_config(input)
-        .map_err(|e| Error::ConfigParseError {
-            message: format!("Nom error: {:?}", e),
-        })
-        .wrap_err("Failed to parse with nom")?;
+/// Parse the `file_types` section into the extensions it classifies
+fn parse_file_type_list(input: &str) -> ParseResult<'_, Vec<(FileType, &str)>> {
+    fn parse_kind(input: &str) -> ParseResult<'_, FileType> {
+        alt((
+            value(FileType::Header, tag_no_case("header")),
+            value(FileType::Source, tag_no_case("source")),
+        ))
+        .terminated(parse_whitespace)
+        .parse(input)
+    }
+
+    block_items(
+        tuple((
+            parse_kind,
+            many1(
+                parse_block_value
+                    .preceded_by(not(parse_kind))
+                    .terminated(opt(parse_whitespace)),
+            ),
+        )),
+        tag_no_case("}"),
+    )
+    .context("file_types")
+    .preceded_by(tuple((
+        opt(parse_whitespace),
+        tag_no_case("{"),
+        opt(parse_whitespace),
+    )))
+    .cut()
+    .preceded_by(tag_no_case("file_types"))
+    .terminated(opt(parse_whitespace))
+    .map(|lists| {
+        lists
+            .into_iter()
+            .flat_map(|(kind, extensions)| extensions.into_iter().map(move |e| (kind, e)))
+            .collect()
+    })
+    .parse(input)
+}
+
+fn parse_symlinks(input: &str) -> ParseResult<'_, SymlinkMode> {
+    alt((
+        block_items(parse_block_item, tag_no_case("}"))
+            .context("resolve_under")
+            .preceded_by(tuple((
+                tag_no_case("resolve_under"),
+                opt(parse_whitespace),
+                tag_no_case("{"),
+                opt(parse_whitespace),
+            )))
+            .map(|roots| SymlinkMode::ResolveUnder(roots.into_iter().map(PathBuf::from).collect())),
+        value(SymlinkMode::Resolve, tag_no_case("resolve")),
+        value(SymlinkMode::Lexical, tag_no_case("lexical")),
+    ))
+    .preceded_by(parse_whitespace)
+    .cut()
+    .preceded_by(tag_no_case("symlinks"))
+    .terminated(opt(parse_whitespace))
+    .parse(input)
+}
+
+/// Parse an `import "path"` statement, returning the path
+fn parse_import(input: &str) -> ParseResult<'_, &str> {
+    is_not("\"\n\r")
+        .preceded_by(tuple((
+            tag_no_case("import"),
+            parse_whitespace,
+            tag_no_case("\""),
+        )))
+        .terminated(tuple((tag_no_case("\""), opt(parse_whitespace))))
+        .parse(input)
+}
+
+fn parse_config(input: &str) -> ParseResult<'_, ConfigurationFile<'_>> {
+    const SECTIONS: &[&str] = &["file_types", "symlinks", "input", "graph"];
+
+    let (input, imports) = many0(parse_import)
+        .preceded_by(opt(parse_whitespace))
+        .parse(input)?;
+    let (input, variables) = parse_variable_assignment_list(input)?;
+    let (input, file_types) = opt(parse_file_type_list).parse(input)?;
+    let (input, symlinks) = opt(consumed(parse_symlinks)).parse(input)?;
+    let (input, input_commands) = opt(parse_input).parse(input)?;
+    let (input, graph) = opt(parse_graph).parse(input)?;
+
+    let (input, _) = or_expecting(
+        eof,
+        sections_after(
+            SECTIONS,
+            &[
+                file_types.is_some(),
+                symlinks.is_some(),
+                input_commands.is_some(),
+                graph.is_some(),
+            ],
+        ),
+    )
+    .parse(input)?;
+
+    Ok((
+        input,
+        ConfigurationFile {
+            imports,
+            variables,
+            file_types: file_types.unwrap_or_default(),
+            symlinks,
+            input_commands: input_commands.unwrap_or_default(),
+            graph: graph.unwrap_or_default(),
+        },
+    ))
+}
+
+/// Parse headers reached through includes that were not loaded yet, until
+/// no new headers are found or `max_depth` levels of includes were followed.
+///
+/// Only headers accepted by `should_follow` are parsed.
+fn follow_includes<F>(
+    dependency_data: &mut DependencyData,
+    max_depth: usize,
+    pool: &ThreadPool,
+    cache: &ScanCache,
+    resolver: &PathResolver,
+    should_follow: F,
+) where
+    F: Fn(&Path) -> bool,
+{
+    let mut known = dependency_data
+        .files
+        .iter()
+        .map(|f| f.path.clone())
+        .collect::<HashSet<_>>();
+
+    // files whose includes were not followed yet
+    let mut unfollowed = 0..dependency_data.files.len();
 
-    if !input.is_empty() {
-        return Err(Error::ConfigParseError {
-            message: format!("Not all input was consumed: {:?}", input),
+    for depth in 1..=max_depth {
+        // headers are parsed with the settings of the first file that reached them
+        let mut pending = Vec::new();
+        for f in dependency_data.files[unfollowed.clone()].iter() {
+            let settings = dependency_data
+                .parse_settings
+                .get(&f.path)
+                .cloned()
+                .unwrap_or_else(|| {
+                    Arc::new(ParseSettings {
+                        include_path: dependency_data.includes.clone(),
+                        preprocessor: dependency_data.preprocessor.clone(),
+                    })
+                });
+            for include in f.includes.iter().chain(f.optional_includes.iter()) {
+                if should_follow(&include.path) && known.insert(include.path.clone()) {
+                    pending.push((include.path.clone(), settings.clone()));
+                }
+            }
+        }
+
+        if pending.is_empty() {
+            break;
+        }
+        info!(target: "follow-includes", "Depth {}: parsing {} new headers", depth, pending.len());
+
+        let start = dependency_data.files.len();
+        let results = pool.install(|| {
+            pending
+                .into_par_iter()
+                .map(|(path, settings)| {
+                    let result = cache.extract_includes(
+                        &path,
+                        &settings.include_path,
+                        &settings.preprocessor,
+                        resolver,
+                    );
+                    (path, settings, result)
+                })
+                .collect::<Vec<_>>()
+        });
+
+        for (path, settings, result) in results {
+            match result {
+                Ok(source) => {
+                    dependency_data.parse_settings.insert(path, settings);
+                    dependency_data.files.push(source);
+                }
+                Err(e) => error!("Include extraction for {:?} failed: {:?}", &path, e),
+            }
         }
-        .into());
+        unfollowed = start..dependency_data.files.len();
+    }
+}
+
+/// Parse a source loaded from a compile database.
+///
+/// Sources use their own include path in compiler order, falling back to
+/// the globally configured directories. Their defines are applied on top
+/// of the global ones.
+///
+/// Returns the settings used together with the parse result.
+fn extract_entry_includes(
+    entry: &SourceFileEntry,
+    includes: &IncludePath,
+    preprocessor: &PreprocessorConfig,
+    cache: &ScanCache,
+    resolver: &PathResolver,
+) -> (ParseSettings, Result<SourceWithIncludes, Error>) {
+    let mut include_path = IncludePath::new(entry.include_directories.iter().cloned());
+    include_path.append(includes);
+
+    let mut preprocessor = preprocessor.clone();
+    for flag in entry.defines.iter() {
+        preprocessor.defines.apply(flag);
+    }
+    // forced includes behave as if included before the first line
+    if !entry.forced_includes.is_empty() {
+        let forced = macros_from_includes(
+            &entry.forced_includes,
+            &include_path,
+            &preprocessor,
+            resolver,
+        );
+        preprocessor.defines.extend(&forced);
     }
 
+    let result = cache
+        .extract_includes(&entry.file_path, &include_path, &preprocessor, resolver)
+        .map(|mut source| {
+            let forced = entry.forced_includes.iter().map(|path| Include {
+                path: path.clone(),
+                directive: IncludeDirective {
+                    line: 0,
+                    column: 0,
+                    spelling: format!("-include {}", path.display()),
+                    style: IncludeStyle::Quoted,
+                },
+            });
+            source.includes.splice(0..0, forced);
+            source
+        });
+
+    (
+        ParseSettings {
+            include_path,
+            preprocessor,
+        },
+        result,
+    )
+}
+
+/// Write the includes of all parsed files that could not be resolved into `path`.
+///
+/// Includes are classified as system headers if they are in the configured
+/// `system_headers` or exist in any system include directory.
+fn write_unresolved_report(
+    data: &DependencyData,
+    path: &Path,
+    format: ReportFormat,
+) -> Result<(), Report> {
+    let system_directories = std::iter::once(&data.includes)
+        .chain(data.parse_settings.values().map(|s| &s.include_path))
+        .flat_map(|p| p.directories())
+        .filter(|d| d.kind == IncludeDirectoryKind::System)
+        .map(|d| d.path.clone())
+        .collect::<BTreeSet<_>>();
+
+    let report = UnresolvedReport::new(
+        data.files.iter(),
+        &SystemHeaders::new(data.system_headers.iter().cloned(), system_directories),
+    );
+
+    std::fs::write(path, report.render(format)?)?;
+
+    Ok(())
+}
+
+/// Build a graph as described by the configuration file contents in `input`.
+///
+/// Paths are canonicalized through `resolver`, using the symlink mode of the
+/// configuration.
+pub fn build_graph(
+    input: &str,
+    options: &BuildOptions,
+    resolver: &PathResolver,
+) -> Result<Graph, Report> {
+    let mut loader = ConfigLoader::new(&options.variables);
+    loader.load_root(
+        options
+            .config_path
+            .as_deref()
+            .unwrap_or(Path::new("<config>")),
+        input,
+    )?;
+    let config = loader.finish()?;
+
     debug!("Variables: {:#?}", config.variable_map);
+    debug!("Symlinks:  {:?}", config.symlinks);
     debug!("Input:     {:#?}", config.input_commands);
-    debug!("Graph:     {:#?}", config.graph);
+    debug!("Map:       {:#?}", config.map_instructions);
+    debug!("Groups:    {:#?}", config.group_instructions);
+
+    resolver.set_mode(config.symlinks.clone());
+
+    // set up a path mapper. Generated roots apply to all inputs (wherever
+    // they are declared) and are mapped first, as they are often below
+    // mapped source directories.
+    let mut mapper = PathMapper::default();
+    let mut generated_prefixes = Vec::new();
+    for i in config.input_commands.iter() {
+        if let InputCommand::GeneratedRoot { dir, display } = &i.instruction {
+            if let Err(e) = resolver.add_generated_root(dir) {
+                error!("Invalid generated root {}: {:?}", dir, e);
+                continue;
+            }
+            mapper.add_mapping(PathMapping {
+                from: normalize_lexically(Path::new(dir)).wrap_err("Invalid generated root")?,
+                to: display.clone(),
+            });
+            generated_prefixes.push(display.clone());
+        }
+    }
+    for i in config.map_instructions.iter() {
+        if let MapInstruction::DisplayMap { from, to } = i {
+            mapper.add_mapping(PathMapping {
+                from: PathBuf::from(from),
+                to: to.clone(),
+            });
+        }
+    }
+    let keep = config
+        .map_instructions
+        .iter()
+        .filter_map(|i| match i {
+            MapInstruction::Keep(v) => Some(v),
+            _ => None,
+        })
+        .chain(generated_prefixes.iter())
+        .collect::<HashSet<_>>();
+
+    let drop = config
+        .map_instructions
+        .iter()
+        .filter_map(|i| match i {
+            MapInstruction::Drop(v) => Some(v),
+            _ => None,
+        })
+        .collect::<HashSet<_>>();
+
+    // Only mapped paths that match some `keep` and no `drop` prefix are used
+    let is_kept = |mapped: &str| {
+        keep.iter().any(|prefix| mapped.starts_with(*prefix))
+            && drop.iter().all(|prefix| !mapped.starts_with(*prefix))
+    };
+
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(options.jobs.unwrap_or_default())
+        .build()
+        .wrap_err("Failed to create the worker pool")?;
+
+    let cache = match options.cache {
+        Some(ref path) => ScanCache::load(path),
+        None => ScanCache::default(),
+    };
 
     let mut dependency_data = DependencyData::default();
 
-    for i in config.input_commands {
-        match i {
+    for Sourced { file, instruction } in config.input_commands {
+        // name the config file of the instruction in all diagnostics (an error
+        // level span is shown whenever any diagnostic is)
+        let _config = error_span!("config", file = %file.display()).entered();
+        match instruction {
             InputCommand::LoadCompileDb {
                 path,
                 load_include_directories,
                 load_sources,
+                options,
             } => {
-                let entries = match parse_compile_database(&path) {
-                    Ok(entries) => entries,
-                    Err(err) => {
-                        error!("Error parsing compile database {}: {:?}", path, err);
-                        continue;
-                    }
-                };
+                let entries =
+                    match parse_compile_database(&path, &config.file_types, &options, resolver) {
+                        Ok(entries) => entries,
+                        Err(err) if options.strict => {
+                            return Err(Report::new(err).wrap_err(format!(
+                                "Failed to load compile database {} (from {})",
+                                path,
+                                file.display()
+                            )));
+                        }
+                        Err(err) => {
+                            error!("Error parsing compile database {}: {:?}", path, err);
+                            continue;
+                        }
+                    };
                 if entries.is_empty() {
                     warn!(target: "compile-db", "No entries loaded from {}", path);
                     warn!(target: "compile-db",
                     "This may happen if directory/source paths are not correct (e.g. compilation on a different system)."
                     );
+                    warn!(target: "compile-db",
+                        "Paths of databases from other systems can be adjusted with `rewrite <from> => <to>` (or `remap` for Windows paths)."
+                    );
                     warn!(target: "compile-db",
                         "Consider debugging by setting RUST_LOG=\"compile-db=debug\" to debug entry parsing."
                     );
                 }
                 info!(target: "compile-db", "Loaded {} compile entries from {}", entries.len(), &path);
                 if load_include_directories {
-                    let compile_db_includes = entries
-                        .iter()
-                        .flat_map(|e| e.include_directories.clone())
-                        .collect::<HashSet<_>>();
+                    let compile_db_includes = IncludePath::new(
+                        entries
+                            .iter()
+                            .flat_map(|e| e.include_directories.iter().cloned()),
+                    );
                     info!(target: "compile-db",
                             "Include directories from {}: {:#?}", &path, compile_db_includes);
 
-                    dependency_data.includes.extend(compile_db_includes);
+                    dependency_data.includes.append(&compile_db_includes);
                 }
                 if load_sources {
-                    let includes_array = dependency_data
-                        .includes
-                        .clone()
-                        .into_iter()
-                        .collect::<Vec<_>>();
-                    for entry in entries {
-                        match extract_includes(&entry.file_path, &includes_array) {
-                            Ok(includes) => {
-                                info!(target: "compile-db", "Loaded {:?} with includes {:#?}", &entry.file_path, includes);
-                                dependency_data.files.push(SourceWithIncludes {
-                                    path: entry.file_path,
-                                    includes,
-                                });
+                    let results = pool.install(|| {
+                        entries
+                            .par_iter()
+                            .map(|entry| {
+                                extract_entry_includes(
+                                    entry,
+                                    &dependency_data.includes,
+                                    &dependency_data.preprocessor,
+                                    &cache,
+                                    resolver,
+                                )
+                            })
+                            .collect::<Vec<_>>()
+                    });
+
+                    for (entry, (settings, result)) in entries.iter().zip(results) {
+                        match result {
+                            Ok(source) => {
+                                info!(target: "compile-db", "Loaded {:?} with includes {:#?}", &entry.file_path, source.includes);
+                                dependency_data
+                                    .parse_settings
+                                    .insert(source.path.clone(), Arc::new(settings));
+                                dependency_data.files.push(source);
                             }
                             Err(e) => {
                                 error!(
@@ -838,9 +2567,79 @@ pub fn build_graph(input: &str) -> Result<Graph, Report> {
                     }
                 }
             }
+            InputCommand::LoadDepfiles { glob, root } => {
+                let paths = match glob::glob(&glob) {
+                    Ok(value) => value,
+                    Err(e) => {
+                        error!("Glob error for {}: {:?}", glob, e);
+                        continue;
+                    }
+                };
+                match load_depfiles(paths, Path::new(&root), &config.file_types, resolver) {
+                    Ok(sources) => {
+                        if sources.is_empty() {
+                            error!("No sources loaded from depfiles {:?}", glob);
+                        }
+                        dependency_data.files.extend(sources);
+                    }
+                    Err(e) => error!("Failed to load depfiles {}: {:?}", glob, e),
+                }
+            }
+            InputCommand::LoadNinjaDeps(build_dir) => {
+                match load_ninja_deps(Path::new(&build_dir), &config.file_types, resolver) {
+                    Ok(sources) => {
+                        if sources.is_empty() {
+                            error!("No sources loaded from ninja deps in {:?}", build_dir);
+                        }
+                        dependency_data.files.extend(sources);
+                    }
+                    Err(e) => error!("Failed to load ninja deps from {}: {:?}", build_dir, e),
+                }
+            }
+            InputCommand::LoadIncludeTrace { glob, format, root } => {
+                let paths = match glob::glob(&glob) {
+                    Ok(value) => value,
+                    Err(e) => {
+                        error!("Glob error for {}: {:?}", glob, e);
+                        continue;
+                    }
+                };
+                match load_include_traces(
+                    paths,
+                    format,
+                    root.as_deref().map(Path::new),
+                    &config.file_types,
+                    resolver,
+                ) {
+                    Ok(sources) => {
+                        if sources.is_empty() {
+                            error!("No sources loaded from include traces {:?}", glob);
+                        }
+                        dependency_data.files.extend(sources);
+                    }
+                    Err(e) => error!("Failed to load include traces {}: {:?}", glob, e),
+                }
+            }
             InputCommand::IncludeDirectory(path) => {
-                dependency_data.includes.insert(PathBuf::from(path));
+                dependency_data.includes.push(IncludeDirectory {
+                    path: PathBuf::from(path),
+                    kind: IncludeDirectoryKind::Regular,
+                });
+            }
+            InputCommand::Define(flag) => {
+                dependency_data.preprocessor.defines.apply(&flag);
+            }
+            InputCommand::UnknownConditions(policy) => {
+                dependency_data.preprocessor.unknown_conditions = policy;
+            }
+            InputCommand::FollowIncludes { depth } => {
+                dependency_data.follow_includes = Some(depth.unwrap_or(usize::MAX));
             }
+            InputCommand::SystemHeaders(names) => {
+                dependency_data.system_headers.extend(names);
+            }
+            // already registered with the resolver
+            InputCommand::GeneratedRoot { .. } => {}
             InputCommand::Glob(g) => {
                 let glob = match glob::glob(&g) {
                     Ok(value) => value,
@@ -849,16 +2648,29 @@ pub fn build_graph(input: &str) -> Result<Graph, Report> {
                         continue;
                     }
                 };
-                let includes_array = dependency_data
-                    .includes
-                    .clone()
-                    .into_iter()
-                    .collect::<Vec<_>>();
-                match all_sources_and_includes(glob, &includes_array) {
+                let settings = Arc::new(ParseSettings {
+                    include_path: dependency_data.includes.clone(),
+                    preprocessor: dependency_data.preprocessor.clone(),
+                });
+                match pool.install(|| {
+                    all_sources_and_includes(
+                        glob,
+                        &settings.include_path,
+                        &settings.preprocessor,
+                        &config.file_types,
+                        &cache,
+                        resolver,
+                    )
+                }) {
                     Ok(data) => {
                         if data.is_empty() {
                             error!("GLOB {:?} resulted in EMPTY file list!", g);
                         }
+                        for f in data.iter() {
+                            dependency_data
+                                .parse_settings
+                                .insert(f.path.clone(), settings.clone());
+                        }
                         dependency_data.files.extend(data)
                     }
                     Err(e) => {
@@ -870,35 +2682,35 @@ pub fn build_graph(input: &str) -> Result<Graph, Report> {
         }
     }
 
-    // set up a path mapper
-    let mut mapper = PathMapper::default();
-    for i in config.graph.map_instructions.iter() {
-        if let MapInstruction::DisplayMap { from, to } = i {
-            mapper.add_mapping(PathMapping {
-                from: PathBuf::from(from),
-                to: to.clone(),
-            });
-        }
+    if let Some(max_depth) = dependency_data.follow_includes {
+        follow_includes(
+            &mut dependency_data,
+            max_depth,
+            &pool,
+            &cache,
+            resolver,
+            |path| {
+                !resolver.is_virtual(path) && mapper.try_map(path).is_some_and(|to| is_kept(&to))
+            },
+        );
     }
-    let keep = config
-        .graph
-        .map_instructions
-        .iter()
-        .filter_map(|i| match i {
-            MapInstruction::Keep(v) => Some(v),
-            _ => None,
-        })
-        .collect::<HashSet<_>>();
 
-    let drop = config
-        .graph
-        .map_instructions
-        .iter()
-        .filter_map(|i| match i {
-            MapInstruction::Drop(v) => Some(v),
-            _ => None,
-        })
-        .collect::<HashSet<_>>();
+    info!(target: "path-resolver", "Path resolution: {}", resolver.stats());
+
+    if let Err(e) = cache.save() {
+        error!("Failed to save the scan cache: {:?}", e);
+    }
+
+    if let Some(ref path) = options.unresolved_report {
+        write_unresolved_report(&dependency_data, path, options.report_format).wrap_err_with(
+            || format!("Failed to write the unresolved include report {:?}", path),
+        )?;
+    }
+
+    let unresolved_computed = UnresolvedComputedIncludes::new(&dependency_data);
+    if !unresolved_computed.is_empty() {
+        warn!(target: "computed-include", "{}", unresolved_computed);
+    }
 
     info!(target: "full-file-list", "Procesed files: {}", FullFileList::new(&dependency_data));
 
@@ -907,30 +2719,41 @@ pub fn build_graph(input: &str) -> Result<Graph, Report> {
         dependency_data
             .files
             .iter()
-            .flat_map(|f| f.includes.iter().chain(std::iter::once(&f.path)))
+            .flat_map(|f| {
+                f.includes
+                    .iter()
+                    .chain(f.optional_includes.iter())
+                    .map(|i| &i.path)
+                    .chain(std::iter::once(&f.path))
+            })
             .filter_map(|path| {
                 mapper.try_map(path).map(|to| PathMapping {
                     from: path.clone(),
                     to,
                 })
             })
-            .filter(|m| keep.iter().any(|prefix| m.to.starts_with(*prefix)))
-            .filter(|m| drop.iter().all(|prefix| !m.to.starts_with(*prefix))),
+            .filter(|m| is_kept(&m.to)),
     );
+    g.mark_virtual(|path| resolver.is_virtual(path));
 
     // define all the groups
-    for group_instruction in config.graph.group_instructions {
-        match group_instruction {
+    for Sourced { file, instruction } in config.group_instructions {
+        let _config = error_span!("config", file = %file.display()).entered();
+        match instruction {
             GroupInstruction::GroupSourceHeader => {
-                g.group_extensions(&["h", "cpp", "hpp", "c", "cxx"]);
+                g.group_extensions(&config.file_types.extensions().collect::<Vec<_>>());
             }
             GroupInstruction::GroupFromGn {
                 gn_root,
                 target,
                 source_root,
                 ignore_targets,
-            } => match load_gn_targets(PathBuf::from(gn_root), PathBuf::from(source_root), &target)
-            {
+            } => match load_gn_targets(
+                PathBuf::from(gn_root),
+                PathBuf::from(source_root),
+                &target,
+                resolver,
+            ) {
                 Ok(targets) => g.add_groups_from_gn(targets, ignore_targets),
                 Err(e) => error!("Failed to load GN targets: {:?}", e),
             },
@@ -947,7 +2770,7 @@ pub fn build_graph(input: &str) -> Result<Graph, Report> {
     }
 
     // mark what is zoomed in ...
-    for item in config.graph.zoom_items {
+    for item in config.zoom_items {
         g.zoom_in(&item.name, item.focused)
     }
 
@@ -955,15 +2778,21 @@ pub fn build_graph(input: &str) -> Result<Graph, Report> {
         if !g.known_path(&dep.path) {
             continue;
         }
-        for dest in dep.includes {
-            if !g.known_path(&dest) {
+        for include in dep.includes {
+            if !g.known_path(&include.path) {
+                continue;
+            }
+            g.add_link(&dep.path, &include.path, &include.directive);
+        }
+        for include in dep.optional_includes {
+            if !g.known_path(&include.path) {
                 continue;
             }
-            g.add_link(&dep.path, &dest);
+            g.add_optional_link(&dep.path, &include.path, &include.directive);
         }
     }
 
-    for i in config.graph.color_instructions {
+    for i in config.color_instructions {
         match i.end {
             GroupEdgeEnd::From(name) => {
                 g.color_from(&name, i.color.color_name(), i.color.is_bold())
@@ -980,10 +2809,21 @@ pub fn build_graph(input: &str) -> Result<Graph, Report> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::dependencies::test_util::TempTree;
+
+    /// The items parsed by `result`, without the text they were parsed from
+    fn items<'a, T>(result: ParseResult<'a, Vec<Parsed<'a, T>>>) -> Option<(&'a str, Vec<T>)> {
+        result
+            .ok()
+            .map(|(rest, items)| (rest, items.into_iter().map(|(_, item)| item).collect()))
+    }
 
     #[test]
     fn test_comment_parsing() {
-        assert_eq!(parse_comment("#abc\r\nhello"), Ok(("\r\nhello", "abc")));
+        assert_eq!(
+            parse_comment("#abc\r\nhello").ok(),
+            Some(("\r\nhello", "abc"))
+        );
         assert!(parse_comment("not a comment").is_err());
         assert!(parse_comment("comment later # like here").is_err());
     }
@@ -991,8 +2831,8 @@ mod tests {
     #[test]
     fn test_gn_target() {
         assert_eq!(
-            parse_gn_target("gn root test1 target //my/target/* sources srcs1"),
-            Ok((
+            parse_gn_target("gn root test1 target //my/target/* sources srcs1").ok(),
+            Some((
                 "",
                 GroupInstruction::GroupFromGn {
                     gn_root: "test1".into(),
@@ -1004,8 +2844,9 @@ mod tests {
         );
 
         assert_eq!(
-            parse_gn_target("gn root test1 target //my/target/* sources srcs1 ignore targets {}"),
-            Ok((
+            parse_gn_target("gn root test1 target //my/target/* sources srcs1 ignore targets {}")
+                .ok(),
+            Some((
                 "",
                 GroupInstruction::GroupFromGn {
                     gn_root: "test1".into(),
@@ -1020,8 +2861,9 @@ mod tests {
             parse_gn_target(
                 "gn root test1 target //my/target/* sources srcs1 ignore targets{
             }"
-            ),
-            Ok((
+            )
+            .ok(),
+            Some((
                 "",
                 GroupInstruction::GroupFromGn {
                     gn_root: "test1".into(),
@@ -1039,8 +2881,9 @@ mod tests {
                 c
                 d
             }"
-            ),
-            Ok((
+            )
+            .ok(),
+            Some((
                 "",
                 GroupInstruction::GroupFromGn {
                     gn_root: "test1".into(),
@@ -1066,8 +2909,9 @@ mod tests {
                 another/file::test
             }
             "
-            ),
-            Ok((
+            )
+            .ok(),
+            Some((
                 "",
                 GroupInstruction::ManualGroup {
                     name: "some/name::special".into(),
@@ -1086,8 +2930,9 @@ mod tests {
                 another/file::test
             }
             "
-            ),
-            Ok((
+            )
+            .ok(),
+            Some((
                 "",
                 GroupInstruction::ManualGroup {
                     name: "some/name::special".into(),
@@ -1100,8 +2945,8 @@ mod tests {
 
     #[test]
     fn test_gn_instruction() {
-        let mut variable_map = HashMap::new();
-        variable_map.insert("Foo".into(), "Bar".into());
+        let mut variable_map = VariableMap::default();
+        variable_map.values.insert("Foo".into(), "Bar".into());
 
         assert_eq!(
             parse_graph(
@@ -1120,32 +2965,37 @@ mod tests {
         }
         ",
             )
-            .map(|(r, g)| { (r, g.expanded_from(&variable_map)) }),
-            Ok((
+            .map(|(r, g)| {
+                let groups = g.group_instructions.into_iter().map(|(_, i)| i);
+                (
+                    r,
+                    g.map_instructions.is_empty(),
+                    groups.collect::<Vec<_>>().expanded_from(&variable_map),
+                )
+            })
+            .ok(),
+            Some((
                 "",
-                GraphInstructions {
-                    map_instructions: Vec::default(),
-                    group_instructions: vec![
-                        GroupInstruction::GroupFromGn {
-                            gn_root: "test1".into(),
-                            target: "//my/target/*".into(),
-                            source_root: "srcs1".into(),
-                            ignore_targets: HashSet::new(),
-                        },
-                        GroupInstruction::GroupFromGn {
-                            gn_root: "test/Bar/blah".into(),
-                            target: "//*".into(),
-                            source_root: "Bar".into(),
-                            ignore_targets: {
-                                let mut h = HashSet::new();
-                                h.insert("//ignore1".into());
-                                h.insert("//ignore:other".into());
-                                h
-                            }
-                        },
-                    ],
-                    ..Default::default()
-                }
+                true,
+                vec![
+                    GroupInstruction::GroupFromGn {
+                        gn_root: "test1".into(),
+                        target: "//my/target/*".into(),
+                        source_root: "srcs1".into(),
+                        ignore_targets: HashSet::new(),
+                    },
+                    GroupInstruction::GroupFromGn {
+                        gn_root: "test/Bar/blah".into(),
+                        target: "//*".into(),
+                        source_root: "Bar".into(),
+                        ignore_targets: {
+                            let mut h = HashSet::new();
+                            h.insert("//ignore1".into());
+                            h.insert("//ignore:other".into());
+                            h
+                        }
+                    },
+                ],
             ))
         );
     }
@@ -1153,8 +3003,8 @@ mod tests {
     #[test]
     fn test_color_instruction_parsing() {
         assert_eq!(
-            parse_color_instruction("from source color"),
-            Ok((
+            parse_color_instruction("from source color").ok(),
+            Some((
                 "",
                 ColorInstruction {
                     end: GroupEdgeEnd::From("source".into()),
@@ -1164,8 +3014,8 @@ mod tests {
         );
 
         assert_eq!(
-            parse_color_instruction("to destination color"),
-            Ok((
+            parse_color_instruction("to destination color").ok(),
+            Some((
                 "",
                 ColorInstruction {
                     end: GroupEdgeEnd::To("destination".into()),
@@ -1175,8 +3025,8 @@ mod tests {
         );
 
         assert_eq!(
-            parse_color_instruction("From x y"),
-            Ok((
+            parse_color_instruction("From x y").ok(),
+            Some((
                 "",
                 ColorInstruction {
                     end: GroupEdgeEnd::From("x".into()),
@@ -1186,8 +3036,8 @@ mod tests {
         );
 
         assert_eq!(
-            parse_color_instruction("#comment\n  TO a bold b"),
-            Ok((
+            parse_color_instruction("#comment\n  TO a bold b").ok(),
+            Some((
                 "",
                 ColorInstruction {
                     end: GroupEdgeEnd::To("a".into()),
@@ -1200,16 +3050,18 @@ mod tests {
     #[test]
     fn test_color_instructions_parsing() {
         assert_eq!(
-            parse_color_instructions("color edges {}"),
-            Ok(("", Vec::default()))
+            items(parse_color_instructions("color edges {}")),
+            Some(("", Vec::default()))
         );
         assert_eq!(
-            parse_color_instructions(" #comment\ncolor edges {  \n  }\n#more comments\n   \n"),
-            Ok(("", Vec::default()))
+            items(parse_color_instructions(
+                " #comment\ncolor edges {  \n  }\n#more comments\n   \n"
+            )),
+            Some(("", Vec::default()))
         );
 
         assert_eq!(
-            parse_color_instructions(
+            items(parse_color_instructions(
                 "
          #comment
          color edges {
@@ -1217,8 +3069,8 @@ mod tests {
             to q r
             from a bold b
          }"
-            ),
-            Ok((
+            )),
+            Some((
                 "",
                 vec![
                     ColorInstruction {
@@ -1238,7 +3090,7 @@ mod tests {
         );
 
         assert_eq!(
-            parse_zoom(
+            items(parse_zoom(
                 "
          #comment
          zoom{
@@ -1246,8 +3098,8 @@ mod tests {
             focus: thisone
             not this
          }"
-            ),
-            Ok((
+            )),
+            Some((
                 "",
                 vec![
                     ZoomItem {
@@ -1275,14 +3127,16 @@ mod tests {
 
     #[test]
     fn test_zoom_parsing() {
-        assert_eq!(parse_zoom("zoom{}"), Ok(("", Vec::default())));
+        assert_eq!(items(parse_zoom("zoom{}")), Some(("", Vec::default())));
         assert_eq!(
-            parse_zoom(" #comment\nzoom {  \n  }\n#more comments\n   \n"),
-            Ok(("", Vec::default()))
+            items(parse_zoom(
+                " #comment\nzoom {  \n  }\n#more comments\n   \n"
+            )),
+            Some(("", Vec::default()))
         );
 
         assert_eq!(
-            parse_zoom(
+            items(parse_zoom(
                 "
          #comment
          zoom{
@@ -1290,8 +3144,8 @@ mod tests {
             is some #notice that whitespace matters and NOT newlines
             test
          }"
-            ),
-            Ok((
+            )),
+            Some((
                 "",
                 vec![
                     ZoomItem {
@@ -1315,7 +3169,7 @@ mod tests {
         );
 
         assert_eq!(
-            parse_zoom(
+            items(parse_zoom(
                 "
          #comment
          zoom{
@@ -1323,8 +3177,8 @@ mod tests {
             focus: thisone
             not this
          }"
-            ),
-            Ok((
+            )),
+            Some((
                 "",
                 vec![
                     ZoomItem {
@@ -1352,78 +3206,286 @@ mod tests {
 
     #[test]
     fn test_parse_target_list() {
-        assert_eq!(parse_target_list(""), Ok(("", vec![])));
-        assert_eq!(parse_target_list("    "), Ok(("", vec![])));
-        assert_eq!(parse_target_list("a b c"), Ok(("", vec!["a", "b", "c"])));
+        assert_eq!(parse_target_list("").ok(), Some(("", vec![])));
+        assert_eq!(parse_target_list("    ").ok(), Some(("", vec![])));
         assert_eq!(
-            parse_target_list("  a  \n\n   b\n   c\n\n"),
-            Ok(("", vec!["a", "b", "c"]))
+            parse_target_list("a b c").ok(),
+            Some(("", vec!["a", "b", "c"]))
+        );
+        assert_eq!(
+            parse_target_list("  a  \n\n   b\n   c\n\n").ok(),
+            Some(("", vec!["a", "b", "c"]))
         );
         // should not consume the ending brace
-        assert_eq!(parse_target_list("}"), Ok(("}", vec![])));
-        assert_eq!(parse_target_list("a b c }"), Ok(("}", vec!["a", "b", "c"])));
+        assert_eq!(parse_target_list("}").ok(), Some(("}", vec![])));
+        assert_eq!(
+            parse_target_list("a b c }").ok(),
+            Some(("}", vec!["a", "b", "c"]))
+        );
     }
 
     #[test]
     fn test_parse_glob() {
         assert_eq!(
-            parse_input_command("glob a/b/**/*"),
-            Ok(("", InputCommand::Glob("a/b/**/*".into())))
+            parse_input_command("glob a/b/**/*").ok(),
+            Some(("", InputCommand::Glob("a/b/**/*".into())))
         );
 
         assert_eq!(
-            parse_input_command("glob a/x/**/*.h # should consume whitespace\n\n  \n\t\n  "),
-            Ok(("", InputCommand::Glob("a/x/**/*.h".into())))
+            parse_input_command("glob a/x/**/*.h # should consume whitespace\n\n  \n\t\n  ").ok(),
+            Some(("", InputCommand::Glob("a/x/**/*.h".into())))
         );
     }
 
     #[test]
     fn test_parse_include_dir() {
         assert_eq!(
-            parse_input_command("include_dir a/b/**/*"),
-            Ok(("", InputCommand::IncludeDirectory("a/b/**/*".into())))
+            parse_input_command("include_dir a/b/**/*").ok(),
+            Some(("", InputCommand::IncludeDirectory("a/b/**/*".into())))
+        );
+
+        assert_eq!(
+            parse_input_command("include_dir a/x/**/*.h # should consume whitespace\n\n  \n\t\n  ")
+                .ok(),
+            Some(("", InputCommand::IncludeDirectory("a/x/**/*.h".into())))
+        );
+    }
+
+    #[test]
+    fn test_parse_follow_includes() {
+        assert_eq!(
+            parse_input_command("follow_includes").ok(),
+            Some(("", InputCommand::FollowIncludes { depth: None }))
+        );
+        assert_eq!(
+            parse_input_command("follow_includes # comment\nglob x").ok(),
+            Some(("glob x", InputCommand::FollowIncludes { depth: None }))
+        );
+        assert_eq!(
+            parse_input_command("follow_includes depth 3\n").ok(),
+            Some(("", InputCommand::FollowIncludes { depth: Some(3) }))
+        );
+    }
+
+    #[test]
+    fn test_follow_includes_settings() {
+        let dir = TempTree::new(&[
+            ("main.cpp", "#include \"a.h\"\n"),
+            ("forced.h", "#define FROM_FORCED 1\n"),
+            (
+                "a.h",
+                "#ifdef USE_B\n#include \"b.h\"\n#else\n#include \"c.h\"\n#endif\n#if FROM_FORCED\n#include \"d.h\"\n#endif\n",
+            ),
+            ("b.h", ""),
+            ("c.h", ""),
+            ("d.h", ""),
+        ]);
+        let entry = SourceFileEntry {
+            file_path: dir.join("main.cpp"),
+            include_directories: Vec::new(),
+            defines: vec![DefineFlag::define("USE_B")],
+            forced_includes: vec![dir.join("forced.h")],
+            output: None,
+            arguments: Vec::new(),
+        };
+        let cache = ScanCache::default();
+        let resolver = PathResolver::default();
+
+        let (settings, source) = extract_entry_includes(
+            &entry,
+            &IncludePath::default(),
+            &PreprocessorConfig::default(),
+            &cache,
+            &resolver,
+        );
+        let source = source.unwrap();
+        let mut data = DependencyData {
+            parse_settings: HashMap::from([(source.path.clone(), Arc::new(settings))]),
+            files: vec![source],
+            ..Default::default()
+        };
+
+        let pool = ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        follow_includes(&mut data, 1, &pool, &cache, &resolver, |_| true);
+
+        // the header sees the defines and forced includes of the source reaching it
+        let header = data
+            .files
+            .iter()
+            .find(|f| f.path == dir.join("a.h"))
+            .unwrap();
+        assert_eq!(
+            header
+                .includes
+                .iter()
+                .map(|i| i.path.clone())
+                .collect::<Vec<_>>(),
+            vec![dir.join("b.h"), dir.join("d.h")]
         );
+    }
 
+    #[test]
+    fn test_parse_deps() {
+        assert!(matches!(
+            parse_input_command("from depfiles out/**/*.d\nglob x"),
+            Err(nom::Err::Failure(_))
+        ));
+        assert_eq!(
+            parse_input_command("from depfiles out/**/*.d root out\n").ok(),
+            Some((
+                "",
+                InputCommand::LoadDepfiles {
+                    glob: "out/**/*.d".into(),
+                    root: "out".into()
+                }
+            ))
+        );
         assert_eq!(
-            parse_input_command("include_dir a/x/**/*.h # should consume whitespace\n\n  \n\t\n  "),
-            Ok(("", InputCommand::IncludeDirectory("a/x/**/*.h".into())))
+            parse_input_command("from ninja_deps out/debug # comment\n").ok(),
+            Some(("", InputCommand::LoadNinjaDeps("out/debug".into())))
+        );
+        assert_eq!(
+            parse_input_command("from include_trace logs/*.log\n").ok(),
+            Some((
+                "",
+                InputCommand::LoadIncludeTrace {
+                    glob: "logs/*.log".into(),
+                    format: TraceFormat::Auto,
+                    root: None,
+                }
+            ))
+        );
+        assert_eq!(
+            parse_input_command("from include_trace out/*.i format line_markers root out\n").ok(),
+            Some((
+                "",
+                InputCommand::LoadIncludeTrace {
+                    glob: "out/*.i".into(),
+                    format: TraceFormat::LineMarkers,
+                    root: Some("out".into()),
+                }
+            ))
         );
     }
 
+    #[test]
+    fn test_parse_system_headers() {
+        assert_eq!(
+            parse_input_command("system_headers {\n  jni.h\n  # comment\n  linux/\n}\nglob x").ok(),
+            Some((
+                "glob x",
+                InputCommand::SystemHeaders(vec!["jni.h".into(), "linux/".into()])
+            ))
+        );
+        assert_eq!(
+            parse_input_command("system_headers {}").ok(),
+            Some(("", InputCommand::SystemHeaders(vec![])))
+        );
+    }
+
+    #[test]
+    fn test_parse_generated_root() {
+        assert_eq!(
+            parse_input_command("generated_root ${OUT}/gen => gen:: # comment\nglob x").ok(),
+            Some((
+                "glob x",
+                InputCommand::GeneratedRoot {
+                    dir: "${OUT}/gen".into(),
+                    display: "gen::".into(),
+                }
+            ))
+        );
+        assert!(parse_input_command("generated_root out/gen").is_err());
+    }
+
+    #[test]
+    fn test_parse_define() {
+        assert_eq!(
+            parse_input_command("define FOO=${BAR} # comment\n").ok(),
+            Some((
+                "",
+                InputCommand::Define(DefineFlag::Define {
+                    name: "FOO".into(),
+                    value: "${BAR}".into()
+                })
+            ))
+        );
+
+        assert_eq!(
+            parse_input_command("define FOO").ok(),
+            Some((
+                "",
+                InputCommand::Define(DefineFlag::Define {
+                    name: "FOO".into(),
+                    value: "1".into()
+                })
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_unknown_conditions() {
+        assert_eq!(
+            parse_input_command("unknown_conditions taken").ok(),
+            Some((
+                "",
+                InputCommand::UnknownConditions(UnknownConditionPolicy::Taken)
+            ))
+        );
+        assert_eq!(
+            parse_input_command("unknown_conditions not_taken\n").ok(),
+            Some((
+                "",
+                InputCommand::UnknownConditions(UnknownConditionPolicy::NotTaken)
+            ))
+        );
+        assert_eq!(
+            parse_input_command("unknown_conditions both").ok(),
+            Some((
+                "",
+                InputCommand::UnknownConditions(UnknownConditionPolicy::Both)
+            ))
+        );
+        assert!(parse_input_command("unknown_conditions maybe").is_err());
+    }
+
     #[test]
     fn test_parse_compiledb() {
         assert_eq!(
-            parse_compiledb("from compiledb foo load include_dirs"),
-            Ok((
+            parse_compiledb("from compiledb foo load include_dirs").ok(),
+            Some((
                 "",
                 InputCommand::LoadCompileDb {
                     path: "foo".into(),
                     load_include_directories: true,
-                    load_sources: false
+                    load_sources: false,
+                    options: CompileDbOptions::default(),
                 }
             ))
         );
 
         assert_eq!(
-            parse_compiledb("from compiledb bar load sources"),
-            Ok((
+            parse_compiledb("from compiledb bar load sources").ok(),
+            Some((
                 "",
                 InputCommand::LoadCompileDb {
                     path: "bar".into(),
                     load_include_directories: false,
-                    load_sources: true
+                    load_sources: true,
+                    options: CompileDbOptions::default(),
                 }
             ))
         );
 
         assert_eq!(
-            parse_compiledb("from compiledb bar load sources, include_dirs, sources"),
-            Ok((
+            parse_compiledb("from compiledb bar load sources, include_dirs, sources").ok(),
+            Some((
                 "",
                 InputCommand::LoadCompileDb {
                     path: "bar".into(),
                     load_include_directories: true,
-                    load_sources: true
+                    load_sources: true,
+                    options: CompileDbOptions::default(),
                 }
             ))
         );
@@ -1433,13 +3495,15 @@ mod tests {
                 "
                 # This is a comment (and whitespace) prefix
                 from compiledb x/y/z load sources, sources\n# space/comment suffix\n"
-            ),
-            Ok((
+            )
+            .ok(),
+            Some((
                 "",
                 InputCommand::LoadCompileDb {
                     path: "x/y/z".into(),
                     load_include_directories: false,
-                    load_sources: true
+                    load_sources: true,
+                    options: CompileDbOptions::default(),
                 }
             ))
         );
@@ -1448,22 +3512,156 @@ mod tests {
             parse_compiledb(
                 "
                 from compiledb x/y/z load sources, sources\nremaining"
-            ),
-            Ok((
+            )
+            .ok(),
+            Some((
                 "remaining",
                 InputCommand::LoadCompileDb {
                     path: "x/y/z".into(),
                     load_include_directories: false,
-                    load_sources: true
+                    load_sources: true,
+                    options: CompileDbOptions::default(),
                 }
             ))
         );
     }
 
+    #[test]
+    fn test_parse_compiledb_remap() {
+        assert_eq!(
+            parse_compiledb(
+                "from compiledb win.json load sources remap C:\\src => ${ROOT} remap D:\\ => /mnt/d\n"
+            ).ok(),
+            Some((
+                "",
+                InputCommand::LoadCompileDb {
+                    path: "win.json".into(),
+                    load_include_directories: false,
+                    load_sources: true,
+                    options: CompileDbOptions {
+                        windows_remaps: vec![
+                            WindowsPathRemap {
+                                from: "C:\\src".into(),
+                                to: "${ROOT}".into(),
+                            },
+                            WindowsPathRemap {
+                                from: "D:\\".into(),
+                                to: "/mnt/d".into(),
+                            },
+                        ],
+                        ..Default::default()
+                    },
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_compiledb_rewrite() {
+        assert_eq!(
+            parse_compiledb(
+                "from compiledb ci.json load include_dirs rewrite /home/ci/work => ${SOURCE_ROOT}\n   rewrite /opt/ci => /opt remap C:\\ => /c"
+            ).ok(),
+            Some((
+                "",
+                InputCommand::LoadCompileDb {
+                    path: "ci.json".into(),
+                    load_include_directories: true,
+                    load_sources: false,
+                    options: CompileDbOptions {
+                        windows_remaps: vec![WindowsPathRemap {
+                            from: "C:\\".into(),
+                            to: "/c".into(),
+                        }],
+                        rewrites: vec![
+                            PathRewrite {
+                                from: "/home/ci/work".into(),
+                                to: "${SOURCE_ROOT}".into(),
+                            },
+                            PathRewrite {
+                                from: "/opt/ci".into(),
+                                to: "/opt".into(),
+                            },
+                        ],
+                        ..Default::default()
+                    },
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_compiledb_strict() {
+        assert_eq!(
+            parse_compiledb("from compiledb a.json load sources rewrite /ci => /src strict\n").ok(),
+            Some((
+                "",
+                InputCommand::LoadCompileDb {
+                    path: "a.json".into(),
+                    load_include_directories: false,
+                    load_sources: true,
+                    options: CompileDbOptions {
+                        rewrites: vec![PathRewrite {
+                            from: "/ci".into(),
+                            to: "/src".into(),
+                        }],
+                        strict: true,
+                        ..Default::default()
+                    },
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_compiledb_filters() {
+        assert_eq!(
+            parse_compiledb(
+                "from compiledb all.json load sources
+                   where file matches ${ROOT}/src/**
+                   where output matches **/arm/**
+                   where arg contains --target=arm-none-eabi
+                   duplicates output ${OUT}/arm
+                   strict"
+            )
+            .ok(),
+            Some((
+                "",
+                InputCommand::LoadCompileDb {
+                    path: "all.json".into(),
+                    load_include_directories: false,
+                    load_sources: true,
+                    options: CompileDbOptions {
+                        filters: vec![
+                            EntryFilter::FileMatches("${ROOT}/src/**".into()),
+                            EntryFilter::OutputMatches("**/arm/**".into()),
+                            EntryFilter::ArgContains("--target=arm-none-eabi".into()),
+                        ],
+                        duplicates: DuplicatePolicy::Output("${OUT}/arm".into()),
+                        strict: true,
+                        ..Default::default()
+                    },
+                }
+            ))
+        );
+
+        for (text, policy) in [
+            ("keep", DuplicatePolicy::Keep),
+            ("first", DuplicatePolicy::First),
+            ("union", DuplicatePolicy::Union),
+        ] {
+            assert_eq!(
+                parse_compiledb_clause(&format!("duplicates {}", text)).ok(),
+                Some(("", CompileDbClause::Duplicates(policy)))
+            );
+        }
+        assert!(parse_compiledb_clause("where nothing matches x").is_err());
+    }
+
     #[test]
     fn test_parse_input() {
         assert_eq!(
-            parse_input(
+            items(parse_input(
                 "input {
            from compiledb some_compile_db.json load include_dirs
            include_dir foo
@@ -1477,27 +3675,30 @@ mod tests {
            glob final/**/*
            glob blah/**/*
         }"
-            ),
-            Ok((
+            )),
+            Some((
                 "",
                 vec![
                     InputCommand::LoadCompileDb {
                         path: "some_compile_db.json".into(),
                         load_include_directories: true,
-                        load_sources: false
+                        load_sources: false,
+                        options: CompileDbOptions::default(),
                     },
                     InputCommand::IncludeDirectory("foo".into()),
                     InputCommand::LoadCompileDb {
                         path: "some_compile_db.json".into(),
                         load_include_directories: false,
-                        load_sources: true
+                        load_sources: true,
+                        options: CompileDbOptions::default(),
                     },
                     InputCommand::Glob("xyz/**/*".into()),
                     InputCommand::IncludeDirectory("bar".into()),
                     InputCommand::LoadCompileDb {
                         path: "another.json".into(),
                         load_include_directories: true,
-                        load_sources: true
+                        load_sources: true,
+                        options: CompileDbOptions::default(),
                     },
                     InputCommand::Glob("final/**/*".into()),
                     InputCommand::Glob("blah/**/*".into()),
@@ -1506,6 +3707,228 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_file_types() {
+        assert_eq!(
+            parse_file_type_list(
+                "file_types {
+           header hh hxx # comment
+                  inl
+           source cu .mm
+           header def
+        }
+        input"
+            )
+            .ok(),
+            Some((
+                "input",
+                vec![
+                    (FileType::Header, "hh"),
+                    (FileType::Header, "hxx"),
+                    (FileType::Header, "inl"),
+                    (FileType::Source, "cu"),
+                    (FileType::Source, ".mm"),
+                    (FileType::Header, "def"),
+                ]
+            ))
+        );
+        assert_eq!(
+            parse_file_type_list("file_types {}").ok(),
+            Some(("", Vec::new()))
+        );
+        assert!(parse_file_type_list("file_types { header }").is_err());
+
+        // loading extends the default file types
+        let mut expected = FileTypes::default();
+        for ext in ["hh", "hxx", "inl", "def"] {
+            expected.add(FileType::Header, ext);
+        }
+        for ext in ["cu", "mm"] {
+            expected.add(FileType::Source, ext);
+        }
+        let mut loader = ConfigLoader::default();
+        loader
+            .load_root(
+                Path::new("test.cfg"),
+                "more = [ inl def ]\nfile_types {\n  header hh hxx ${more}\n  source cu .mm\n}\n",
+            )
+            .unwrap();
+        assert_eq!(loader.finish().unwrap().file_types, expected);
+    }
+
+    #[test]
+    fn test_parse_symlinks() {
+        assert_eq!(
+            parse_symlinks("symlinks resolve\ninput").ok(),
+            Some(("input", SymlinkMode::Resolve))
+        );
+        assert_eq!(
+            parse_symlinks("symlinks lexical # comment\ninput").ok(),
+            Some(("input", SymlinkMode::Lexical))
+        );
+        assert_eq!(
+            parse_symlinks("symlinks resolve_under {\n  /a/b\n  ${ROOT}/c\n}\ninput").ok(),
+            Some((
+                "input",
+                SymlinkMode::ResolveUnder(vec!["/a/b".into(), "${ROOT}/c".into()])
+            ))
+        );
+        assert!(parse_symlinks("symlinks sometimes").is_err());
+    }
+
+    #[test]
+    fn test_parse_imports() {
+        let (rest, config) = parse_config(
+            "import \"common.cfg\"\nimport \"../other dir/x.cfg\"\n\nroot = /src\ngraph {\n}\n",
+        )
+        .unwrap();
+
+        assert_eq!(rest, "");
+        assert_eq!(config.imports, vec!["common.cfg", "../other dir/x.cfg"]);
+        assert_eq!(
+            config.variables,
+            vec![VariableAssignment {
+                name: "root".into(),
+                value: VariableValue::Single("/src".into())
+            }]
+        );
+
+        // a config may consist of any subset of sections
+        assert!(parse_config("").is_ok());
+        assert!(parse_config("graph {\n  color edges {\n  }\n}\n").is_ok());
+        assert!(parse_config("import common.cfg\n").is_err());
+    }
+
+    #[test]
+    fn test_config_imports() {
+        let dir = TempTree::new(&[
+            (
+                "common.cfg",
+                "root = /common\nname = common\ninput {\n  glob ${root}/*.h\n}\ngraph {\n  map {\n    ${root} => ${name}::\n  }\n}\n",
+            ),
+            (
+                "extra.cfg",
+                "import \"common.cfg\"\nsymlinks lexical\ngraph {\n  group {\n    group_source_header\n  }\n}\n",
+            ),
+            (
+                "sub/main.cfg",
+                "import \"../common.cfg\"\nimport \"../extra.cfg\"\nname = main\ninput {\n  glob ${root}/*.cc\n}\n",
+            ),
+        ]);
+
+        let main = dir.join("sub/main.cfg");
+        let mut loader = ConfigLoader::default();
+        loader
+            .load_root(&main, &std::fs::read_to_string(&main).unwrap())
+            .unwrap();
+        let config = loader.finish().unwrap();
+
+        // common.cfg is merged once, before the files importing it
+        assert_eq!(
+            config
+                .input_commands
+                .iter()
+                .map(|c| (c.file.to_path_buf(), c.instruction.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    dir.join("sub/../common.cfg"),
+                    InputCommand::Glob("/common/*.h".into())
+                ),
+                (main.clone(), InputCommand::Glob("/common/*.cc".into())),
+            ]
+        );
+        assert_eq!(
+            config.group_instructions,
+            vec![Sourced {
+                file: dir.join("sub/../extra.cfg").into(),
+                instruction: GroupInstruction::GroupSourceHeader,
+            }]
+        );
+
+        // variables of the importing file win, wherever they are used
+        assert_eq!(
+            config.map_instructions,
+            vec![MapInstruction::DisplayMap {
+                from: "/common".into(),
+                to: "main::".into()
+            }]
+        );
+        assert_eq!(config.symlinks, SymlinkMode::Lexical);
+
+        std::fs::write(dir.join("common.cfg"), "import \"sub/main.cfg\"\n").unwrap();
+        let mut loader = ConfigLoader::default();
+        let err = loader
+            .load_root(&main, &std::fs::read_to_string(&main).unwrap())
+            .unwrap_err();
+        assert!(matches!(
+            err.root_cause().downcast_ref::<Error>(),
+            Some(Error::ImportCycle { .. })
+        ));
+
+        std::fs::write(dir.join("common.cfg"), "graf {\n}\n").unwrap();
+        let mut loader = ConfigLoader::default();
+        let err = loader
+            .load_root(&main, &std::fs::read_to_string(&main).unwrap())
+            .unwrap_err();
+        assert!(format!("{:?}", err).contains("common.cfg:1:1"));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let render = |config: &str| match parse_config(config) {
+            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+                render_parse_error(config, "test.cfg", &e)
+            }
+            other => panic!("Unexpected parse result: {:?}", other.map(|_| ())),
+        };
+
+        assert_eq!(
+            render("input {\n  glob a\n}\ngraph {\n  map {\n    keep a\n  }\n  grop {\n  }\n}\n"),
+            concat!(
+                "error: expected one of `}`, `group`, `color`, `zoom`, found `grop`\n",
+                " --> test.cfg:8:3\n",
+                "  |\n",
+                "8 |   grop {\n",
+                "  |   ^^^^\n",
+                "  = note: inside graph { ... }\n",
+            )
+        );
+        assert_eq!(
+            render("input {\n  system_headers {\n    a\n"),
+            concat!(
+                "error: expected one of `}`, a value, found end of file\n",
+                " --> test.cfg:4:1\n",
+                "  |\n",
+                "4 |\n",
+                "  | ^\n",
+                "  = note: inside input { system_headers { ... } }\n",
+            )
+        );
+        assert!(
+            render("symlinks lexical\ninput {\n}\ngraf {\n}\n").starts_with(
+                "error: expected one of end of file, `graph`, found `graf`\n --> test.cfg:4:1\n"
+            )
+        );
+        assert!(render("graph {\n  map {\n    keep a\n")
+            .ends_with("  = note: inside graph { map { ... } }\n"));
+        assert!(
+            render("input {\n}\ngraph {\n  map {\n  }\n  group {\n  }\n}\nextra\n")
+                .starts_with("error: expected end of file, found `extra`\n")
+        );
+        assert_eq!(
+            render("input {\n  from compiledb a.json load sorces\n}\n"),
+            concat!(
+                "error: expected one of `include_dirs`, `sources`, found `sorces`\n",
+                " --> test.cfg:2:30\n",
+                "  |\n",
+                "2 |   from compiledb a.json load sorces\n",
+                "  |                              ^^^^^^\n",
+                "  = note: inside input { ... }\n",
+            )
+        );
+    }
+
     #[test]
     fn test_variable_assignments() {
         assert_eq!(
@@ -1517,7 +3940,8 @@ mod tests {
              ab=test
              other=${a${a}}ing
            "
-            ),
+            )
+            .ok(),
             {
                 let mut expected = HashMap::new();
                 expected.insert("a".into(), "b".into());
@@ -1525,7 +3949,7 @@ mod tests {
                 expected.insert("z".into(), "by".into());
                 expected.insert("ab".into(), "test".into());
                 expected.insert("other".into(), "testing".into());
-                Ok(("", expected))
+                Some(("", expected))
             }
         );
     }
@@ -1537,14 +3961,386 @@ mod tests {
         vars.insert("another".into(), "one".into());
         vars.insert("test".into(), "1234".into());
         vars.insert("theone".into(), "final".into());
+        let vars = VariableMap {
+            values: vars,
+            ..Default::default()
+        };
+
+        assert_eq!(vars.expand("xyz"), "xyz");
+        assert_eq!(vars.expand("${foo}"), "bar");
+        assert_eq!(vars.expand("${another}"), "one");
+        assert_eq!(vars.expand("${foo}/${another}/${foo}"), "bar/one/bar");
+        assert_eq!(vars.expand("${the${another}}"), "final");
+    }
+
+    #[test]
+    fn test_expand_references() {
+        std::env::set_var("IGRAPH_TEST_EXPAND", "from-env");
+
+        let vars = VariableMap::resolve(
+            vec![
+                VariableAssignment {
+                    name: "root".into(),
+                    value: VariableValue::Single("/src".into()),
+                },
+                VariableAssignment {
+                    name: "empty".into(),
+                    value: VariableValue::Single("".into()),
+                },
+                VariableAssignment {
+                    name: "late".into(),
+                    value: VariableValue::Single("${later}/x".into()),
+                },
+                VariableAssignment {
+                    name: "later".into(),
+                    value: VariableValue::Single("/defined".into()),
+                },
+                VariableAssignment {
+                    name: "build".into(),
+                    value: VariableValue::Single("${root}/out".into()),
+                },
+                VariableAssignment {
+                    name: "flavor".into(),
+                    value: VariableValue::Single("${flavor:-debug}".into()),
+                },
+            ],
+            &[("build".into(), "/tmp/build".into())],
+        );
+
+        assert_eq!(vars.expand("${env:IGRAPH_TEST_EXPAND}/a"), "from-env/a");
+        assert_eq!(vars.expand("${missing:-${root}/lib}"), "/src/lib");
+        assert_eq!(vars.expand("${empty:-default}"), "default");
+        assert_eq!(vars.expand("${root:-default}"), "/src");
+        assert_eq!(vars.expand("${late}"), "/defined/x");
+        assert_eq!(vars.expand("${build}"), "/tmp/build");
+        assert_eq!(vars.expand("${flavor}"), "debug");
+        assert!(vars.take_errors().is_empty());
+
+        assert_eq!(
+            vars.expand("${missing}/${env:IGRAPH_TEST_UNSET}"),
+            "${missing}/${env:IGRAPH_TEST_UNSET}"
+        );
+        assert_eq!(vars.expand("${missing}"), "${missing}");
+        assert_eq!(
+            vars.take_errors(),
+            vec![
+                FailedExpansion {
+                    error: ExpansionError::Undefined("${missing}".into()),
+                    used: "${missing}".into(),
+                },
+                FailedExpansion {
+                    error: ExpansionError::Undefined("${env:IGRAPH_TEST_UNSET}".into()),
+                    used: "${env:IGRAPH_TEST_UNSET}".into(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_undefined_variables() {
+        let mut loader = ConfigLoader::default();
+        loader
+            .load_root(
+                Path::new("test.cfg"),
+                "# uses ${nope}\ninput {\n  glob ${nope}/*.h\n}\n",
+            )
+            .unwrap();
+        let err = loader.finish().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            concat!(
+                "error: undefined variable `nope`\n",
+                " --> test.cfg:3:8\n",
+                "  |\n",
+                "3 |   glob ${nope}/*.h\n",
+                "  |        ^^^^^^^\n",
+                "  = help: define it (e.g. with `-D nope=...`), or use a default like `${nope:-default}`",
+            )
+        );
+
+        // the error points at the use that failed, not at the variable it uses
+        let mut loader = ConfigLoader::default();
+        loader
+            .load_root(
+                Path::new("test.cfg"),
+                "dir = ${nope}/include\ninput {\n  glob ${nope:-src}/*.h\n  include_dir ${dir}\n}\n",
+            )
+            .unwrap();
+        let err = loader.finish().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            concat!(
+                "error: undefined variable `nope`\n",
+                " --> test.cfg:4:15\n",
+                "  |\n",
+                "4 |   include_dir ${dir}\n",
+                "  |               ^^^^^^\n",
+                "  = note: `${nope}` is reached by expanding `${dir}`\n",
+                "  = help: define it (e.g. with `-D nope=...`), or use a default like `${nope:-default}`",
+            )
+        );
+
+        let mut loader = ConfigLoader::new(&[("nope".into(), "/yes".into())]);
+        loader
+            .load_root(Path::new("test.cfg"), "input {\n  glob ${nope}/*.h\n}\n")
+            .unwrap();
+        let config = loader.finish().unwrap();
+        assert_eq!(
+            config.input_commands[0].instruction,
+            InputCommand::Glob("/yes/*.h".into())
+        );
+    }
+
+    #[test]
+    fn test_variable_cycles() {
+        let mut loader = ConfigLoader::new(&[("a".into(), "x${a}".into())]);
+        loader
+            .load_root(Path::new("test.cfg"), "input {\n  glob ${a}/*.h\n}\n")
+            .unwrap();
+        let err = loader.finish().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            concat!(
+                "error: variable `a` refers to itself\n",
+                " --> test.cfg:2:8\n",
+                "  |\n",
+                "2 |   glob ${a}/*.h\n",
+                "  |        ^^^^\n",
+                "  = help: variables may not refer to themselves, directly or through other variables",
+            )
+        );
+
+        let vars = VariableMap {
+            values: [("a", "${b}"), ("b", "${a}")]
+                .into_iter()
+                .map(|(n, v)| (n.to_string(), v.to_string()))
+                .collect(),
+            strict: true,
+            ..Default::default()
+        };
+        assert_eq!(vars.expand("${b:-x}"), "${b}");
+        assert_eq!(
+            vars.take_errors(),
+            vec![FailedExpansion {
+                error: ExpansionError::Cycle("${b}".into()),
+                used: "${b:-x}".into(),
+            }]
+        );
+
+        let vars = VariableMap {
+            lists: [("l".to_string(), vec!["${l}".to_string()])].into(),
+            strict: true,
+            ..Default::default()
+        };
+        assert_eq!(vars.expand_list("${l}"), vec!["${l}"]);
+        assert_eq!(
+            vars.take_errors(),
+            vec![FailedExpansion {
+                error: ExpansionError::Cycle("${l}".into()),
+                used: "${l}".into(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_list_variables() {
+        assert_eq!(
+            parse_variable_assignment_list("files = [ a.h ${b}/c.h ]\nempty = []\nglob = [a-z]*\n")
+                .ok(),
+            Some((
+                "",
+                vec![
+                    VariableAssignment {
+                        name: "files".into(),
+                        value: VariableValue::List(vec!["a.h".into(), "${b}/c.h".into()]),
+                    },
+                    VariableAssignment {
+                        name: "empty".into(),
+                        value: VariableValue::List(vec![]),
+                    },
+                    VariableAssignment {
+                        name: "glob".into(),
+                        value: VariableValue::Single("[a-z]*".into()),
+                    },
+                ]
+            ))
+        );
+
+        assert_eq!(
+            parse_variable_assignment_list("files = [\n  a.h # first\n  b.h\n]").ok(),
+            Some((
+                "",
+                vec![VariableAssignment {
+                    name: "files".into(),
+                    value: VariableValue::List(vec!["a.h".into(), "b.h".into()]),
+                }]
+            ))
+        );
+    }
+
+    #[test]
+    fn test_expand_lists() {
+        let (_, assignments) = parse_variable_assignment_list(
+            "
+            root = /src
+            headers = [ a.h b.h ]
+            dirs = [ ${root}/x ${root}/y ]
+            all = [ ${headers} c.h ${late} ]
+            none = [ ]
+            ",
+        )
+        .unwrap();
+        let vars = VariableMap::resolve(assignments, &[("late".into(), "d.h".into())]);
+
+        assert_eq!(vars.expand_list("${root}/a"), vec!["/src/a"]);
+        assert_eq!(
+            vars.expand_list("${dirs}/inc"),
+            vec!["/src/x/inc", "/src/y/inc"]
+        );
+        assert_eq!(vars.expand_list("${all}"), vec!["a.h", "b.h", "c.h", "d.h"]);
+        assert_eq!(
+            vars.expand_list("${dirs}/${headers}"),
+            vec!["/src/x/a.h", "/src/x/b.h", "/src/y/a.h", "/src/y/b.h"]
+        );
+        assert!(vars.expand_list("${none}").is_empty());
+        assert_eq!(vars.expand_list("${none:-x.h}"), vec!["x.h"]);
+        assert!(vars.take_errors().is_empty());
+
+        assert_eq!(vars.expand("${headers}/x"), "${headers}/x");
+        assert_eq!(
+            vars.take_errors(),
+            vec![FailedExpansion {
+                error: ExpansionError::ListAsValue("${headers}".into()),
+                used: "${headers}".into(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_expand_all_fields() {
+        let mut loader = ConfigLoader::new(&[("target".into(), "//core:lib".into())]);
+        loader
+            .load_root(
+                Path::new("test.cfg"),
+                "
+                core = [ a.h b.h ]
+                defines = [ A B=2 ]
+                name = core
+                input {
+                  glob ${core}
+                  define ${defines}
+                }
+                graph {
+                  map {
+                    keep ${core}
+                  }
+                  group {
+                    gn root /gn target ${target} sources /src ignore targets { ${target}_test }
+                    manual ${name} color ${name}_color {
+                      ${core}
+                      c.h
+                    }
+                  }
+                  zoom {
+                    focus: ${core}
+                    ${name}
+                  }
+                }
+                ",
+            )
+            .unwrap();
+        let config = loader.finish().unwrap();
+
+        assert_eq!(
+            config
+                .input_commands
+                .into_iter()
+                .map(|c| c.instruction)
+                .collect::<Vec<_>>(),
+            vec![
+                InputCommand::Glob("a.h".into()),
+                InputCommand::Glob("b.h".into()),
+                InputCommand::Define(DefineFlag::define("A")),
+                InputCommand::Define(DefineFlag::define("B=2")),
+            ]
+        );
+        assert_eq!(
+            config.map_instructions,
+            vec![
+                MapInstruction::Keep("a.h".into()),
+                MapInstruction::Keep("b.h".into()),
+            ]
+        );
+        assert_eq!(
+            config
+                .group_instructions
+                .into_iter()
+                .map(|g| g.instruction)
+                .collect::<Vec<_>>(),
+            vec![
+                GroupInstruction::GroupFromGn {
+                    gn_root: "/gn".into(),
+                    target: "//core:lib".into(),
+                    source_root: "/src".into(),
+                    ignore_targets: ["//core:lib_test".to_string()].into_iter().collect(),
+                },
+                GroupInstruction::ManualGroup {
+                    name: "core".into(),
+                    color: Some("core_color".into()),
+                    items: vec!["a.h".into(), "b.h".into(), "c.h".into()],
+                },
+            ]
+        );
+        assert_eq!(
+            config.zoom_items,
+            vec![
+                ZoomItem {
+                    name: "a.h".into(),
+                    focused: true
+                },
+                ZoomItem {
+                    name: "b.h".into(),
+                    focused: true
+                },
+                ZoomItem {
+                    name: "core".into(),
+                    focused: false
+                },
+            ]
+        );
+
+        let mut loader = ConfigLoader::default();
+        loader
+            .load_root(
+                Path::new("test.cfg"),
+                "core = [ a.h b.h ]\ngraph {\n  group {\n    manual ${core} {\n      x.h\n    }\n  }\n}\n",
+            )
+            .unwrap();
+        let err = loader.finish().unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("error: list variable `core` used where a single value is expected\n --> test.cfg:4:12\n"));
+    }
+
+    #[test]
+    fn test_import_variables() {
+        let mut loader = ConfigLoader::default();
+        let err = loader
+            .load_root(Path::new("test.cfg"), "import \"${dir}/common.cfg\"\n")
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("error: undefined variable `dir`\n --> test.cfg:1:9\n"));
+
+        let dir = TempTree::new(&[("common.cfg", "input {\n  glob *.h\n}\n")]);
 
-        assert_eq!(expand_variable("xyz", &vars), "xyz");
-        assert_eq!(expand_variable("${foo}", &vars), "bar");
-        assert_eq!(expand_variable("${another}", &vars), "one");
+        let mut loader = ConfigLoader::new(&[("dir".into(), dir.to_string_lossy().into())]);
+        loader
+            .load_root(Path::new("test.cfg"), "import \"${dir}/common.cfg\"\n")
+            .unwrap();
         assert_eq!(
-            expand_variable("${foo}/${another}/${foo}", &vars),
-            "bar/one/bar"
+            loader.finish().unwrap().input_commands[0].instruction,
+            InputCommand::Glob("*.h".into())
         );
-        assert_eq!(expand_variable("${the${another}}", &vars), "final");
     }
 }
@@ -1,52 +1,288 @@
-use super::canonicalize::canonicalize_cached;
+use super::canonicalize::PathResolver;
 use super::error::Error;
+use super::include_path::IncludePath;
+use super::lexer::LogicalLines;
+use super::preprocessor::{Defines, Preprocessor, PreprocessorConfig};
+use super::scan_cache::ScanCache;
 
-use regex::Regex;
+use rayon::prelude::*;
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
 use std::{
+    borrow::Cow,
+    collections::HashSet,
     fmt::Debug,
     fs::File,
-    io::{BufRead, BufReader},
+    io::BufReader,
     path::{Path, PathBuf},
-    sync::{Arc, LazyLock},
-    thread,
+    rc::Rc,
+    sync::LazyLock,
 };
-use tracing::{error, info, trace};
+use tracing::{debug, error, info, trace};
 
 /// Attempt to make the full path of head::tail
 /// returns None if that fails (e.g. path does not exist)
-fn try_resolve(head: &Path, tail: &Path) -> Option<PathBuf> {
-    canonicalize_cached(head.join(tail)).ok()?
+fn try_resolve(head: &Path, tail: &Path, resolver: &PathResolver) -> Option<PathBuf> {
+    resolver.canonicalize(head.join(tail)).ok()?
 }
 
-#[derive(PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+#[derive(PartialEq, Eq, Hash, PartialOrd, Ord, Debug, Clone, Copy)]
 pub enum FileType {
     Header,
     Source,
     Unknown,
 }
 
-impl FileType {
-    pub fn of(path: &Path) -> Self {
+/// What file extensions are headers and sources.
+///
+/// Extensions are matched case-insensitively.
+///
+/// ```
+/// # use include_graph::dependencies::cparse::{FileType, FileTypes};
+/// # use std::path::Path;
+///
+/// let mut types = FileTypes::default();
+/// assert_eq!(types.of(Path::new("foo.hh")), FileType::Unknown);
+///
+/// types.add(FileType::Header, "hh");
+/// assert_eq!(types.of(Path::new("foo.HH")), FileType::Header);
+/// assert_eq!(types.of(Path::new("foo.cpp")), FileType::Source);
+/// ```
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct FileTypes {
+    headers: Vec<String>,
+    sources: Vec<String>,
+}
+
+impl Default for FileTypes {
+    fn default() -> Self {
+        Self {
+            headers: vec!["h".into(), "hpp".into()],
+            sources: vec!["c".into(), "cpp".into(), "cc".into(), "cxx".into()],
+        }
+    }
+}
+
+impl FileTypes {
+    /// Classify `extension` (with or without a leading `.`) as the given type.
+    ///
+    /// Adding an `Unknown` extension removes it from headers and sources.
+    pub fn add(&mut self, file_type: FileType, extension: &str) {
+        let extension = extension.trim_start_matches('.').to_lowercase();
+        self.headers.retain(|e| *e != extension);
+        self.sources.retain(|e| *e != extension);
+
+        match file_type {
+            FileType::Header => self.headers.push(extension),
+            FileType::Source => self.sources.push(extension),
+            FileType::Unknown => {}
+        }
+    }
+
+    pub fn of(&self, path: &Path) -> FileType {
         let ext = path
             .extension()
             .and_then(|e| e.to_str())
             .unwrap_or("")
             .to_lowercase();
-        match ext.as_str() {
-            "h" | "hpp" => FileType::Header,
-            "c" | "cpp" | "cc" | "cxx" => FileType::Source,
-            _ => FileType::Unknown,
+
+        if self.headers.contains(&ext) {
+            FileType::Header
+        } else if self.sources.contains(&ext) {
+            FileType::Source
+        } else {
+            FileType::Unknown
         }
     }
+
+    /// All known extensions, headers first.
+    pub fn extensions(&self) -> impl Iterator<Item = &str> {
+        self.headers
+            .iter()
+            .chain(self.sources.iter())
+            .map(String::as_str)
+    }
 }
 
+static DIRECTIVE_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r##"^\s*#\s*([a-z_]+)(.*)$"##).unwrap());
+
 static INCLUDE_REGEX: LazyLock<Regex> =
-    LazyLock::new(|| Regex::new(r##"^\s*#include\s*(["<])([^">]*)[">]"##).unwrap());
+    LazyLock::new(|| Regex::new(r##"^\s*(["<])([^">]*)[">]"##).unwrap());
 
-/// Given a C-like source, try to resolve includes.
+static HAS_INCLUDE_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r##"__has_include(_next)?\s*\(\s*(["<])([^">]*)[">]\s*\)"##).unwrap()
+});
+
+/// Find the position of the include directory that contains `path`
+fn containing_include_dir(
+    path: &Path,
+    include_path: &IncludePath,
+    resolver: &PathResolver,
+) -> Option<usize> {
+    include_path.directories().iter().position(|dir| {
+        resolver
+            .canonicalize(&dir.path)
+            .ok()
+            .flatten()
+            .is_some_and(|dir| path.starts_with(dir))
+    })
+}
+
+/// The directories to search for `relative` as included from the file `current`.
+///
+/// `quoted` includes (i.e. `#include "foo.h"`) are first looked up relative to `current`
+/// and also searched in quote include directories.
+/// `next` includes (i.e. `#include_next <foo.h>`) resume the search after the include
+/// directory that contains `current`.
+fn search_directories<'a>(
+    current: &'a Path,
+    quoted: bool,
+    next: bool,
+    include_path: &'a IncludePath,
+    resolver: &PathResolver,
+) -> Option<Vec<&'a Path>> {
+    let (search_start, current_dir) = if next {
+        let start = containing_include_dir(current, include_path, resolver)
+            .map(|idx| idx + 1)
+            .unwrap_or(0);
+        (start, None)
+    } else if quoted {
+        (0, Some(current.parent()?))
+    } else {
+        (0, None)
+    };
+    Some(
+        current_dir
+            .into_iter()
+            .chain(include_path.search(quoted, search_start))
+            .collect(),
+    )
+}
+
+/// Find the existing file `relative` refers to when included from the file `current`.
 ///
-/// Includes are generally of the form `#include <name>` or `#include "name"`
-pub fn extract_includes(path: &PathBuf, include_dirs: &[PathBuf]) -> Result<Vec<PathBuf>, Error> {
+/// Unlike [resolve_include], this never resolves to a virtual path, which
+/// makes it suitable for `__has_include`.
+fn find_include(
+    current: &Path,
+    relative: &Path,
+    quoted: bool,
+    next: bool,
+    include_path: &IncludePath,
+    resolver: &PathResolver,
+) -> Option<PathBuf> {
+    search_directories(current, quoted, next, include_path, resolver)?
+        .into_iter()
+        .find_map(|dir| try_resolve(dir, relative, resolver))
+}
+
+/// Resolve `relative` as included from the file `current`.
+///
+/// If no existing file is found (see [find_include]), the include resolves
+/// to a virtual path if joining it to one of the searched directories names
+/// a file below a generated root.
+fn resolve_include(
+    current: &Path,
+    relative: &Path,
+    quoted: bool,
+    next: bool,
+    include_path: &IncludePath,
+    resolver: &PathResolver,
+) -> Option<PathBuf> {
+    let directories = search_directories(current, quoted, next, include_path, resolver)?;
+
+    directories
+        .iter()
+        .find_map(|dir| try_resolve(dir, relative, resolver))
+        .or_else(|| {
+            directories
+                .iter()
+                .find_map(|dir| resolver.generated(dir.join(relative)))
+        })
+}
+
+/// Split an include operand like `<foo.h>` or `"foo.h"` into
+/// whether it is quoted and the path it names.
+fn parse_include_operand(operand: &str) -> Option<(bool, PathBuf)> {
+    let captures = INCLUDE_REGEX.captures(operand)?;
+    Some((
+        captures.get(1).unwrap().as_str() == "\"",
+        PathBuf::from(captures.get(2).unwrap().as_str()),
+    ))
+}
+
+/// Whether an include names its file in quotes or angle brackets
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum IncludeStyle {
+    /// `#include "foo.h"`
+    Quoted,
+
+    /// `#include <foo.h>`
+    Angled,
+}
+
+impl IncludeStyle {
+    fn of(quoted: bool) -> Self {
+        if quoted {
+            IncludeStyle::Quoted
+        } else {
+            IncludeStyle::Angled
+        }
+    }
+}
+
+/// Where and how an include was written in a source
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct IncludeDirective {
+    /// 1-based line of the directive (0 for includes given on the command line)
+    pub line: usize,
+
+    /// 1-based column where the directive starts
+    pub column: usize,
+
+    /// The directive as written, like `#include "foo/bar.h"`
+    pub spelling: String,
+
+    /// Style of the included name. For computed includes this is the
+    /// style of the macro expansion.
+    pub style: IncludeStyle,
+}
+
+/// An include resolved to a file
+#[derive(Debug, Clone, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub struct Include {
+    pub path: PathBuf,
+    pub directive: IncludeDirective,
+}
+
+/// Things of interest found while preprocessing a file
+enum ScanEvent<'a> {
+    /// An `#include`, `#import` or `#include_next` in active code.
+    Include {
+        line: usize,
+        column: usize,
+        spelling: &'a str,
+        operand: &'a str,
+        next: bool,
+    },
+
+    /// A `__has_include` check in active code, for a file that exists
+    OptionalInclude(Include),
+}
+
+/// Run `preprocessor` over the given file, reporting includes in active code
+/// to `on_event`.
+fn scan<F>(
+    path: &PathBuf,
+    include_path: &IncludePath,
+    resolver: &PathResolver,
+    preprocessor: &mut Preprocessor,
+    mut on_event: F,
+) -> Result<(), Error>
+where
+    F: FnMut(&mut Preprocessor, ScanEvent<'_>),
+{
     let f = File::open(path).map_err(|source| Error::FileIOError {
         source,
         path: path.clone(),
@@ -54,72 +290,419 @@ pub fn extract_includes(path: &PathBuf, include_dirs: &[PathBuf]) -> Result<Vec<
     })?;
 
     let reader = BufReader::new(f);
-    let mut result = Vec::new();
-    let parent_dir = PathBuf::from(path.parent().unwrap());
-
-    let lines = reader.lines();
+    let mut optional_includes = Vec::new();
 
-    for line in lines {
+    for line in LogicalLines::new(reader) {
         let line = line.map_err(|source| Error::FileIOError {
             source,
             path: path.clone(),
             message: "line read",
         })?;
 
-        if let Some(captures) = INCLUDE_REGEX.captures(&line) {
-            let inc_type = captures.get(1).unwrap().as_str();
-            let relative_path = PathBuf::from(captures.get(2).unwrap().as_str());
+        let (directive, rest, rest_start) = match DIRECTIVE_REGEX.captures(&line.text) {
+            Some(captures) => (
+                captures.get(1).unwrap().as_str(),
+                captures.get(2).unwrap().as_str(),
+                captures.get(2).unwrap().start(),
+            ),
+            None => continue,
+        };
+        let spelling = line.text.trim();
+        let column = line.text.len() - line.text.trim_start().len() + 1;
 
-            trace!("Possible include: {:?}", relative_path);
+        // `__has_include` is evaluated right away, as resolving includes
+        // is not something the preprocessor knows about. Headers that cannot
+        // be found (commonly system headers outside of the include path) are
+        // not known to be absent, so the check becomes an expression that
+        // cannot be evaluated and follows the unknown condition policy.
+        let rest = match directive {
+            "if" | "elif" => {
+                let record = match directive {
+                    "if" => preprocessor.is_active(),
+                    _ => preprocessor.is_alternative_reachable(),
+                };
+                HAS_INCLUDE_REGEX.replace_all(rest, |captures: &Captures| {
+                    let quoted = captures.get(2).unwrap().as_str() == "\"";
+                    match find_include(
+                        path,
+                        Path::new(captures.get(3).unwrap().as_str()),
+                        quoted,
+                        captures.get(1).is_some(),
+                        include_path,
+                        resolver,
+                    ) {
+                        Some(p) => {
+                            if record {
+                                let check = captures.get(0).unwrap();
+                                optional_includes.push(Include {
+                                    path: p,
+                                    directive: IncludeDirective {
+                                        line: line.line,
+                                        column: rest_start + check.start() + 1,
+                                        spelling: check.as_str().into(),
+                                        style: IncludeStyle::of(quoted),
+                                    },
+                                });
+                            }
+                            "1"
+                        }
+                        None => "__has_include()",
+                    }
+                })
+            }
+            _ => Cow::Borrowed(rest),
+        };
 
-            if inc_type == "\"" {
-                if let Some(p) = try_resolve(&parent_dir, &relative_path) {
-                    result.push(p);
-                    continue;
+        for include in optional_includes.drain(..) {
+            on_event(preprocessor, ScanEvent::OptionalInclude(include));
+        }
+
+        if preprocessor.handle_directive(directive, &rest) {
+            continue;
+        }
+
+        let next = match directive {
+            "include" | "import" => false,
+            "include_next" => true,
+            _ => continue,
+        };
+
+        if preprocessor.is_active() {
+            on_event(
+                preprocessor,
+                ScanEvent::Include {
+                    line: line.line,
+                    column,
+                    spelling,
+                    operand: rest.trim(),
+                    next,
+                },
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Preprocess `path` (and recursively everything it includes) only to
+/// find what macros it defines.
+fn collect_macros(
+    path: &PathBuf,
+    include_path: &IncludePath,
+    resolver: &PathResolver,
+    preprocessor: &mut Preprocessor,
+    visited: &mut HashSet<PathBuf>,
+) {
+    if !visited.insert(path.clone()) {
+        return;
+    }
+
+    let result = scan(
+        path,
+        include_path,
+        resolver,
+        preprocessor,
+        |preprocessor, event| {
+            if let ScanEvent::Include { operand, next, .. } = event {
+                let target = parse_include_operand(operand).or_else(|| {
+                    preprocessor
+                        .defines()
+                        .expand_include(operand)
+                        .as_deref()
+                        .and_then(parse_include_operand)
+                });
+                if let Some(p) = target.and_then(|(quoted, relative)| {
+                    resolve_include(path, &relative, quoted, next, include_path, resolver)
+                }) {
+                    collect_macros(&p, include_path, resolver, preprocessor, visited);
                 }
             }
+        },
+    );
 
-            if let Some(p) = include_dirs
-                .iter()
-                .find_map(|i| try_resolve(i, &relative_path))
+    if let Err(e) = result {
+        debug!("Failed to collect macros from {:?}: {:?}", path, e);
+    }
+}
+
+/// Macros defined once all of `includes` were processed (in order).
+pub fn macros_from_includes(
+    includes: &[PathBuf],
+    include_path: &IncludePath,
+    config: &PreprocessorConfig,
+    resolver: &PathResolver,
+) -> Defines {
+    let mut preprocessor = Preprocessor::new(config);
+    let mut visited = HashSet::new();
+    for include in includes {
+        collect_macros(
+            include,
+            include_path,
+            resolver,
+            &mut preprocessor,
+            &mut visited,
+        );
+    }
+    preprocessor.defines().clone()
+}
+
+/// Macros defined by the headers a file included so far, used to expand its
+/// computed includes.
+///
+/// Headers are only preprocessed once a computed include needs their macros,
+/// each of them at most once per file and with the defines the file had when
+/// including it.
+struct IncludedMacros<'a> {
+    include_path: &'a IncludePath,
+    resolver: &'a PathResolver,
+
+    /// Included headers not preprocessed yet, with the defines of the
+    /// including file at the include (shared while they do not change)
+    pending: Vec<(PathBuf, Rc<Defines>)>,
+    last_defines: Option<Rc<Defines>>,
+
+    /// The defines of the including file applied last to `preprocessor`
+    applied: Option<Rc<Defines>>,
+
+    preprocessor: Preprocessor,
+    visited: HashSet<PathBuf>,
+}
+
+impl<'a> IncludedMacros<'a> {
+    fn new(
+        config: &PreprocessorConfig,
+        include_path: &'a IncludePath,
+        resolver: &'a PathResolver,
+    ) -> Self {
+        Self {
+            include_path,
+            resolver,
+            pending: Vec::new(),
+            last_defines: None,
+            applied: None,
+            preprocessor: Preprocessor::new(config),
+            visited: HashSet::new(),
+        }
+    }
+
+    /// Record that `header` was included while `defines` were defined
+    fn push(&mut self, header: &Path, defines: &Defines) {
+        let defines = match self.last_defines {
+            Some(ref last) if **last == *defines => last.clone(),
+            _ => Rc::new(defines.clone()),
+        };
+        self.last_defines = Some(defines.clone());
+        self.pending.push((header.to_path_buf(), defines));
+    }
+
+    /// Expand a computed include `operand` using `defines` of the file and
+    /// the macros of all headers included so far
+    fn expand_include(&mut self, operand: &str, defines: &Defines) -> Option<String> {
+        for (header, including_defines) in self.pending.drain(..) {
+            if !self
+                .applied
+                .as_ref()
+                .is_some_and(|a| Rc::ptr_eq(a, &including_defines))
             {
-                result.push(p);
-            } else {
-                // Debug only as this is VERY common due to C++ and system inclues,
-                // like "list", "vector", "string" or even platform specific like "jni.h"
-                // or non-enabled things (like openthread on a non-thread platform)
-                trace!("Include {:?} could not be resolved", relative_path);
+                self.preprocessor.define_all(&including_defines);
+                self.applied = Some(including_defines);
             }
+            collect_macros(
+                &header,
+                self.include_path,
+                self.resolver,
+                &mut self.preprocessor,
+                &mut self.visited,
+            );
         }
+
+        let mut all = self.preprocessor.defines().clone();
+        all.extend(defines);
+        all.expand_include(operand)
     }
+}
+
+/// Given a C-like source, try to resolve includes.
+///
+/// Includes are generally of the form `#include <name>` or `#include "name"`. The
+/// `#import` and `#include_next` forms are supported as well. Targets of resolvable
+/// `__has_include(...)` checks are reported as optional includes.
+///
+/// Computed includes (`#include MACRO`) are expanded using object-like macros
+/// defined in `preprocessor`, in the file itself or in the headers it included
+/// before. The ones that cannot be resolved are reported in `unresolved_computed`.
+///
+/// Comments are ignored and backslash-continued lines are joined before
+/// looking for directives. Includes within conditional blocks (`#if`/`#ifdef`/...)
+/// that are not taken given the `preprocessor` defines are skipped.
+pub fn extract_includes(
+    path: &PathBuf,
+    include_path: &IncludePath,
+    preprocessor: &PreprocessorConfig,
+    resolver: &PathResolver,
+) -> Result<SourceWithIncludes, Error> {
+    let mut includes = Vec::new();
+    let mut optional_includes = Vec::new();
+    let mut unresolved = Vec::new();
+    let mut unresolved_computed = Vec::new();
+    let mut state = Preprocessor::new(preprocessor);
+    let mut included_macros = IncludedMacros::new(preprocessor, include_path, resolver);
+
+    scan(
+        path,
+        include_path,
+        resolver,
+        &mut state,
+        |state, event| match event {
+            ScanEvent::OptionalInclude(include) => optional_includes.push(include),
+            ScanEvent::Include {
+                line,
+                column,
+                spelling,
+                operand,
+                next,
+            } => {
+                let directive = |quoted| IncludeDirective {
+                    line,
+                    column,
+                    spelling: spelling.into(),
+                    style: IncludeStyle::of(quoted),
+                };
+
+                if let Some((quoted, relative_path)) = parse_include_operand(operand) {
+                    trace!("Possible include: {:?}", relative_path);
+
+                    match resolve_include(
+                        path,
+                        &relative_path,
+                        quoted,
+                        next,
+                        include_path,
+                        resolver,
+                    ) {
+                        Some(p) => {
+                            included_macros.push(&p, state.defines());
+                            includes.push(Include {
+                                path: p,
+                                directive: directive(quoted),
+                            })
+                        }
+                        // Debug only as this is VERY common due to C++ and system inclues,
+                        // like "list", "vector", "string" or even platform specific like "jni.h"
+                        // or non-enabled things (like openthread on a non-thread platform)
+                        None => {
+                            trace!("Include {:?} could not be resolved", relative_path);
+                            unresolved.push(UnresolvedInclude {
+                                name: relative_path,
+                                directive: directive(quoted),
+                            });
+                        }
+                    }
+                    return;
+                }
+
+                let expansion = state.defines().expand_include(operand).or_else(|| {
+                    // The macro may come from an earlier included header
+                    included_macros.expand_include(operand, state.defines())
+                });
+
+                trace!("Computed include {:?} expands to {:?}", operand, expansion);
+
+                match expansion
+                    .as_deref()
+                    .and_then(parse_include_operand)
+                    .and_then(|(quoted, relative_path)| {
+                        resolve_include(path, &relative_path, quoted, next, include_path, resolver)
+                            .map(|p| (quoted, p))
+                    }) {
+                    Some((quoted, p)) => {
+                        included_macros.push(&p, state.defines());
+                        includes.push(Include {
+                            path: p,
+                            directive: directive(quoted),
+                        })
+                    }
+                    None => unresolved_computed.push(UnresolvedComputedInclude {
+                        line,
+                        expression: operand.into(),
+                        expansion,
+                    }),
+                }
+            }
+        },
+    )?;
 
     info!(target: "include-extract",
-          "Includes for:\n  {:?}: {:#?}", path, result);
+          "Includes for:\n  {:?}: {:#?}\n  optional: {:#?}", path, includes, optional_includes);
 
-    Ok(result)
+    Ok(SourceWithIncludes {
+        path: path.clone(),
+        includes,
+        optional_includes,
+        unresolved,
+        unresolved_computed,
+    })
 }
 
-#[derive(Debug, PartialEq, PartialOrd)]
+/// An include that could not be resolved to a file
+#[derive(Debug, PartialEq, PartialOrd, Clone, Serialize, Deserialize)]
+pub struct UnresolvedInclude {
+    /// The included name, like `foo/bar.h`
+    pub name: PathBuf,
+
+    pub directive: IncludeDirective,
+}
+
+/// A `#include MACRO` that could not be resolved to a file
+#[derive(Debug, PartialEq, PartialOrd, Clone, Serialize, Deserialize)]
+pub struct UnresolvedComputedInclude {
+    /// Line of the include directive
+    pub line: usize,
+
+    /// The include operand as written (e.g. `PLATFORM_HEADER`)
+    pub expression: String,
+
+    /// What the operand expanded to, if the expansion succeeded
+    pub expansion: Option<String>,
+}
+
+#[derive(Debug, PartialEq, PartialOrd, Clone, Serialize, Deserialize)]
 pub struct SourceWithIncludes {
     pub path: PathBuf,
-    pub includes: Vec<PathBuf>,
+    pub includes: Vec<Include>,
+
+    /// Includes that are only checked for (via `__has_include`)
+    pub optional_includes: Vec<Include>,
+
+    /// Includes that could not be resolved
+    pub unresolved: Vec<UnresolvedInclude>,
+
+    /// Computed includes that could not be resolved
+    pub unresolved_computed: Vec<UnresolvedComputedInclude>,
 }
 
 /// Given a list of paths, figure out their dependencies
+///
+/// Files are parsed in parallel on the current rayon thread pool. Results
+/// are in the order of `paths`.
 pub fn all_sources_and_includes<I, E>(
     paths: I,
-    includes: &[PathBuf],
+    include_path: &IncludePath,
+    preprocessor: &PreprocessorConfig,
+    file_types: &FileTypes,
+    cache: &ScanCache,
+    resolver: &PathResolver,
 ) -> Result<Vec<SourceWithIncludes>, Error>
 where
     I: Iterator<Item = Result<PathBuf, E>>,
     E: Debug,
 {
-    let includes = Arc::new(Vec::from(includes));
-    let mut handles = Vec::new();
+    let mut sources = Vec::new();
 
     for entry in paths {
         let path = match entry {
-            Ok(value) => canonicalize_cached(value)
+            Ok(value) => resolver
+                .canonicalize(value)
                 .map_err(|e| Error::Internal {
                     message: format!("{:?}", e),
                 })?
@@ -131,33 +714,370 @@ where
             }
         };
 
-        if FileType::of(&path) == FileType::Unknown {
+        if file_types.of(&path) == FileType::Unknown {
             trace!("Skipping non-source: {:?}", path);
             continue;
         }
 
-        // prepare data to move into sub-task
-        let includes = includes.clone();
+        sources.push(path);
+    }
 
-        handles.push(thread::spawn(move || {
+    sources
+        .par_iter()
+        .map(|path| {
             trace!("PROCESS: {:?}", path);
-            let includes = match extract_includes(&path, &includes) {
-                Ok(value) => value,
-                Err(e) => {
+            cache
+                .extract_includes(path, include_path, preprocessor, resolver)
+                .inspect_err(|e| {
                     error!("Error extracting includes: {:?}", e);
-                    return Err(e);
-                }
-            };
+                })
+        })
+        // collected in order first, so the error reported is always the first one
+        .collect::<Vec<_>>()
+        .into_iter()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dependencies::include_path::{IncludeDirectory, IncludeDirectoryKind};
+    use crate::dependencies::preprocessor::UnknownConditionPolicy;
+    use crate::dependencies::test_util::TempTree;
+    use crate::dependencies::unresolved::{SystemHeaders, UnresolvedReport};
+
+    fn include_path(dirs: &[PathBuf]) -> IncludePath {
+        IncludePath::new(dirs.iter().map(|path| IncludeDirectory {
+            path: path.clone(),
+            kind: IncludeDirectoryKind::Regular,
+        }))
+    }
+
+    fn included(source: &SourceWithIncludes) -> Vec<&Path> {
+        source.includes.iter().map(|i| i.path.as_path()).collect()
+    }
+
+    #[test]
+    fn test_include_next() {
+        let dir = TempTree::new(&[
+            ("a/foo.h", "#include_next <foo.h>\n"),
+            ("b/foo.h", "#include_next <foo.h>\n"),
+            ("main.cpp", "#include <foo.h>\n"),
+        ]);
+        let include_path = include_path(&[dir.join("a"), dir.join("b")]);
+        let config = PreprocessorConfig::default();
+        let resolver = PathResolver::default();
 
-            Ok(SourceWithIncludes { path, includes })
-        }));
+        let main =
+            extract_includes(&dir.join("main.cpp"), &include_path, &config, &resolver).unwrap();
+        assert_eq!(included(&main), vec![dir.join("a/foo.h")]);
+
+        // the search resumes after the directory that resolved the current file
+        let a = extract_includes(&dir.join("a/foo.h"), &include_path, &config, &resolver).unwrap();
+        assert_eq!(included(&a), vec![dir.join("b/foo.h")]);
+
+        let b = extract_includes(&dir.join("b/foo.h"), &include_path, &config, &resolver).unwrap();
+        assert!(b.includes.is_empty());
+        assert_eq!(b.unresolved[0].name, PathBuf::from("foo.h"));
     }
 
-    let mut results = Vec::new();
-    for handle in handles {
-        let res = handle.join().map_err(|_| Error::JoinError)?;
-        results.push(res?);
+    #[test]
+    fn test_import() {
+        let dir = TempTree::new(&[
+            ("inc/Foundation.h", ""),
+            ("local.h", ""),
+            (
+                "main.m",
+                "#import <Foundation.h>\n  #  import \"local.h\"\n#import <Missing.h>\n",
+            ),
+        ]);
+        let source = extract_includes(
+            &dir.join("main.m"),
+            &include_path(&[dir.join("inc")]),
+            &PreprocessorConfig::default(),
+            &PathResolver::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            source.includes,
+            vec![
+                Include {
+                    path: dir.join("inc/Foundation.h"),
+                    directive: IncludeDirective {
+                        line: 1,
+                        column: 1,
+                        spelling: "#import <Foundation.h>".into(),
+                        style: IncludeStyle::Angled,
+                    },
+                },
+                Include {
+                    path: dir.join("local.h"),
+                    directive: IncludeDirective {
+                        line: 2,
+                        column: 3,
+                        spelling: "#  import \"local.h\"".into(),
+                        style: IncludeStyle::Quoted,
+                    },
+                },
+            ]
+        );
+        assert_eq!(source.unresolved.len(), 1);
+        assert_eq!(source.unresolved[0].name, PathBuf::from("Missing.h"));
     }
 
-    Ok(results)
+    #[test]
+    fn test_has_include() {
+        let dir = TempTree::new(&[
+            ("inc/optional.h", ""),
+            ("inc/other.h", ""),
+            ("fallback.h", ""),
+            ("with_vector.h", ""),
+            ("without_vector.h", ""),
+            (
+                "main.cpp",
+                concat!(
+                    "#if __has_include(<optional.h>)\n",
+                    "#define HAVE_OPTIONAL 1\n",
+                    "#elif __has_include(<other.h>)\n",
+                    "#include <other.h>\n",
+                    "#else\n",
+                    "#include \"fallback.h\"\n",
+                    "#endif\n",
+                    "#if __has_include(<vector>)\n",
+                    "#include \"with_vector.h\"\n",
+                    "#else\n",
+                    "#include \"without_vector.h\"\n",
+                    "#endif\n",
+                ),
+            ),
+        ]);
+        let extract = |unknown_conditions| {
+            extract_includes(
+                &dir.join("main.cpp"),
+                &include_path(&[dir.join("inc")]),
+                &PreprocessorConfig {
+                    unknown_conditions,
+                    ..Default::default()
+                },
+                &PathResolver::default(),
+            )
+            .unwrap()
+        };
+
+        let source = extract(UnknownConditionPolicy::Both);
+        // the `#elif` cannot be reached, so its check is not recorded
+        assert_eq!(
+            source.optional_includes,
+            vec![Include {
+                path: dir.join("inc/optional.h"),
+                directive: IncludeDirective {
+                    line: 1,
+                    column: 5,
+                    spelling: "__has_include(<optional.h>)".into(),
+                    style: IncludeStyle::Angled,
+                },
+            }]
+        );
+        // a header that cannot be found is not known to be missing
+        assert_eq!(
+            included(&source),
+            vec![dir.join("with_vector.h"), dir.join("without_vector.h")]
+        );
+        assert!(source.unresolved.is_empty());
+
+        assert_eq!(
+            included(&extract(UnknownConditionPolicy::Taken)),
+            vec![dir.join("with_vector.h")]
+        );
+        assert_eq!(
+            included(&extract(UnknownConditionPolicy::NotTaken)),
+            vec![dir.join("without_vector.h")]
+        );
+    }
+
+    #[test]
+    fn test_generated_includes() {
+        let dir = TempTree::new(&[
+            ("inc/lib.h", ""),
+            (
+                "main.cpp",
+                concat!(
+                    "#include <vector>\n",
+                    "#include \"lbi.h\"\n",
+                    "#include \"gen/config.h\"\n",
+                    "#if __has_include(<gen/config.h>)\n",
+                    "#include \"has_config.h\"\n",
+                    "#endif\n",
+                    "#if __has_include(<vector>)\n",
+                    "#include \"has_vector.h\"\n",
+                    "#endif\n",
+                ),
+            ),
+        ]);
+        let resolver = PathResolver::default();
+        resolver.add_generated_root(dir.join("out/gen")).unwrap();
+
+        let source = extract_includes(
+            &dir.join("main.cpp"),
+            &include_path(&[dir.join("inc"), dir.join("out")]),
+            &PreprocessorConfig::default(),
+            &resolver,
+        )
+        .unwrap();
+
+        // only an include found via a searched directory becomes virtual
+        assert_eq!(included(&source), vec![dir.join("out/gen/config.h")]);
+        assert!(resolver.is_virtual(&dir.join("out/gen/config.h")));
+        assert_eq!(
+            source
+                .unresolved
+                .iter()
+                .map(|u| u.name.as_path())
+                .collect::<Vec<_>>(),
+            vec![
+                Path::new("vector"),
+                Path::new("lbi.h"),
+                Path::new("has_config.h"),
+                Path::new("has_vector.h"),
+            ]
+        );
+        // `__has_include` only considers existing files, so both checks are
+        // unknown (and the branches followed) and record nothing
+        assert!(source.optional_includes.is_empty());
+    }
+
+    #[test]
+    fn test_computed_includes() {
+        let dir = TempTree::new(&[
+            (
+                "config.h",
+                concat!(
+                    "#ifdef USE_B\n",
+                    "#define PLATFORM_HEADER \"platform/b.h\"\n",
+                    "#else\n",
+                    "#define PLATFORM_HEADER \"platform/a.h\"\n",
+                    "#endif\n",
+                    "#define MISSING_HEADER <platform/missing.h>\n",
+                ),
+            ),
+            ("platform/a.h", ""),
+            ("platform/b.h", ""),
+            (
+                "main.cpp",
+                concat!(
+                    "#define USE_B\n",
+                    "#include \"config.h\"\n",
+                    "#include PLATFORM_HEADER\n",
+                    "#include MISSING_HEADER\n",
+                    "#include UNKNOWN_HEADER\n",
+                ),
+            ),
+        ]);
+        let source = extract_includes(
+            &dir.join("main.cpp"),
+            &IncludePath::default(),
+            &PreprocessorConfig::default(),
+            &PathResolver::default(),
+        )
+        .unwrap();
+
+        // config.h is read with `USE_B` defined, as the file defined it first
+        assert_eq!(
+            included(&source),
+            vec![dir.join("config.h"), dir.join("platform/b.h")]
+        );
+        assert_eq!(
+            source.unresolved_computed,
+            vec![
+                UnresolvedComputedInclude {
+                    line: 4,
+                    expression: "MISSING_HEADER".into(),
+                    expansion: Some("<platform/missing.h>".into()),
+                },
+                UnresolvedComputedInclude {
+                    line: 5,
+                    expression: "UNKNOWN_HEADER".into(),
+                    expansion: None,
+                },
+            ]
+        );
+
+        let report = UnresolvedReport::new([&source], &SystemHeaders::default());
+        let main = dir.join("main.cpp");
+        assert_eq!(
+            report.to_string(),
+            format!(
+                concat!(
+                    "Unresolved includes: 2 (0 system headers, 2 other)\n",
+                    "\n",
+                    "{0}\n",
+                    "  {0}:4: #include MISSING_HEADER (expands to <platform/missing.h>)\n",
+                    "  {0}:5: #include UNKNOWN_HEADER\n",
+                ),
+                main.display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_parallel_results_in_order() {
+        let sources = (0..16)
+            .map(|i| {
+                (
+                    format!("src{}.cpp", i),
+                    format!("#include \"common.h\"\n#include \"missing{}.h\"\n", i),
+                )
+            })
+            .collect::<Vec<_>>();
+        let files = sources
+            .iter()
+            .map(|(path, content)| (path.as_str(), content.as_str()))
+            .chain([("common.h", "")])
+            .collect::<Vec<_>>();
+        let dir = TempTree::new(&files);
+        // directories cannot be read as files
+        std::fs::create_dir_all(dir.join("broken1.h")).unwrap();
+        std::fs::create_dir_all(dir.join("broken2.h")).unwrap();
+
+        let scan = |jobs: usize, names: &[String]| {
+            let paths = names.iter().map(|n| Ok::<_, ()>(dir.join(n)));
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(jobs)
+                .build()
+                .unwrap()
+                .install(|| {
+                    all_sources_and_includes(
+                        paths,
+                        &IncludePath::default(),
+                        &PreprocessorConfig::default(),
+                        &FileTypes::default(),
+                        &ScanCache::default(),
+                        &PathResolver::default(),
+                    )
+                })
+                .map_err(|e| e.to_string())
+        };
+
+        let mut names = (0..16)
+            .rev()
+            .map(|i| format!("src{}.cpp", i))
+            .collect::<Vec<_>>();
+        let sequential = scan(1, &names).unwrap();
+        assert_eq!(
+            sequential
+                .iter()
+                .map(|s| s.path.clone())
+                .collect::<Vec<_>>(),
+            names.iter().map(|n| dir.join(n)).collect::<Vec<_>>()
+        );
+        assert_eq!(scan(4, &names).unwrap(), sequential);
+
+        // the error of the first failing file is reported, however many jobs
+        names.insert(5, "broken2.h".into());
+        names.insert(9, "broken1.h".into());
+        let error = scan(1, &names).unwrap_err();
+        assert!(error.contains("broken2.h"), "{}", error);
+        assert_eq!(scan(4, &names).unwrap_err(), error);
+    }
 }
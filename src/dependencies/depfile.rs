@@ -0,0 +1,349 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+
+use tracing::{debug, info};
+
+use super::canonicalize::PathResolver;
+use super::cparse::{
+    FileType, FileTypes, Include, IncludeDirective, IncludeStyle, SourceWithIncludes,
+    UnresolvedInclude,
+};
+use super::error::Error;
+
+/// A rule of a Makefile-style dependency file: `targets: prerequisites`
+#[derive(Debug, PartialEq)]
+pub struct DepfileRule {
+    pub targets: Vec<String>,
+    pub prerequisites: Vec<String>,
+}
+
+fn flush(word: &mut String, list: &mut Vec<String>) {
+    if !word.is_empty() {
+        list.push(std::mem::take(word));
+    }
+}
+
+fn parse_rule(line: &str) -> Option<DepfileRule> {
+    let mut targets = Vec::new();
+    let mut prerequisites = Vec::new();
+    let mut in_targets = true;
+    let mut word = String::new();
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        let list = if in_targets {
+            &mut targets
+        } else {
+            &mut prerequisites
+        };
+        match c {
+            '\\' if matches!(chars.peek(), Some(' ' | '#')) => word.push(chars.next().unwrap()),
+            '$' if chars.peek() == Some(&'$') => {
+                chars.next();
+                word.push('$');
+            }
+            '#' => break,
+            // `C:\foo` is a path, `foo.o:` ends the targets
+            ':' if in_targets && chars.peek().is_none_or(|c| c.is_whitespace()) => {
+                flush(&mut word, list);
+                in_targets = false;
+            }
+            c if c.is_whitespace() => flush(&mut word, list),
+            c => word.push(c),
+        }
+    }
+
+    if in_targets {
+        return None;
+    }
+    flush(&mut word, &mut prerequisites);
+
+    Some(DepfileRule {
+        targets,
+        prerequisites,
+    })
+}
+
+/// Parse a dependency file as written by compilers for `-MD`/`-MMD`.
+///
+/// ```
+/// # use include_graph::dependencies::depfile::{parse_depfile, DepfileRule};
+/// assert_eq!(
+///     parse_depfile("obj/a.o: ../src/a.cpp \\\n  ../src/my\\ header.h\n../src/my\\ header.h:\n"),
+///     vec![
+///         DepfileRule {
+///             targets: vec!["obj/a.o".into()],
+///             prerequisites: vec!["../src/a.cpp".into(), "../src/my header.h".into()],
+///         },
+///         DepfileRule {
+///             targets: vec!["../src/my header.h".into()],
+///             prerequisites: vec![],
+///         },
+///     ]
+/// );
+/// ```
+pub fn parse_depfile(content: &str) -> Vec<DepfileRule> {
+    content
+        .replace("\\\r\n", " ")
+        .replace("\\\n", " ")
+        .lines()
+        .filter_map(parse_rule)
+        .collect()
+}
+
+/// Files read to build some output, as recorded by the build system or compiler
+#[derive(Debug)]
+pub struct Dependencies {
+    /// Where the dependencies were recorded, used to describe the includes
+    pub origin: String,
+
+    /// Directory that relative `paths` are relative to
+    pub root: PathBuf,
+
+    /// Dependency paths, as recorded
+    pub paths: Vec<String>,
+}
+
+/// Sources with includes recorded by a build, merged by path
+#[derive(Debug, Default)]
+pub struct RecordedSources {
+    sources: Vec<SourceWithIncludes>,
+    positions: HashMap<PathBuf, usize>,
+}
+
+impl RecordedSources {
+    /// The entry for `path`, created without includes if not known yet
+    pub fn entry(&mut self, path: &Path) -> &mut SourceWithIncludes {
+        let index = match self.positions.get(path) {
+            Some(index) => *index,
+            None => {
+                self.sources.push(SourceWithIncludes {
+                    path: path.to_path_buf(),
+                    includes: Vec::new(),
+                    optional_includes: Vec::new(),
+                    unresolved: Vec::new(),
+                    unresolved_computed: Vec::new(),
+                });
+                self.positions
+                    .insert(path.to_path_buf(), self.sources.len() - 1);
+                self.sources.len() - 1
+            }
+        };
+        &mut self.sources[index]
+    }
+
+    /// Record that `from` includes `name` (resolved to `path`, if it could be).
+    ///
+    /// Includes of a path that is already known are ignored.
+    pub fn add_include(
+        &mut self,
+        from: &Path,
+        name: &str,
+        path: Option<PathBuf>,
+        directive: IncludeDirective,
+    ) {
+        let entry = self.entry(from);
+        match path {
+            Some(path) => {
+                if !entry.includes.iter().any(|i| i.path == path) {
+                    entry.includes.push(Include { path, directive });
+                }
+            }
+            None => entry.unresolved.push(UnresolvedInclude {
+                name: PathBuf::from(name),
+                directive,
+            }),
+        }
+    }
+
+    pub fn into_sources(self) -> Vec<SourceWithIncludes> {
+        self.sources
+    }
+}
+
+/// Turn recorded dependencies into sources with includes.
+///
+/// The first dependency that is a source (according to `file_types`) is the
+/// compiled file, all others are considered its includes. As dependencies are
+/// all the files read during compilation, these include headers that are only
+/// included indirectly.
+///
+/// Dependencies of the same source (e.g. compiled for several outputs) are merged.
+pub fn sources_from_dependencies<I>(
+    dependencies: I,
+    file_types: &FileTypes,
+    resolver: &PathResolver,
+) -> Vec<SourceWithIncludes>
+where
+    I: IntoIterator<Item = Dependencies>,
+{
+    let mut sources = RecordedSources::default();
+
+    for deps in dependencies {
+        let resolved = deps
+            .paths
+            .iter()
+            .map(|p| (p, resolver.canonicalize(deps.root.join(p)).ok().flatten()))
+            .collect::<Vec<_>>();
+
+        let source = match resolved.iter().find_map(|(_, path)| {
+            path.as_ref()
+                .filter(|p| file_types.of(p) == FileType::Source)
+        }) {
+            Some(source) => source.clone(),
+            None => {
+                debug!(target: "depfile", "No source found in dependencies from {}", deps.origin);
+                continue;
+            }
+        };
+
+        sources.entry(&source);
+        for (name, path) in resolved {
+            if path.as_ref() == Some(&source) {
+                continue;
+            }
+            let directive = IncludeDirective {
+                line: 0,
+                column: 0,
+                spelling: format!("{}: {}", deps.origin, name),
+                style: IncludeStyle::Quoted,
+            };
+            sources.add_include(&source, name, path, directive);
+        }
+    }
+
+    sources.into_sources()
+}
+
+/// Load sources and their includes from dependency files.
+///
+/// Relative paths are relative to `root`, which is the working directory of
+/// the compiler (generally the build directory).
+pub fn load_depfiles<I, E>(
+    paths: I,
+    root: &Path,
+    file_types: &FileTypes,
+    resolver: &PathResolver,
+) -> Result<Vec<SourceWithIncludes>, Error>
+where
+    I: Iterator<Item = Result<PathBuf, E>>,
+    E: Debug,
+{
+    let mut dependencies = Vec::new();
+
+    for path in paths {
+        let path = path.map_err(|e| Error::Internal {
+            message: format!("{:?}", e),
+        })?;
+        let content = std::fs::read_to_string(&path).map_err(|source| Error::FileIOError {
+            source,
+            path: path.clone(),
+            message: "read",
+        })?;
+
+        // rules without prerequisites are phony targets for headers (`-MP`)
+        dependencies.extend(
+            parse_depfile(&content)
+                .into_iter()
+                .filter(|rule| !rule.prerequisites.is_empty())
+                .map(|rule| Dependencies {
+                    origin: path.display().to_string(),
+                    root: root.to_path_buf(),
+                    paths: rule.prerequisites,
+                }),
+        );
+    }
+
+    let sources = sources_from_dependencies(dependencies, file_types, resolver);
+    info!(target: "depfile", "Loaded dependencies of {} sources", sources.len());
+
+    Ok(sources)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dependencies::test_util::TempTree;
+
+    #[test]
+    fn test_parse_depfile() {
+        assert_eq!(
+            parse_depfile("# comment\n\na.o b.o : a.c x$$.h C:\\inc\\y.h z\\#.h # trailing\n"),
+            vec![DepfileRule {
+                targets: vec!["a.o".into(), "b.o".into()],
+                prerequisites: vec![
+                    "a.c".into(),
+                    "x$.h".into(),
+                    "C:\\inc\\y.h".into(),
+                    "z#.h".into()
+                ],
+            }]
+        );
+        assert_eq!(
+            parse_depfile("a.o: \\\r\n a.c\\\n b.h"),
+            vec![DepfileRule {
+                targets: vec!["a.o".into()],
+                prerequisites: vec!["a.c".into(), "b.h".into()],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_sources_from_dependencies() {
+        let dir = TempTree::new(&[("src/a.h", ""), ("src/b.h", ""), ("src/a.cpp", "")]);
+
+        let deps = |paths: &[&str]| Dependencies {
+            origin: "test.d".into(),
+            root: dir.to_path_buf(),
+            paths: paths.iter().map(|p| p.to_string()).collect(),
+        };
+
+        let sources = sources_from_dependencies(
+            [
+                deps(&["src/a.h", "src/a.cpp", "src/missing.h"]),
+                deps(&["src/a.cpp", "src/b.h", "src/./a.h"]),
+                deps(&["src/a.h"]),
+            ],
+            &FileTypes::default(),
+            &PathResolver::default(),
+        );
+
+        assert_eq!(sources.len(), 1);
+        assert_eq!(sources[0].path, dir.join("src/a.cpp"));
+        assert_eq!(
+            sources[0]
+                .includes
+                .iter()
+                .map(|i| i.path.clone())
+                .collect::<Vec<_>>(),
+            vec![dir.join("src/a.h"), dir.join("src/b.h")]
+        );
+        assert_eq!(sources[0].unresolved[0].name, Path::new("src/missing.h"));
+        assert_eq!(sources[0].includes[0].directive.spelling, "test.d: src/a.h");
+    }
+
+    #[test]
+    fn test_load_depfiles() {
+        let dir = TempTree::new(&[
+            ("src/a.cpp", ""),
+            ("src/a.h", ""),
+            // relative to the build directory, as written by the compiler
+            ("out/obj/src/a.d", "obj/src/a.o: ../src/a.cpp ../src/a.h\n"),
+        ]);
+        let depfile = dir.join("out/obj/src/a.d");
+
+        let sources = load_depfiles(
+            [Ok::<_, ()>(depfile)].into_iter(),
+            &dir.join("out"),
+            &FileTypes::default(),
+            &PathResolver::default(),
+        )
+        .unwrap();
+
+        assert_eq!(sources.len(), 1);
+        assert_eq!(sources[0].path, dir.join("src/a.cpp"));
+        assert_eq!(sources[0].includes[0].path, dir.join("src/a.h"));
+        assert!(sources[0].unresolved.is_empty());
+    }
+}
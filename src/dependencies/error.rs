@@ -23,20 +23,41 @@ pub enum Error {
     #[error("Failed to parse JSON")]
     JsonParseError(serde_json::Error),
 
-    // std::thread panics do not return printable values by default, and while
-    // it might be possible to upcast to a `dyn Debug`, it is hardly worth it
-    // here.
-    #[error("Subtask join error")]
-    JoinError,
-
     // Unfortunately, in most cases where this error can occur the path is no
     // longer available to avoid unnecessary cloning in the hot path.
     #[error("Required file not found")]
     FileNotFound,
 
+    #[error("Compile database entry {}: {}", entry, message)]
+    InvalidCompileCommand { entry: String, message: String },
+
+    #[error("Compile database entry {}: cannot read response file {}", entry, path.to_string_lossy())]
+    ResponseFileError {
+        #[source]
+        source: std::io::Error,
+        entry: String,
+        path: PathBuf,
+    },
+
+    #[error("Compile database entry {}: source file not found", entry)]
+    CompileDbSourceNotFound { entry: String },
+
+    #[error("Invalid glob pattern {}", pattern)]
+    InvalidPattern {
+        #[source]
+        source: glob::PatternError,
+        pattern: String,
+    },
+
     #[error("Internal error")]
     Internal { message: String },
 
-    #[error("Error parsing a config file (nom error)")]
+    #[error("{}", message.trim_end())]
     ConfigParseError { message: String },
+
+    #[error("Config import cycle: {}", cycle)]
+    ImportCycle { cycle: String },
+
+    #[error("{}", message.trim_end())]
+    UndefinedVariables { message: String },
 }
@@ -3,7 +3,7 @@ use std::{collections::HashMap, path::PathBuf, process::Command};
 use serde::Deserialize;
 use tracing::{error, info};
 
-use super::canonicalize::canonicalize_cached;
+use super::canonicalize::PathResolver;
 use super::error::Error;
 
 #[derive(Debug, PartialEq)]
@@ -27,10 +27,12 @@ pub fn load_gn_targets(
     gn_dir: PathBuf,
     source_root: PathBuf,
     target: &str,
+    resolver: &PathResolver,
 ) -> Result<Vec<GnTarget>, Error> {
     // TODO: GN PATH?
     let mut command = Command::new("/usr/bin/gn");
-    let source_root = canonicalize_cached(source_root)
+    let source_root = resolver
+        .canonicalize(source_root)
         .map_err(|e| Error::Internal {
             message: format!("Canonical path: {:?}", e),
         })?
@@ -40,7 +42,8 @@ pub fn load_gn_targets(
     command.arg("--format=json");
     command.arg(format!("--root={}", source_root.to_string_lossy()));
     command.arg(
-        canonicalize_cached(gn_dir)
+        resolver
+            .canonicalize(gn_dir)
             .map_err(|e| Error::Internal {
                 message: format!("Canonical path: {:?}", e),
             })?
@@ -55,14 +58,14 @@ pub fn load_gn_targets(
 
     if !output.status.success() {
         let data = String::from_utf8_lossy(&output.stdout);
-        if data.len() > 0 {
+        if !data.is_empty() {
             for l in data.lines() {
                 error!("STDOUT: {}", l);
             }
         }
 
         let data = String::from_utf8_lossy(&output.stderr);
-        if data.len() > 0 {
+        if !data.is_empty() {
             for l in data.lines() {
                 error!("STDERR: {}", l);
             }
@@ -99,7 +102,7 @@ pub fn load_gn_targets(
                             // otherwise assume absolute and use as-is
                             PathBuf::from(&s.as_str())
                         };
-                        canonicalize_cached(p).ok()?
+                        resolver.canonicalize(p).ok()?
                     })
                     .inspect(|path| {
                         info!(target: "gn-path", " - {:?}", path);
@@ -1,14 +1,16 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt::Display,
+    hash::{Hash, Hasher},
     io::{BufWriter, Write},
     path::{Path, PathBuf},
 };
 
 use serde::Serialize;
-use tera::{Context, Tera};
+use tera::{Context, Tera, Value};
 use tracing::{debug, error};
 
-use super::{error::Error, gn::GnTarget, path_mapper::PathMapping};
+use super::{cparse::IncludeDirective, error::Error, gn::GnTarget, path_mapper::PathMapping};
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
 pub struct MappedNode {
@@ -20,6 +22,9 @@ pub struct MappedNode {
 
     // mapped name for display
     pub display_name: String,
+
+    /// A file that does not exist (yet), like a generated header
+    pub is_virtual: bool,
 }
 
 /// A group of related items.
@@ -54,6 +59,7 @@ impl Group {
                 id: new_id.clone(),
                 path: n.path.clone(),
                 display_name: n.display_name.clone(),
+                is_virtual: n.is_virtual,
             });
             id_map.insert(n.id.clone(), new_id);
         }
@@ -97,15 +103,86 @@ impl LinkNode {
     }
 }
 
-#[derive(Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Serialize)]
+/// Where an include that a link represents is written
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Serialize)]
+pub struct IncludeLocation {
+    /// Mapped name of the including file
+    pub file: String,
+
+    pub line: usize,
+    pub column: usize,
+
+    /// The include as written, like `#include "foo/bar.h"`
+    pub spelling: String,
+}
+
+impl Display for IncludeLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.spelling)
+    }
+}
+
+/// A link between nodes or groups.
+///
+/// Links are identified by their ends and style only: `locations` are
+/// not compared, but merged when equal links are added (see `merge_link`).
+#[derive(Debug, Clone, Serialize)]
 pub struct GraphLink {
     pub from: LinkNode,
     pub to: LinkNode,
     pub color: Option<String>, // specific color for a link
     pub is_bold: bool,         // should the link color be bold?
+    pub is_optional: bool,     // link only exists as a `__has_include` check
+
+    /// includes this link represents
+    pub locations: BTreeSet<IncludeLocation>,
+}
+
+impl PartialEq for GraphLink {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for GraphLink {}
+
+impl Hash for GraphLink {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key().hash(state)
+    }
+}
+
+impl PartialOrd for GraphLink {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for GraphLink {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+/// Insert `link`, merging its locations into an equal existing link (if any)
+fn merge_link(links: &mut HashSet<GraphLink>, mut link: GraphLink) {
+    if let Some(existing) = links.take(&link) {
+        link.locations.extend(existing.locations);
+    }
+    links.insert(link);
 }
 
 impl GraphLink {
+    fn key(&self) -> (&LinkNode, &LinkNode, &Option<String>, bool, bool) {
+        (
+            &self.from,
+            &self.to,
+            &self.color,
+            self.is_bold,
+            self.is_optional,
+        )
+    }
+
     pub fn try_remap(&self, m: &HashMap<String, String>) -> Option<Self> {
         Some(Self {
             from: self.from.try_remap(m)?,
@@ -127,6 +204,7 @@ impl Graph {
         let mut writer = BufWriter::new(dest);
 
         let mut tera = Tera::default();
+        tera.register_filter("dot_escape", dot_escape);
         tera.add_raw_template("dot_template", include_str!("dot.template"))
             .map_err(Error::RenderError)?;
 
@@ -151,6 +229,14 @@ impl Graph {
     }
 }
 
+/// Escapes a value for use within a double-quoted dot string
+fn dot_escape(value: &Value, _: &HashMap<String, Value>) -> tera::Result<Value> {
+    let value = tera::try_get_value!("dot_escape", "value", String, value);
+    Ok(Value::String(
+        value.replace('\\', "\\\\").replace('"', "\\\""),
+    ))
+}
+
 #[derive(Debug, Default)]
 pub struct GraphBuilder {
     /// Actual graph being built
@@ -167,6 +253,9 @@ pub struct GraphBuilder {
 
     /// What graphs are focused zoomed. Remove links that span non-focused
     focus_zoomed: HashSet<String>,
+
+    /// known paths of files that do not exist
+    virtual_paths: HashSet<PathBuf>,
 }
 
 impl GraphBuilder {
@@ -177,10 +266,27 @@ impl GraphBuilder {
         }
     }
 
+    /// Whether `path` is mapped to a node, including virtual ones
     pub fn known_path(&self, path: &Path) -> bool {
         self.path_maps.contains_key(path)
     }
 
+    /// Mark known paths as virtual (e.g. generated files that do not exist yet),
+    /// which are displayed differently.
+    ///
+    /// Must be called before groups are defined.
+    pub fn mark_virtual<F>(&mut self, is_virtual: F)
+    where
+        F: Fn(&Path) -> bool,
+    {
+        self.virtual_paths = self
+            .path_maps
+            .keys()
+            .filter(|p| is_virtual(p))
+            .cloned()
+            .collect();
+    }
+
     pub fn group_extensions(&mut self, extensions: &[&str]) {
         // Get every single possible grouping
         let groups = self
@@ -236,11 +342,14 @@ impl GraphBuilder {
 
         for k in keys {
             self.graph.links.remove(&k);
-            self.graph.links.insert(GraphLink {
-                color: Some(color.into()),
-                is_bold,
-                ..k
-            });
+            merge_link(
+                &mut self.graph.links,
+                GraphLink {
+                    color: Some(color.into()),
+                    is_bold,
+                    ..k
+                },
+            );
         }
     }
 
@@ -264,11 +373,14 @@ impl GraphBuilder {
 
         for k in keys {
             self.graph.links.remove(&k);
-            self.graph.links.insert(GraphLink {
-                color: Some(color.into()),
-                is_bold,
-                ..k
-            });
+            merge_link(
+                &mut self.graph.links,
+                GraphLink {
+                    color: Some(color.into()),
+                    is_bold,
+                    ..k
+                },
+            );
         }
     }
 
@@ -357,7 +469,10 @@ impl GraphBuilder {
 
                 Some(link)
             })
-            .collect::<HashSet<_>>();
+            .fold(HashSet::new(), |mut links, link| {
+                merge_link(&mut links, link);
+                links
+            });
 
         // Create group links only here
         let links = self
@@ -370,6 +485,23 @@ impl GraphBuilder {
                 ..l.clone()
             })
             .filter(|l| l.from != l.to)
+            .fold(HashSet::new(), |mut links, link| {
+                merge_link(&mut links, link);
+                links
+            });
+
+        // grouping may produce both regular and optional links between the
+        // same groups, in which case only the regular one is kept
+        let links = links
+            .iter()
+            .filter(|l| {
+                !l.is_optional
+                    || !links.contains(&GraphLink {
+                        is_optional: false,
+                        ..(*l).clone()
+                    })
+            })
+            .cloned()
             .collect::<HashSet<_>>();
 
         self.graph.links = {
@@ -402,7 +534,37 @@ impl GraphBuilder {
         Some(full_location.clone())
     }
 
-    pub fn add_link(&mut self, from: &Path, to: &Path) {
+    /// Adds a link for an include of `to` in `from`, written as `directive`.
+    pub fn add_link(&mut self, from: &Path, to: &Path, directive: &IncludeDirective) {
+        self.insert_link(from, to, directive, false)
+    }
+
+    /// Adds a link for an include that is only checked for existence
+    /// (e.g. `__has_include`).
+    ///
+    /// Regular links between the same nodes take precedence.
+    pub fn add_optional_link(&mut self, from: &Path, to: &Path, directive: &IncludeDirective) {
+        self.insert_link(from, to, directive, true)
+    }
+
+    fn insert_link(
+        &mut self,
+        from: &Path,
+        to: &Path,
+        directive: &IncludeDirective,
+        is_optional: bool,
+    ) {
+        let location = IncludeLocation {
+            file: self
+                .path_maps
+                .get(from)
+                .map(|m| m.to.clone())
+                .unwrap_or_else(|| from.to_string_lossy().into()),
+            line: directive.line,
+            column: directive.column,
+            spelling: directive.spelling.clone(),
+        };
+
         let from = match self.ensure_link_node(from) {
             Some(p) => p,
             None => {
@@ -423,12 +585,28 @@ impl GraphBuilder {
             return;
         }
 
-        self.graph.links.insert(GraphLink {
+        let link = GraphLink {
             from,
             to,
             color: None,
             is_bold: false,
-        });
+            is_optional,
+            locations: BTreeSet::from([location]),
+        };
+
+        let other = GraphLink {
+            is_optional: !is_optional,
+            ..link.clone()
+        };
+
+        if self.graph.links.contains(&other) {
+            if is_optional {
+                return;
+            }
+            self.graph.links.remove(&other);
+        }
+
+        merge_link(&mut self.graph.links, link);
     }
 
     pub fn add_groups_from_gn(
@@ -508,6 +686,7 @@ impl GraphBuilder {
                 id: node_id.clone(),
                 path: PathBuf::from(path),
                 display_name: m.to.clone(),
+                is_virtual: self.virtual_paths.contains(path),
             });
 
             self.placement_maps.insert(
@@ -544,3 +723,157 @@ impl GraphBuilder {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dependencies::cparse::IncludeStyle;
+
+    fn link(line: usize) -> GraphLink {
+        GraphLink {
+            from: LinkNode {
+                group_id: "a".into(),
+                node_id: None,
+            },
+            to: LinkNode {
+                group_id: "b".into(),
+                node_id: None,
+            },
+            color: None,
+            is_bold: false,
+            is_optional: false,
+            locations: BTreeSet::from([IncludeLocation {
+                file: "a.cpp".into(),
+                line,
+                column: 1,
+                spelling: "#include \"b.h\"".into(),
+            }]),
+        }
+    }
+
+    #[test]
+    fn test_merge_link() {
+        let mut links = HashSet::new();
+        merge_link(&mut links, link(1));
+        merge_link(&mut links, link(3));
+        merge_link(&mut links, link(1));
+
+        assert_eq!(links.len(), 1);
+        assert_eq!(
+            links
+                .iter()
+                .next()
+                .unwrap()
+                .locations
+                .iter()
+                .map(|l| l.to_string())
+                .collect::<Vec<_>>(),
+            vec!["a.cpp:1: #include \"b.h\"", "a.cpp:3: #include \"b.h\""]
+        );
+    }
+
+    #[test]
+    fn test_optional_links() {
+        let mapping = |from: &str, to: &str| PathMapping {
+            from: from.into(),
+            to: to.into(),
+        };
+        let directive = |line| IncludeDirective {
+            line,
+            column: 1,
+            spelling: "#include \"b.h\"".into(),
+            style: IncludeStyle::Quoted,
+        };
+        let (a, b, c) = (Path::new("/a.cpp"), Path::new("/b.h"), Path::new("/c.h"));
+        let mut builder = GraphBuilder::new(
+            [
+                mapping("/a.cpp", "a.cpp"),
+                mapping("/b.h", "b.h"),
+                mapping("/c.h", "c.h"),
+            ]
+            .into_iter(),
+        );
+        let links = |builder: &GraphBuilder| {
+            let mut links = builder
+                .graph
+                .links
+                .iter()
+                .map(|l| {
+                    (
+                        l.to.group_id.clone(),
+                        l.is_optional,
+                        l.locations.iter().map(|l| l.line).collect::<Vec<_>>(),
+                    )
+                })
+                .collect::<Vec<_>>();
+            links.sort();
+            links
+        };
+        let b_id = |builder: &GraphBuilder| builder.placement_maps[b].group_id.clone();
+
+        // only checked for
+        builder.add_optional_link(a, b, &directive(1));
+        assert_eq!(links(&builder), vec![(b_id(&builder), true, vec![1])]);
+
+        // an actual include replaces the optional link
+        builder.add_link(a, b, &directive(3));
+        assert_eq!(links(&builder), vec![(b_id(&builder), false, vec![3])]);
+
+        // ... and is not downgraded by later checks
+        builder.add_optional_link(a, b, &directive(5));
+        assert_eq!(links(&builder), vec![(b_id(&builder), false, vec![3])]);
+
+        // links to unmapped files and to self are dropped
+        builder.add_optional_link(a, Path::new("/unmapped.h"), &directive(7));
+        builder.add_optional_link(c, c, &directive(8));
+        assert_eq!(links(&builder).len(), 1);
+    }
+
+    #[test]
+    fn test_virtual_nodes() {
+        let mapping = |from: &str, to: &str| PathMapping {
+            from: from.into(),
+            to: to.into(),
+        };
+        let mut builder = GraphBuilder::new(
+            [
+                mapping("/src/a.cpp", "src::a.cpp"),
+                mapping("/out/gen/a.pb.h", "gen::a.pb.h"),
+            ]
+            .into_iter(),
+        );
+        builder.mark_virtual(|p| p.starts_with("/out/gen"));
+
+        let directive = IncludeDirective {
+            line: 1,
+            column: 1,
+            spelling: "#include \"a.pb.h\"".into(),
+            style: IncludeStyle::Quoted,
+        };
+        assert!(builder.known_path(Path::new("/out/gen/a.pb.h")));
+        builder.add_link(
+            Path::new("/src/a.cpp"),
+            Path::new("/out/gen/a.pb.h"),
+            &directive,
+        );
+
+        let graph = builder.build();
+        assert_eq!(graph.links.len(), 1);
+        let nodes = graph
+            .groups
+            .values()
+            .flat_map(|g| g.nodes.iter())
+            .map(|n| (n.display_name.as_str(), n.is_virtual))
+            .collect::<BTreeSet<_>>();
+        assert_eq!(
+            nodes,
+            BTreeSet::from([("gen::a.pb.h", true), ("src::a.cpp", false)])
+        );
+
+        let mut dot = Vec::new();
+        graph.write_dot(&mut dot).unwrap();
+        assert!(String::from_utf8(dot)
+            .unwrap()
+            .contains("<I>gen::a.pb.h</I>"));
+    }
+}
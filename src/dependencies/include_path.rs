@@ -0,0 +1,151 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// How an include directory was given to the compiler, which determines
+/// for what includes and in what order it is searched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum IncludeDirectoryKind {
+    /// `-iquote`: only searched for `#include "..."`
+    Quote,
+
+    /// `-I` or `--include-directory`
+    Regular,
+
+    /// `-isystem` and the default system directories of a `--sysroot`
+    System,
+
+    /// `-idirafter`: searched after all other directories
+    After,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct IncludeDirectory {
+    pub path: PathBuf,
+    pub kind: IncludeDirectoryKind,
+}
+
+/// Ordered include directories, as searched by the compiler.
+///
+/// Directories are kept in command line order within each kind and kinds
+/// are searched in order: quote, regular, system and then "after" directories.
+/// A directory given more than once is only searched at its first position.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct IncludePath {
+    directories: Vec<IncludeDirectory>,
+}
+
+impl IncludePath {
+    /// Create a search path out of directories in command line order.
+    pub fn new<I>(directories: I) -> Self
+    where
+        I: IntoIterator<Item = IncludeDirectory>,
+    {
+        let mut directories = directories.into_iter().collect::<Vec<_>>();
+        directories.sort_by_key(|d| d.kind);
+
+        let mut result = Self::default();
+        for d in directories {
+            result.push(d);
+        }
+        result
+    }
+
+    /// Add a directory at the end of the search path (if not already part of it).
+    pub fn push(&mut self, directory: IncludeDirectory) {
+        if !self.directories.iter().any(|d| d.path == directory.path) {
+            self.directories.push(directory);
+        }
+    }
+
+    /// Add all directories of `other` at the end of the search path.
+    pub fn append(&mut self, other: &IncludePath) {
+        for d in other.directories.iter() {
+            self.push(d.clone());
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.directories.is_empty()
+    }
+
+    /// All directories, in search order
+    pub fn directories(&self) -> &[IncludeDirectory] {
+        &self.directories
+    }
+
+    /// Directories searched for an include, starting at position `start`.
+    ///
+    /// Quote directories are only searched for `quoted` includes.
+    pub fn search(&self, quoted: bool, start: usize) -> impl Iterator<Item = &Path> {
+        self.directories
+            .iter()
+            .skip(start)
+            .filter(move |d| quoted || d.kind != IncludeDirectoryKind::Quote)
+            .map(|d| d.path.as_path())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir(path: &str, kind: IncludeDirectoryKind) -> IncludeDirectory {
+        IncludeDirectory {
+            path: path.into(),
+            kind,
+        }
+    }
+
+    #[test]
+    fn test_search_order() {
+        let path = IncludePath::new([
+            dir("sys", IncludeDirectoryKind::System),
+            dir("a", IncludeDirectoryKind::Regular),
+            dir("after", IncludeDirectoryKind::After),
+            dir("q", IncludeDirectoryKind::Quote),
+            dir("b", IncludeDirectoryKind::Regular),
+            dir("a", IncludeDirectoryKind::System),
+        ]);
+
+        assert_eq!(
+            path.search(true, 0).collect::<Vec<_>>(),
+            vec![
+                Path::new("q"),
+                Path::new("a"),
+                Path::new("b"),
+                Path::new("sys"),
+                Path::new("after")
+            ]
+        );
+        assert_eq!(
+            path.search(false, 0).collect::<Vec<_>>(),
+            vec![
+                Path::new("a"),
+                Path::new("b"),
+                Path::new("sys"),
+                Path::new("after")
+            ]
+        );
+        assert_eq!(
+            path.search(false, 3).collect::<Vec<_>>(),
+            vec![Path::new("sys"), Path::new("after")]
+        );
+    }
+
+    #[test]
+    fn test_append() {
+        let mut path = IncludePath::new([dir("a", IncludeDirectoryKind::System)]);
+        path.append(&IncludePath::new([
+            dir("b", IncludeDirectoryKind::Quote),
+            dir("a", IncludeDirectoryKind::Regular),
+        ]));
+
+        assert_eq!(
+            path.directories(),
+            &[
+                dir("a", IncludeDirectoryKind::System),
+                dir("b", IncludeDirectoryKind::Quote)
+            ]
+        );
+    }
+}
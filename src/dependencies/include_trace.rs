@@ -0,0 +1,439 @@
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+use regex::Regex;
+use tracing::{info, warn};
+
+use super::canonicalize::PathResolver;
+use super::cparse::{FileType, FileTypes, IncludeDirective, IncludeStyle, SourceWithIncludes};
+use super::depfile::RecordedSources;
+use super::error::Error;
+
+/// What produced an include trace
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// Detect the format from the trace content
+    #[default]
+    Auto,
+
+    /// `-H` output of gcc/clang: `.. path` where the number of dots is the depth
+    DotDepth,
+
+    /// MSVC `/showIncludes` output: `Note: including file:  path` where the
+    /// number of spaces is the depth
+    ShowIncludes,
+
+    /// Line markers of preprocessed (`.i`) output: `# 1 "path" 1` or `#line 1 "path"`
+    LineMarkers,
+}
+
+/// Included file names in order, with their depth (1 for files included by
+/// the root) and the line of the include in their parent
+type IncludeList = Vec<(usize, String, usize)>;
+
+/// A file and the files it included, as recorded in a trace
+#[derive(Debug, PartialEq)]
+pub struct IncludeTree {
+    /// File name as written in the trace
+    pub name: String,
+
+    /// Line of the include in the parent (0 if not known)
+    pub line: usize,
+
+    pub children: Vec<IncludeTree>,
+}
+
+impl IncludeTree {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.into(),
+            line: 0,
+            children: Vec::new(),
+        }
+    }
+
+    /// Build a tree from includes listed in order with their depth
+    fn from_depths(name: &str, includes: IncludeList) -> Self {
+        let mut stack = vec![IncludeTree::new(name)];
+
+        for (depth, name, line) in includes {
+            while stack.len() > depth.max(1) {
+                let child = stack.pop().unwrap();
+                stack.last_mut().unwrap().children.push(child);
+            }
+            stack.push(IncludeTree {
+                name,
+                line,
+                children: Vec::new(),
+            });
+        }
+
+        while stack.len() > 1 {
+            let child = stack.pop().unwrap();
+            stack.last_mut().unwrap().children.push(child);
+        }
+        stack.pop().unwrap()
+    }
+}
+
+static LINE_MARKER_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"^#\s*(?:line\s+)?(\d+)\s+"((?:[^"\\]|\\.)*)"((?:\s+\d+)*)\s*$"#).unwrap()
+});
+
+const SHOW_INCLUDES_PREFIX: &str = "Note: including file:";
+
+fn detect_format(content: &str) -> TraceFormat {
+    if content.lines().any(|l| l.starts_with(SHOW_INCLUDES_PREFIX)) {
+        TraceFormat::ShowIncludes
+    } else if content.lines().any(|l| LINE_MARKER_REGEX.is_match(l)) {
+        TraceFormat::LineMarkers
+    } else {
+        TraceFormat::DotDepth
+    }
+}
+
+/// Depth and name of an include listed in `-H` or `/showIncludes` output
+fn parse_depth_line(line: &str, format: TraceFormat) -> Option<(usize, &str)> {
+    let (marker, rest) = match format {
+        TraceFormat::ShowIncludes => (' ', line.strip_prefix(SHOW_INCLUDES_PREFIX)?),
+        _ => ('.', line),
+    };
+    let name = rest.trim_start_matches(marker);
+    let depth = rest.len() - name.len();
+    let name = match format {
+        TraceFormat::ShowIncludes => name.trim_end(),
+        _ => name.strip_prefix(' ')?.trim_end(),
+    };
+
+    if depth == 0 || name.is_empty() {
+        return None;
+    }
+    Some((depth, name))
+}
+
+/// Parse `-H` or `/showIncludes` output.
+///
+/// Traces do not name the compiled source, however compilers (MSVC) or build
+/// scripts often print it before the includes. Lines accepted by `is_source`
+/// start the trace of a new source. Includes listed before any source are
+/// attributed to `default_source` (if any).
+fn parse_depth_trace<F>(
+    content: &str,
+    format: TraceFormat,
+    default_source: Option<&str>,
+    is_source: F,
+) -> Vec<IncludeTree>
+where
+    F: Fn(&str) -> bool,
+{
+    let mut trees = Vec::new();
+    let mut current: Option<(String, IncludeList)> =
+        default_source.map(|s| (s.to_string(), Vec::new()));
+
+    for line in content.lines() {
+        if let Some((depth, name)) = parse_depth_line(line, format) {
+            match current {
+                Some((_, ref mut includes)) => includes.push((depth, name.into(), 0)),
+                None => warn!(target: "include-trace", "Include {:?} of unknown source", name),
+            }
+        } else if is_source(line.trim()) {
+            if let Some((name, includes)) = current.take() {
+                trees.push(IncludeTree::from_depths(&name, includes));
+            }
+            current = Some((line.trim().into(), Vec::new()));
+        }
+    }
+
+    if let Some((name, includes)) = current {
+        trees.push(IncludeTree::from_depths(&name, includes));
+    }
+
+    trees.retain(|t| !t.children.is_empty());
+    trees
+}
+
+fn unescape(name: &str) -> String {
+    let mut result = String::with_capacity(name.len());
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => result.extend(chars.next()),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+/// Parse the line markers of preprocessed output.
+///
+/// The first marker names the compiled source. Entering a file is marked by
+/// flag `1` (gcc/clang) or, for markers without flags (MSVC `#line`), by a
+/// file name that is not on the include stack.
+fn parse_line_markers(content: &str) -> Option<IncludeTree> {
+    // file names and the line of the next line of output
+    let mut stack: Vec<(String, usize)> = Vec::new();
+    let mut includes = Vec::new();
+
+    for line in content.lines() {
+        let captures = match LINE_MARKER_REGEX.captures(line) {
+            Some(captures) => captures,
+            None => {
+                if let Some((_, next_line)) = stack.last_mut() {
+                    *next_line += 1;
+                }
+                continue;
+            }
+        };
+
+        let line_number = captures[1].parse::<usize>().unwrap_or_default();
+        let name = unescape(&captures[2]);
+        let flags = captures[3].split_whitespace().collect::<Vec<_>>();
+
+        if stack.is_empty() {
+            stack.push((name, line_number));
+            continue;
+        }
+
+        let position = stack.iter().rposition(|(n, _)| *n == name);
+        let entering = flags.contains(&"1") || (flags.is_empty() && position.is_none());
+
+        if entering {
+            // includes of pseudo files like `<command-line>` are skipped
+            if !stack.iter().any(|(n, _)| n.starts_with('<')) && !name.starts_with('<') {
+                let line_in_parent = stack.last().unwrap().1;
+                includes.push((stack.len(), name.clone(), line_in_parent));
+            }
+            stack.push((name, line_number));
+        } else if let Some(position) = position {
+            // returning to a file (or a line jump within the current one)
+            stack.truncate(position + 1);
+            stack.last_mut().unwrap().1 = line_number;
+        }
+    }
+
+    let root = stack.into_iter().next()?;
+    Some(IncludeTree::from_depths(&root.0, includes))
+}
+
+/// Parse an include trace into one include tree per compiled source.
+///
+/// `default_source` and `is_source` identify the compiled sources of `-H` and
+/// `/showIncludes` traces, see [`TraceFormat`].
+///
+/// ```
+/// # use include_graph::dependencies::include_trace::{parse_trace, IncludeTree, TraceFormat};
+/// let trees = parse_trace(
+///     "a.cpp\n. a.h\n.. b.h\n. c.h\n",
+///     TraceFormat::Auto,
+///     None,
+///     |line| line.ends_with(".cpp"),
+/// );
+/// assert_eq!(trees.len(), 1);
+/// assert_eq!(trees[0].name, "a.cpp");
+/// assert_eq!(trees[0].children[0].name, "a.h");
+/// assert_eq!(trees[0].children[0].children[0].name, "b.h");
+/// assert_eq!(trees[0].children[1].name, "c.h");
+/// ```
+pub fn parse_trace<F>(
+    content: &str,
+    format: TraceFormat,
+    default_source: Option<&str>,
+    is_source: F,
+) -> Vec<IncludeTree>
+where
+    F: Fn(&str) -> bool,
+{
+    let format = match format {
+        TraceFormat::Auto => detect_format(content),
+        other => other,
+    };
+
+    match format {
+        TraceFormat::LineMarkers => parse_line_markers(content).into_iter().collect(),
+        _ => parse_depth_trace(content, format, default_source, is_source),
+    }
+}
+
+fn add_tree(
+    sources: &mut RecordedSources,
+    tree: &IncludeTree,
+    path: &Path,
+    origin: &str,
+    root: &Path,
+    resolver: &PathResolver,
+) {
+    sources.entry(path);
+
+    for child in tree.children.iter() {
+        let child_path = resolver.canonicalize(root.join(&child.name)).ok().flatten();
+        let directive = IncludeDirective {
+            line: child.line,
+            column: 0,
+            spelling: format!("{}: {}", origin, child.name),
+            style: IncludeStyle::Quoted,
+        };
+        sources.add_include(path, &child.name, child_path.clone(), directive);
+
+        if let Some(child_path) = child_path {
+            add_tree(sources, child, &child_path, origin, root, resolver);
+        }
+    }
+}
+
+/// Load sources and headers with their direct includes from include traces.
+///
+/// Relative paths are relative to `root` if given, otherwise to the directory
+/// of the trace. Includes listed before any source in `-H` and `/showIncludes`
+/// traces belong to the source named like the trace file without its
+/// extension (i.e. the trace of `a.cpp` is in `a.cpp.log`).
+pub fn load_include_traces<I, E>(
+    paths: I,
+    format: TraceFormat,
+    root: Option<&Path>,
+    file_types: &FileTypes,
+    resolver: &PathResolver,
+) -> Result<Vec<SourceWithIncludes>, Error>
+where
+    I: Iterator<Item = Result<PathBuf, E>>,
+    E: Debug,
+{
+    let mut sources = RecordedSources::default();
+
+    for path in paths {
+        let path = path.map_err(|e| Error::Internal {
+            message: format!("{:?}", e),
+        })?;
+        let content = std::fs::read_to_string(&path).map_err(|source| Error::FileIOError {
+            source,
+            path: path.clone(),
+            message: "read",
+        })?;
+
+        let root = match root {
+            Some(root) => root.to_path_buf(),
+            None => path.parent().map(Path::to_path_buf).unwrap_or_default(),
+        };
+        let default_source = path.file_stem().map(|s| s.to_string_lossy());
+        let origin = path.display().to_string();
+
+        let trees = parse_trace(&content, format, default_source.as_deref(), |line| {
+            !line.contains(": ") && file_types.of(Path::new(line)) == FileType::Source
+        });
+
+        for tree in trees {
+            match resolver.canonicalize(root.join(&tree.name)).ok().flatten() {
+                Some(tree_path) => {
+                    add_tree(&mut sources, &tree, &tree_path, &origin, &root, resolver)
+                }
+                None => {
+                    warn!(target: "include-trace", "Source {:?} of {:?} not found", tree.name, path)
+                }
+            }
+        }
+    }
+
+    let sources = sources.into_sources();
+    info!(target: "include-trace", "Loaded includes of {} files", sources.len());
+
+    Ok(sources)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(tree: &IncludeTree) -> Vec<(usize, &str)> {
+        let mut result = Vec::new();
+        fn walk<'a>(tree: &'a IncludeTree, depth: usize, result: &mut Vec<(usize, &'a str)>) {
+            result.push((depth, tree.name.as_str()));
+            for child in tree.children.iter() {
+                walk(child, depth + 1, result);
+            }
+        }
+        walk(tree, 0, &mut result);
+        result
+    }
+
+    #[test]
+    fn test_dot_depth() {
+        let trees = parse_trace(
+            ". /x/a.h\n.. /x/b.h\n... /x/c.h\n. /x/d.h\nMultiple include guards may be useful for:\n/x/d.h\nb.cpp\n. /x/a.h\n",
+            TraceFormat::Auto,
+            Some("a.cpp"),
+            |line| line.ends_with(".cpp"),
+        );
+        assert_eq!(
+            trees.iter().map(names).collect::<Vec<_>>(),
+            vec![
+                vec![
+                    (0, "a.cpp"),
+                    (1, "/x/a.h"),
+                    (2, "/x/b.h"),
+                    (3, "/x/c.h"),
+                    (1, "/x/d.h")
+                ],
+                vec![(0, "b.cpp"), (1, "/x/a.h")],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_show_includes() {
+        let trees = parse_trace(
+            "a.cpp\nNote: including file: C:\\x\\a.h\nNote: including file:  C:\\x\\b h.h\nNote: including file: C:\\x\\c.h\r\n",
+            TraceFormat::Auto,
+            None,
+            |line| line.ends_with(".cpp"),
+        );
+        assert_eq!(
+            trees.iter().map(names).collect::<Vec<_>>(),
+            vec![vec![
+                (0, "a.cpp"),
+                (1, "C:\\x\\a.h"),
+                (2, "C:\\x\\b h.h"),
+                (1, "C:\\x\\c.h")
+            ]]
+        );
+    }
+
+    #[test]
+    fn test_line_markers() {
+        let tree = parse_line_markers(
+            r#"# 0 "a.cpp"
+# 0 "<built-in>"
+# 0 "<command-line>"
+# 1 "/usr/include/stdc-predef.h" 1 3 4
+# 0 "<command-line>" 2
+# 1 "a.cpp"
+
+int x;
+# 1 "b.h" 1
+# 1 "c\"d.h" 1
+int y;
+# 2 "b.h" 2
+# 4 "a.cpp" 2
+# 1 "e.h" 1
+# 5 "a.cpp" 2
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            names(&tree),
+            vec![(0, "a.cpp"), (1, "b.h"), (2, "c\"d.h"), (1, "e.h")]
+        );
+        assert_eq!(tree.children[0].line, 3);
+        assert_eq!(tree.children[0].children[0].line, 1);
+        assert_eq!(tree.children[1].line, 4);
+
+        // MSVC style markers without flags
+        let tree = parse_line_markers(
+            "#line 1 \"a.cpp\"\n#line 1 \"b.h\"\n#line 1 \"c.h\"\n#line 2 \"b.h\"\n#line 2 \"a.cpp\"\n#line 1 \"d.h\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            names(&tree),
+            vec![(0, "a.cpp"), (1, "b.h"), (2, "c.h"), (1, "d.h")]
+        );
+    }
+}
@@ -0,0 +1,314 @@
+use std::io::{BufRead, Lines};
+
+/// A single logical source line: physical lines joined by backslash
+/// continuations, with comments replaced by a space.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct LogicalLine {
+    /// 1-based number of the first physical line
+    pub line: usize,
+
+    /// Line content, without comments
+    pub text: String,
+}
+
+#[derive(Debug, PartialEq)]
+enum State {
+    Code,
+    BlockComment,
+    RawString { delimiter: String },
+}
+
+/// Identifiers that make a following `"` start a raw string literal
+const RAW_STRING_PREFIXES: &[&str] = &["R", "LR", "uR", "UR", "u8R"];
+
+/// Maximum length of a raw string delimiter according to the standard
+const MAX_RAW_DELIMITER: usize = 16;
+
+/// Streams logical lines out of a C-like source.
+///
+/// Block comments and raw strings spanning several physical lines are
+/// merged into the logical line they start on, so that their content is never
+/// mistaken for a preprocessor directive. String and character literals are
+/// kept as-is (an include like `"foo.h"` looks like a string), however comment
+/// markers within them are not interpreted. Raw string content is dropped.
+///
+/// ```
+/// # use include_graph::dependencies::lexer::LogicalLines;
+///
+/// let source = "#include \"a.h\" // comment\n/*\n#include \"b.h\"\n*/ #inc\\\nlude <c.h>\n";
+/// let lines = LogicalLines::new(source.as_bytes())
+///     .map(|l| l.unwrap().text)
+///     .collect::<Vec<_>>();
+///
+/// assert_eq!(lines, vec!["#include \"a.h\"  ", "  #include <c.h>"]);
+/// ```
+pub struct LogicalLines<R> {
+    lines: Lines<R>,
+    line_number: usize,
+    state: State,
+}
+
+impl<R: BufRead> LogicalLines<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            lines: reader.lines(),
+            line_number: 0,
+            state: State::Code,
+        }
+    }
+
+    /// Read the next physical line, joining backslash continuations.
+    ///
+    /// Returns the line number where the line starts and its content.
+    fn next_spliced(&mut self) -> Option<Result<(usize, String), std::io::Error>> {
+        let mut result: Option<(usize, String)> = None;
+
+        loop {
+            let line = match self.lines.next() {
+                Some(Ok(line)) => line,
+                Some(Err(e)) => return Some(Err(e)),
+                None => return result.map(Ok),
+            };
+            self.line_number += 1;
+
+            let (start, text) = result.get_or_insert_with(|| (self.line_number, String::new()));
+            match line.strip_suffix('\\') {
+                Some(spliced) => text.push_str(spliced),
+                None => {
+                    text.push_str(&line);
+                    return Some(Ok((*start, std::mem::take(text))));
+                }
+            }
+        }
+    }
+
+    /// Handles code outside comments and raw strings until the next
+    /// state change. Returns the text left to process.
+    fn lex_code<'a>(&mut self, text: &'a str, out: &mut String) -> &'a str {
+        let idx = match text.find(['/', '"', '\'']) {
+            Some(idx) => idx,
+            None => {
+                out.push_str(text);
+                return "";
+            }
+        };
+
+        let (before, rest) = text.split_at(idx);
+        out.push_str(before);
+
+        if rest.starts_with("//") {
+            out.push(' ');
+            return "";
+        }
+
+        if let Some(rest) = rest.strip_prefix("/*") {
+            self.state = State::BlockComment;
+            return rest;
+        }
+
+        if let Some(rest) = rest.strip_prefix('"') {
+            if let Some((delimiter, content)) = Self::raw_string_start(out, rest) {
+                out.push('"');
+                self.state = State::RawString {
+                    delimiter: delimiter.into(),
+                };
+                return content;
+            }
+            let end = Self::literal_end(rest, '"');
+            out.push('"');
+            out.push_str(&rest[..end]);
+            return &rest[end..];
+        }
+
+        if let Some(rest) = rest.strip_prefix('\'') {
+            out.push('\'');
+
+            // digit separators like `1'000'000`
+            if out[..out.len() - 1].ends_with(|c: char| c.is_ascii_alphanumeric()) {
+                return rest;
+            }
+
+            let end = Self::literal_end(rest, '\'');
+            out.push_str(&rest[..end]);
+            return &rest[end..];
+        }
+
+        // a lone `/`
+        out.push('/');
+        &rest[1..]
+    }
+
+    /// Figure out if a `"` (just stripped off `rest`) starts a raw string.
+    ///
+    /// Returns the raw string delimiter and the raw string content if so.
+    fn raw_string_start<'a>(out: &str, rest: &'a str) -> Option<(&'a str, &'a str)> {
+        let prefix_start = out
+            .rfind(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .map(|idx| idx + 1)
+            .unwrap_or(0);
+        if !RAW_STRING_PREFIXES.contains(&&out[prefix_start..]) {
+            return None;
+        }
+
+        let (delimiter, content) = rest.split_once('(')?;
+        if delimiter.len() > MAX_RAW_DELIMITER
+            || delimiter.contains(|c: char| c.is_whitespace() || c == ')' || c == '\\')
+        {
+            return None;
+        }
+
+        Some((delimiter, content))
+    }
+
+    /// Find where a string or character literal ends (after its closing quote).
+    ///
+    /// Unterminated literals end at the end of the line.
+    fn literal_end(text: &str, quote: char) -> usize {
+        let mut escaped = false;
+        for (idx, c) in text.char_indices() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                c if c == quote => return idx + 1,
+                _ => {}
+            }
+        }
+        text.len()
+    }
+
+    /// Lex a spliced physical line, appending the content to `out`.
+    fn lex_into(&mut self, line: &str, out: &mut String) {
+        let mut rest = line;
+
+        while !rest.is_empty() {
+            rest = match self.state {
+                State::Code => self.lex_code(rest, out),
+                State::BlockComment => match rest.find("*/") {
+                    Some(end) => {
+                        self.state = State::Code;
+                        out.push(' ');
+                        &rest[end + 2..]
+                    }
+                    None => "",
+                },
+                State::RawString { ref delimiter } => {
+                    let terminator = format!("){}\"", delimiter);
+                    match rest.find(&terminator) {
+                        Some(end) => {
+                            self.state = State::Code;
+                            out.push('"');
+                            &rest[end + terminator.len()..]
+                        }
+                        None => "",
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for LogicalLines<R> {
+    type Item = Result<LogicalLine, std::io::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut result: Option<LogicalLine> = None;
+
+        loop {
+            let (line, text) = match self.next_spliced() {
+                Some(Ok(value)) => value,
+                Some(Err(e)) => return Some(Err(e)),
+                // unterminated comment or raw string at the end of the file
+                None => return result.map(Ok),
+            };
+
+            let logical = result.get_or_insert_with(|| LogicalLine {
+                line,
+                text: String::new(),
+            });
+            self.lex_into(&text, &mut logical.text);
+
+            if self.state == State::Code {
+                return result.map(Ok);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lex(source: &str) -> Vec<(usize, String)> {
+        LogicalLines::new(source.as_bytes())
+            .map(|l| l.unwrap())
+            .map(|l| (l.line, l.text))
+            .collect()
+    }
+
+    #[test]
+    fn test_plain_lines() {
+        assert_eq!(
+            lex("a\nb\n\nc"),
+            vec![
+                (1, "a".into()),
+                (2, "b".into()),
+                (3, "".into()),
+                (4, "c".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_comments() {
+        assert_eq!(
+            lex("a // b\n/* c */ d /* e\nf */ g\n/*\n#include <x>\n*/\nh"),
+            vec![
+                (1, "a  ".into()),
+                (2, "  d   g".into()),
+                (4, " ".into()),
+                (7, "h".into())
+            ]
+        );
+
+        // line comments are continued by a backslash as well
+        assert_eq!(
+            lex("a // b \\\n#include <x>\nc"),
+            vec![(1, "a  ".into()), (3, "c".into())]
+        );
+    }
+
+    #[test]
+    fn test_continuations() {
+        assert_eq!(
+            lex("#incl\\\nude \\\n\"a.h\"\nb"),
+            vec![(1, "#include \"a.h\"".into()), (4, "b".into())]
+        );
+    }
+
+    #[test]
+    fn test_literals() {
+        assert_eq!(
+            lex("s = \"/* not a comment */\"; // comment\nc = '\"'; d = 1'000; // x"),
+            vec![
+                (1, "s = \"/* not a comment */\";  ".into()),
+                (2, "c = '\"'; d = 1'000;  ".into())
+            ]
+        );
+
+        assert_eq!(
+            lex("s = \"escaped \\\" // still string\"; /**/x"),
+            vec![(1, "s = \"escaped \\\" // still string\";  x".into())]
+        );
+    }
+
+    #[test]
+    fn test_raw_strings() {
+        assert_eq!(
+            lex("s = R\"xy(\n#include \"a.h\"\n)\" // )xy\";\n#include \"b.h\""),
+            vec![(1, "s = R\"\";".into()), (4, "#include \"b.h\"".into())]
+        );
+
+        // not a raw string prefix
+        assert_eq!(lex("FOR\"(\" // x"), vec![(1, "FOR\"(\"  ".into())]);
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use tracing::{info, warn};
+
+use super::canonicalize::PathResolver;
+use super::cparse::{FileTypes, SourceWithIncludes};
+use super::depfile::{sources_from_dependencies, Dependencies};
+use super::error::Error;
+
+const SIGNATURE: &[u8] = b"# ninjadeps\n";
+
+/// Set on the size of records that contain dependencies (instead of a path)
+const DEPS_RECORD_FLAG: u32 = 0x8000_0000;
+
+/// Dependencies of a build output, as recorded in a `.ninja_deps` log
+#[derive(Debug, PartialEq)]
+pub struct NinjaDeps {
+    pub output: String,
+    pub inputs: Vec<String>,
+}
+
+/// Parse the contents of a ninja deps log (versions 3 and 4).
+///
+/// Like ninja itself, a truncated or corrupted tail of the log (e.g. from an
+/// interrupted build) is ignored.
+pub fn parse_ninja_deps(data: &[u8]) -> Result<Vec<NinjaDeps>, Error> {
+    let invalid = |message: String| Error::Internal { message };
+
+    let data = data
+        .strip_prefix(SIGNATURE)
+        .ok_or_else(|| invalid("Not a ninja deps log".into()))?;
+    if data.len() < 4 {
+        return Err(invalid("Missing ninja deps log version".into()));
+    }
+    let version = u32::from_le_bytes(data[..4].try_into().unwrap());
+
+    // ids of the output node and its mtime precede the inputs
+    let header_ids = match version {
+        3 => 2,
+        4 => 3,
+        _ => {
+            return Err(invalid(format!(
+                "Unsupported ninja deps log version {}",
+                version
+            )))
+        }
+    };
+
+    let mut ids = data[4..]
+        .chunks_exact(4)
+        .map(|c| u32::from_le_bytes(c.try_into().unwrap()));
+
+    let mut paths: Vec<String> = Vec::new();
+    let mut deps = BTreeMap::new();
+
+    while let Some(header) = ids.next() {
+        let size = (header & !DEPS_RECORD_FLAG) as usize;
+        if !size.is_multiple_of(4) || size < 4 {
+            warn!(target: "ninja-deps", "Invalid record size {}, ignoring the rest of the log", size);
+            break;
+        }
+        let record = ids.by_ref().take(size / 4).collect::<Vec<_>>();
+        if record.len() != size / 4 {
+            warn!(target: "ninja-deps", "Ignoring truncated record at the end of the log");
+            break;
+        }
+
+        if header & DEPS_RECORD_FLAG == 0 {
+            // path, NUL-padded to 4 bytes and followed by a checksum of the id
+            let (checksum, path) = record.split_last().unwrap();
+            if *checksum != !(paths.len() as u32) {
+                warn!(target: "ninja-deps", "Invalid path checksum, ignoring the rest of the log");
+                break;
+            }
+            let bytes = path
+                .iter()
+                .flat_map(|v| v.to_le_bytes())
+                .collect::<Vec<_>>();
+            let end = bytes.iter().rposition(|b| *b != 0).map_or(0, |p| p + 1);
+            paths.push(String::from_utf8_lossy(&bytes[..end]).into_owned());
+        } else {
+            let is_known = |id: &u32| (*id as usize) < paths.len();
+            if record.len() < header_ids
+                || !is_known(&record[0])
+                || !record[header_ids..].iter().all(is_known)
+            {
+                warn!(target: "ninja-deps", "Invalid deps record, ignoring the rest of the log");
+                break;
+            }
+            // later records of the same output replace earlier ones
+            deps.insert(record[0], record[header_ids..].to_vec());
+        }
+    }
+
+    Ok(deps
+        .into_iter()
+        .map(|(output, inputs)| NinjaDeps {
+            output: paths[output as usize].clone(),
+            inputs: inputs
+                .into_iter()
+                .map(|id| paths[id as usize].clone())
+                .collect(),
+        })
+        .collect())
+}
+
+/// Load sources and their includes from the `.ninja_deps` log in `build_dir`.
+///
+/// Only outputs whose inputs contain a source file are used, which is the case
+/// for `deps = gcc`. Relative paths are relative to `build_dir`.
+pub fn load_ninja_deps(
+    build_dir: &Path,
+    file_types: &FileTypes,
+    resolver: &PathResolver,
+) -> Result<Vec<SourceWithIncludes>, Error> {
+    let path = build_dir.join(".ninja_deps");
+    let data = std::fs::read(&path).map_err(|source| Error::FileIOError {
+        source,
+        path: path.clone(),
+        message: "read",
+    })?;
+
+    let deps = parse_ninja_deps(&data)?;
+    info!(target: "ninja-deps", "Loaded dependencies of {} outputs from {:?}", deps.len(), path);
+
+    Ok(sources_from_dependencies(
+        deps.into_iter().map(|d| Dependencies {
+            origin: format!("ninja_deps {}", d.output),
+            root: build_dir.to_path_buf(),
+            paths: d.inputs,
+        }),
+        file_types,
+        resolver,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path_record(data: &mut Vec<u8>, id: u32, path: &str) {
+        let mut bytes = path.as_bytes().to_vec();
+        bytes.resize(path.len().div_ceil(4) * 4, 0);
+        data.extend(((bytes.len() + 4) as u32).to_le_bytes());
+        data.extend(bytes);
+        data.extend((!id).to_le_bytes());
+    }
+
+    fn deps_record(data: &mut Vec<u8>, mtime_ids: usize, ids: &[u32]) {
+        let size = ((ids.len() + mtime_ids) * 4) as u32;
+        data.extend((size | DEPS_RECORD_FLAG).to_le_bytes());
+        data.extend(ids[0].to_le_bytes());
+        for _ in 0..mtime_ids {
+            data.extend(7u32.to_le_bytes());
+        }
+        for id in &ids[1..] {
+            data.extend(id.to_le_bytes());
+        }
+    }
+
+    #[test]
+    fn test_parse_ninja_deps() {
+        for (version, mtime_ids) in [(4u32, 2), (3, 1)] {
+            let mut data = SIGNATURE.to_vec();
+            data.extend(version.to_le_bytes());
+            path_record(&mut data, 0, "obj/a.o");
+            path_record(&mut data, 1, "../src/a.cpp");
+            path_record(&mut data, 2, "../src/a.h");
+            deps_record(&mut data, mtime_ids, &[0, 1]);
+            deps_record(&mut data, mtime_ids, &[0, 1, 2]);
+            // truncated record
+            data.extend(100u32.to_le_bytes());
+
+            assert_eq!(
+                parse_ninja_deps(&data).unwrap(),
+                vec![NinjaDeps {
+                    output: "obj/a.o".into(),
+                    inputs: vec!["../src/a.cpp".into(), "../src/a.h".into()],
+                }]
+            );
+        }
+
+        assert!(parse_ninja_deps(b"# ninjadeps\n\x02\x00\x00\x00").is_err());
+        assert!(parse_ninja_deps(b"something else").is_err());
+    }
+}
@@ -0,0 +1,786 @@
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+/// How `#if`/`#elif` conditions that cannot be evaluated (e.g. function-like
+/// macro invocations or compiler built-ins) should be handled.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UnknownConditionPolicy {
+    /// The condition is considered true
+    Taken,
+
+    /// The condition is considered false
+    NotTaken,
+
+    /// The branch is considered taken, however so are any alternatives
+    /// (`#elif`/`#else`) following it.
+    #[default]
+    Both,
+}
+
+/// A macro definition, as far as include processing cares about it
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Macro {
+    /// `#define NAME value`
+    Object(String),
+
+    /// `#define NAME(args) body`. Only existence is tracked, invocations
+    /// cannot be evaluated.
+    Function,
+}
+
+/// A `-D` or `-U` flag (or equivalent config instruction), applied in order.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Hash, Serialize, Deserialize)]
+pub enum DefineFlag {
+    Define { name: String, value: String },
+    Undefine(String),
+}
+
+impl DefineFlag {
+    /// Create a define from a `-D` argument like `NAME`, `NAME=value` or `NAME(x)=x`.
+    ///
+    /// ```
+    /// # use include_graph::dependencies::preprocessor::DefineFlag;
+    ///
+    /// assert_eq!(
+    ///     DefineFlag::define("FOO"),
+    ///     DefineFlag::Define { name: "FOO".into(), value: "1".into() }
+    /// );
+    /// assert_eq!(
+    ///     DefineFlag::define("FOO=a=b"),
+    ///     DefineFlag::Define { name: "FOO".into(), value: "a=b".into() }
+    /// );
+    /// ```
+    pub fn define(arg: &str) -> Self {
+        let (name, value) = arg.split_once('=').unwrap_or((arg, "1"));
+        DefineFlag::Define {
+            name: name.into(),
+            value: value.into(),
+        }
+    }
+}
+
+/// A set of currently defined macros
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Defines {
+    macros: HashMap<String, Macro>,
+}
+
+impl Defines {
+    /// Define `name` as `value`. Names of the form `NAME(args)` are function-like.
+    pub fn define(&mut self, name: &str, value: &str) {
+        match name.split_once('(') {
+            Some((name, _)) => self.macros.insert(name.into(), Macro::Function),
+            None => self
+                .macros
+                .insert(name.into(), Macro::Object(value.trim().into())),
+        };
+    }
+
+    pub fn undefine(&mut self, name: &str) {
+        self.macros.remove(name);
+    }
+
+    pub fn apply(&mut self, flag: &DefineFlag) {
+        match flag {
+            DefineFlag::Define { name, value } => self.define(name, value),
+            DefineFlag::Undefine(name) => self.undefine(name),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Macro> {
+        self.macros.get(name)
+    }
+
+    /// Add all macros of `other`, replacing existing ones with the same name
+    pub fn extend(&mut self, other: &Defines) {
+        self.macros
+            .extend(other.macros.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+
+    /// Expand the operand of a computed include (`#include NAME`), where `NAME` is
+    /// an object-like macro (possibly through other macros) for `<file>` or `"file"`.
+    ///
+    /// ```
+    /// # use include_graph::dependencies::preprocessor::Defines;
+    ///
+    /// let mut defines = Defines::default();
+    /// defines.define("PLATFORM_HEADER", "<platform/foo.h>");
+    /// defines.define("CONFIG", "PLATFORM_HEADER");
+    ///
+    /// assert_eq!(defines.expand_include("CONFIG"), Some("<platform/foo.h>".into()));
+    /// assert_eq!(defines.expand_include("\"a.h\""), Some("\"a.h\"".into()));
+    /// assert_eq!(defines.expand_include("UNKNOWN"), None);
+    /// ```
+    pub fn expand_include(&self, operand: &str) -> Option<String> {
+        let mut operand = operand.trim();
+        for _ in 0..MAX_EXPANSION_DEPTH {
+            if operand.starts_with(['<', '"']) {
+                return Some(operand.into());
+            }
+            operand = match self.get(operand)? {
+                Macro::Object(value) => value.trim(),
+                Macro::Function => return None,
+            };
+        }
+        None
+    }
+
+    pub fn is_defined(&self, name: &str) -> bool {
+        self.macros.contains_key(name) || BUILTIN_DEFINES.contains(&name)
+    }
+
+    /// Handles the content following a `#define` directive.
+    ///
+    /// A macro is function-like only if the `(` immediately follows its name.
+    fn define_directive(&mut self, rest: &str) {
+        let rest = rest.trim_start();
+        let name_len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        let (name, body) = rest.split_at(name_len);
+        if name.is_empty() {
+            return;
+        }
+        if body.starts_with('(') {
+            self.macros.insert(name.into(), Macro::Function);
+        } else {
+            self.macros
+                .insert(name.into(), Macro::Object(body.trim().into()));
+        }
+    }
+}
+
+// Hashed in name order, so the hash is stable across runs given a stable hasher.
+impl Hash for Defines {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let mut macros = self.macros.iter().collect::<Vec<_>>();
+        macros.sort_by_key(|(name, _)| *name);
+        macros.hash(state);
+    }
+}
+
+/// Starting state for preprocessing a single file.
+#[derive(Debug, Default, Clone, PartialEq, Hash)]
+pub struct PreprocessorConfig {
+    pub defines: Defines,
+    pub unknown_conditions: UnknownConditionPolicy,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Identifier(String),
+    Punctuator(&'static str),
+}
+
+/// Punctuators valid in `#if` expressions. Longer ones first so that
+/// matching by prefix finds the longest one.
+const PUNCTUATORS: &[&str] = &[
+    "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "(", ")", "!", "~", "*", "/", "%", "+", "-",
+    "<", ">", "&", "^", "|", "?", ":",
+];
+
+/// Names that `defined` reports as defined even though they are
+/// not macros (the `__has_include` checks are evaluated by the include extraction)
+const BUILTIN_DEFINES: &[&str] = &["__has_include", "__has_include_next"];
+
+/// Maximum nesting of macro expansion before giving up
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+fn parse_number(text: &str) -> Option<i64> {
+    let text = text.trim_end_matches(['u', 'U', 'l', 'L']);
+    if let Some(hex) = text.strip_prefix("0x").or(text.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = text.strip_prefix("0b").or(text.strip_prefix("0B")) {
+        i64::from_str_radix(bin, 2).ok()
+    } else if text.len() > 1 && text.starts_with('0') {
+        i64::from_str_radix(&text[1..], 8).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+fn parse_char_literal(text: &str) -> Option<i64> {
+    let mut chars = text.chars();
+    let value = match chars.next()? {
+        '\\' => match chars.next()? {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '0' => '\0',
+            c @ ('\\' | '\'' | '"') => c,
+            _ => return None,
+        },
+        c => c,
+    };
+    if chars.next().is_some() {
+        return None;
+    }
+    Some(value as i64)
+}
+
+/// Split an expression into tokens. Returns None for anything that is not
+/// understood.
+fn tokenize(expression: &str) -> Option<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut rest = expression;
+
+    loop {
+        rest = rest.trim_start();
+        let c = match rest.chars().next() {
+            Some(c) => c,
+            None => return Some(tokens),
+        };
+
+        if c.is_ascii_digit() {
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(rest.len());
+            tokens.push(Token::Number(parse_number(&rest[..len])?));
+            rest = &rest[len..];
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Identifier(rest[..len].into()));
+            rest = &rest[len..];
+        } else if c == '\'' {
+            let end = rest[1..].find('\'')? + 1;
+            tokens.push(Token::Number(parse_char_literal(&rest[1..end])?));
+            rest = &rest[end + 1..];
+        } else {
+            let p = PUNCTUATORS.iter().find(|p| rest.starts_with(*p))?;
+            tokens.push(Token::Punctuator(p));
+            rest = &rest[p.len()..];
+        }
+    }
+}
+
+/// Replace object-like macros with their values.
+///
+/// Operands of `defined` are left untouched. Macros currently being
+/// expanded (in `disabled`) are not expanded again, so self-referencing
+/// macros terminate.
+fn expand(
+    tokens: Vec<Token>,
+    defines: &Defines,
+    disabled: &mut HashSet<String>,
+) -> Option<Vec<Token>> {
+    if disabled.len() > MAX_EXPANSION_DEPTH {
+        return None;
+    }
+
+    let mut result = Vec::new();
+    let mut tokens = tokens.into_iter().peekable();
+
+    while let Some(token) = tokens.next() {
+        let name = match token {
+            Token::Identifier(ref name) => name.clone(),
+            other => {
+                result.push(other);
+                continue;
+            }
+        };
+
+        if name == "defined" {
+            result.push(token);
+            // copy over either `NAME` or `( NAME )` unexpanded
+            if tokens.peek() == Some(&Token::Punctuator("(")) {
+                result.extend(tokens.by_ref().take(3));
+            } else {
+                result.extend(tokens.next());
+            }
+            continue;
+        }
+
+        match defines.get(&name) {
+            Some(Macro::Object(value)) if !disabled.contains(&name) => {
+                disabled.insert(name.clone());
+                let expanded = expand(tokenize(value)?, defines, disabled);
+                disabled.remove(&name);
+                result.extend(expanded?);
+            }
+            _ => result.push(token),
+        }
+    }
+
+    Some(result)
+}
+
+/// Recursive-descent evaluation of an already macro-expanded expression.
+struct Evaluator<'a> {
+    tokens: &'a [Token],
+    position: usize,
+    defines: &'a Defines,
+}
+
+impl<'a> Evaluator<'a> {
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<&'a Token> {
+        let token = self.tokens.get(self.position);
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, punctuator: &str) -> Option<()> {
+        match self.next()? {
+            Token::Punctuator(p) if *p == punctuator => Some(()),
+            _ => None,
+        }
+    }
+
+    fn binary_precedence(token: &Token) -> Option<u8> {
+        let p = match token {
+            Token::Punctuator(p) => *p,
+            _ => return None,
+        };
+        Some(match p {
+            "||" => 1,
+            "&&" => 2,
+            "|" => 3,
+            "^" => 4,
+            "&" => 5,
+            "==" | "!=" => 6,
+            "<" | ">" | "<=" | ">=" => 7,
+            "<<" | ">>" => 8,
+            "+" | "-" => 9,
+            "*" | "/" | "%" => 10,
+            _ => return None,
+        })
+    }
+
+    fn conditional(&mut self) -> Option<i64> {
+        let condition = self.binary(1)?;
+        if self.peek() != Some(&Token::Punctuator("?")) {
+            return Some(condition);
+        }
+        self.next();
+        let if_true = self.conditional()?;
+        self.expect(":")?;
+        let if_false = self.conditional()?;
+        Some(if condition != 0 { if_true } else { if_false })
+    }
+
+    fn binary(&mut self, min_precedence: u8) -> Option<i64> {
+        let mut left = self.unary()?;
+
+        while let Some(token) = self.peek() {
+            let precedence = match Self::binary_precedence(token) {
+                Some(p) if p >= min_precedence => p,
+                _ => break,
+            };
+            self.next();
+            let right = self.binary(precedence + 1)?;
+
+            let op = match token {
+                Token::Punctuator(p) => *p,
+                _ => unreachable!("precedence only exists for punctuators"),
+            };
+            left = match op {
+                "||" => ((left != 0) || (right != 0)) as i64,
+                "&&" => ((left != 0) && (right != 0)) as i64,
+                "|" => left | right,
+                "^" => left ^ right,
+                "&" => left & right,
+                "==" => (left == right) as i64,
+                "!=" => (left != right) as i64,
+                "<" => (left < right) as i64,
+                ">" => (left > right) as i64,
+                "<=" => (left <= right) as i64,
+                ">=" => (left >= right) as i64,
+                "<<" => left.wrapping_shl(right as u32),
+                ">>" => left.wrapping_shr(right as u32),
+                "+" => left.wrapping_add(right),
+                "-" => left.wrapping_sub(right),
+                "*" => left.wrapping_mul(right),
+                "/" => left.checked_div(right)?,
+                "%" => left.checked_rem(right)?,
+                _ => unreachable!("all operators with a precedence are handled"),
+            };
+        }
+
+        Some(left)
+    }
+
+    fn unary(&mut self) -> Option<i64> {
+        match self.next()? {
+            Token::Punctuator("!") => Some((self.unary()? == 0) as i64),
+            Token::Punctuator("~") => Some(!self.unary()?),
+            Token::Punctuator("-") => Some(self.unary()?.wrapping_neg()),
+            Token::Punctuator("+") => self.unary(),
+            Token::Punctuator("(") => {
+                let value = self.conditional()?;
+                self.expect(")")?;
+                Some(value)
+            }
+            Token::Number(n) => Some(*n),
+            Token::Identifier(name) if name == "defined" => {
+                let parenthesized = self.peek() == Some(&Token::Punctuator("("));
+                if parenthesized {
+                    self.next();
+                }
+                let value = match self.next()? {
+                    Token::Identifier(name) => self.defines.is_defined(name) as i64,
+                    _ => return None,
+                };
+                if parenthesized {
+                    self.expect(")")?;
+                }
+                Some(value)
+            }
+            Token::Identifier(name) => {
+                // Function-like invocations (including things like `__has_feature(x)`)
+                // cannot be evaluated.
+                if self.peek() == Some(&Token::Punctuator("(")) {
+                    return None;
+                }
+                // Identifiers left after macro expansion evaluate to 0, except
+                // for the C++ boolean literals.
+                Some((name == "true") as i64)
+            }
+            Token::Punctuator(_) => None,
+        }
+    }
+}
+
+/// Evaluate a `#if` expression given a set of defines.
+///
+/// Returns None if the expression cannot be evaluated.
+///
+/// ```
+/// # use include_graph::dependencies::preprocessor::{evaluate, Defines};
+///
+/// let mut defines = Defines::default();
+/// defines.define("FOO", "2");
+///
+/// assert_eq!(evaluate("FOO > 1 && !defined(BAR)", &defines), Some(1));
+/// assert_eq!(evaluate("BAR", &defines), Some(0));
+/// assert_eq!(evaluate("__has_feature(x)", &defines), None);
+/// ```
+pub fn evaluate(expression: &str, defines: &Defines) -> Option<i64> {
+    let tokens = expand(tokenize(expression)?, defines, &mut HashSet::new())?;
+    let mut evaluator = Evaluator {
+        tokens: &tokens,
+        position: 0,
+        defines,
+    };
+    let value = evaluator.conditional()?;
+
+    // Everything must be consumed for a valid expression
+    match evaluator.position == tokens.len() {
+        true => Some(value),
+        false => None,
+    }
+}
+
+/// Whether a conditional branch is taken. Ordered so that `max` combines
+/// knowledge of branches within the same `#if` group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Certainty {
+    No,
+    Maybe,
+    Yes,
+}
+
+#[derive(Debug)]
+struct ConditionalFrame {
+    /// Was the enclosing code active
+    parent_active: bool,
+
+    /// Was any branch of this group taken so far
+    taken: Certainty,
+
+    /// Is the current branch active
+    active: bool,
+}
+
+/// Tracks conditional compilation state within a single file.
+#[derive(Debug)]
+pub struct Preprocessor {
+    defines: Defines,
+    unknown_conditions: UnknownConditionPolicy,
+    frames: Vec<ConditionalFrame>,
+}
+
+impl Preprocessor {
+    pub fn new(config: &PreprocessorConfig) -> Self {
+        Self {
+            defines: config.defines.clone(),
+            unknown_conditions: config.unknown_conditions,
+            frames: Vec::new(),
+        }
+    }
+
+    /// Should code at the current position be processed
+    pub fn is_active(&self) -> bool {
+        self.frames.last().map(|f| f.active).unwrap_or(true)
+    }
+
+    /// Would an `#elif` at the current position be evaluated, i.e. is the
+    /// surrounding code active and no earlier branch of the group certainly taken
+    pub fn is_alternative_reachable(&self) -> bool {
+        self.frames
+            .last()
+            .map(|f| f.parent_active && f.taken != Certainty::Yes)
+            .unwrap_or(true)
+    }
+
+    pub fn defines(&self) -> &Defines {
+        &self.defines
+    }
+
+    /// Add all macros of `defines`, replacing existing ones with the same name
+    pub fn define_all(&mut self, defines: &Defines) {
+        self.defines.extend(defines);
+    }
+
+    fn condition(&self, expression: &str) -> Certainty {
+        match evaluate(expression, &self.defines) {
+            Some(0) => Certainty::No,
+            Some(_) => Certainty::Yes,
+            None => {
+                debug!("Cannot evaluate condition: {:?}", expression);
+                match self.unknown_conditions {
+                    UnknownConditionPolicy::Taken => Certainty::Yes,
+                    UnknownConditionPolicy::NotTaken => Certainty::No,
+                    UnknownConditionPolicy::Both => Certainty::Maybe,
+                }
+            }
+        }
+    }
+
+    fn is_defined(&self, rest: &str) -> Certainty {
+        match self
+            .defines
+            .is_defined(rest.split_whitespace().next().unwrap_or_default())
+        {
+            true => Certainty::Yes,
+            false => Certainty::No,
+        }
+    }
+
+    fn push(&mut self, condition: impl FnOnce(&Self) -> Certainty) {
+        let parent_active = self.is_active();
+        let taken = match parent_active {
+            true => condition(self),
+            false => Certainty::No,
+        };
+        self.frames.push(ConditionalFrame {
+            parent_active,
+            taken,
+            active: taken != Certainty::No,
+        });
+    }
+
+    fn alternative(&mut self, directive: &str, condition: impl FnOnce(&Self) -> Certainty) {
+        let (parent_active, taken) = match self.frames.last() {
+            Some(frame) => (frame.parent_active, frame.taken),
+            None => {
+                warn!("#{} without matching #if", directive);
+                return;
+            }
+        };
+
+        let (active, taken) = match parent_active && taken != Certainty::Yes {
+            true => {
+                let current = condition(self);
+                (current != Certainty::No, taken.max(current))
+            }
+            false => (false, taken),
+        };
+
+        let frame = self.frames.last_mut().expect("checked above");
+        frame.active = active;
+        frame.taken = taken;
+    }
+
+    /// Process a preprocessor directive (`name` is the directive name without `#`).
+    ///
+    /// Returns false if the directive does not affect preprocessor state.
+    pub fn handle_directive(&mut self, name: &str, rest: &str) -> bool {
+        match name {
+            "if" => self.push(|s| s.condition(rest)),
+            "ifdef" => self.push(|s| s.is_defined(rest)),
+            "ifndef" => self.push(|s| match s.is_defined(rest) {
+                Certainty::Yes => Certainty::No,
+                _ => Certainty::Yes,
+            }),
+            "elif" => self.alternative(name, |s| s.condition(rest)),
+            "elifdef" => self.alternative(name, |s| s.is_defined(rest)),
+            "elifndef" => self.alternative(name, |s| match s.is_defined(rest) {
+                Certainty::Yes => Certainty::No,
+                _ => Certainty::Yes,
+            }),
+            "else" => self.alternative(name, |_| Certainty::Yes),
+            "endif" => {
+                if self.frames.pop().is_none() {
+                    warn!("#endif without matching #if");
+                }
+            }
+            "define" if self.is_active() => self.defines.define_directive(rest),
+            "undef" if self.is_active() => self
+                .defines
+                .undefine(rest.split_whitespace().next().unwrap_or_default()),
+            "define" | "undef" => {}
+            _ => return false,
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn defines(items: &[(&str, &str)]) -> Defines {
+        let mut d = Defines::default();
+        for (name, value) in items {
+            d.define(name, value);
+        }
+        d
+    }
+
+    /// Runs the given directives, returning what lines were active
+    fn active_lines(config: &PreprocessorConfig, lines: &[&str]) -> Vec<usize> {
+        let mut p = Preprocessor::new(config);
+        let mut active = Vec::new();
+        for (idx, line) in lines.iter().enumerate() {
+            if let Some(directive) = line.strip_prefix('#') {
+                let (name, rest) = directive.split_once(' ').unwrap_or((directive, ""));
+                if p.handle_directive(name, rest) {
+                    continue;
+                }
+            }
+            if p.is_active() {
+                active.push(idx);
+            }
+        }
+        active
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let d = defines(&[("A", "1"), ("B", "A + 1"), ("SELF", "SELF"), ("F(x)", "x")]);
+
+        assert_eq!(evaluate("1", &d), Some(1));
+        assert_eq!(evaluate("0x10 + 010 + 1u", &d), Some(25));
+        // expansion is textual: `A + 1 * 3`
+        assert_eq!(evaluate("B * 3", &d), Some(4));
+        assert_eq!(evaluate("(1 + 2) * 3 - 4 / 2", &d), Some(7));
+        assert_eq!(evaluate("1 << 4 | 1", &d), Some(17));
+        assert_eq!(evaluate("A ? 5 : 6", &d), Some(5));
+        assert_eq!(evaluate("UNDEFINED ? 5 : 6", &d), Some(6));
+        assert_eq!(evaluate("defined A && defined(B)", &d), Some(1));
+        assert_eq!(evaluate("defined(C) || !A", &d), Some(0));
+        assert_eq!(evaluate("'a' == 97", &d), Some(1));
+        assert_eq!(evaluate("-1 < 0", &d), Some(1));
+        assert_eq!(evaluate("SELF", &d), Some(0));
+        assert_eq!(evaluate("defined(F)", &d), Some(1));
+
+        assert_eq!(evaluate("F(1)", &d), None);
+        assert_eq!(evaluate("1 / 0", &d), None);
+        assert_eq!(evaluate("(1", &d), None);
+        assert_eq!(evaluate("1 2", &d), None);
+        assert_eq!(evaluate("\"str\"", &d), None);
+    }
+
+    #[test]
+    fn test_conditionals() {
+        let config = PreprocessorConfig {
+            defines: defines(&[("X", "1")]),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            active_lines(
+                &config,
+                &[
+                    "a",
+                    "#ifdef X",
+                    "b",
+                    "#else",
+                    "c",
+                    "#endif",
+                    "#ifndef X",
+                    "d",
+                    "#elif 1",
+                    "e",
+                    "#else",
+                    "f",
+                    "#endif",
+                    "#if 0",
+                    "#if 1",
+                    "g",
+                    "#endif",
+                    "#else",
+                    "h",
+                    "#endif",
+                ]
+            ),
+            vec![0, 2, 9, 18]
+        );
+    }
+
+    #[test]
+    fn test_defines_in_file() {
+        assert_eq!(
+            active_lines(
+                &PreprocessorConfig::default(),
+                &[
+                    "#define Y 2",
+                    "#if Y == 2",
+                    "a",
+                    "#endif",
+                    "#undef Y",
+                    "#ifdef Y",
+                    "b",
+                    "#endif",
+                    "#if 0",
+                    "#define Z",
+                    "#endif",
+                    "#ifdef Z",
+                    "c",
+                    "#endif",
+                ]
+            ),
+            vec![2]
+        );
+    }
+
+    #[test]
+    fn test_unknown_conditions() {
+        let lines = [
+            "#if __has_feature(x)",
+            "a",
+            "#elif 1",
+            "b",
+            "#else",
+            "c",
+            "#endif",
+        ];
+
+        let config = |unknown_conditions| PreprocessorConfig {
+            unknown_conditions,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            active_lines(&config(UnknownConditionPolicy::Taken), &lines),
+            vec![1]
+        );
+        assert_eq!(
+            active_lines(&config(UnknownConditionPolicy::NotTaken), &lines),
+            vec![3]
+        );
+        assert_eq!(
+            active_lines(&config(UnknownConditionPolicy::Both), &lines),
+            vec![1, 3]
+        );
+    }
+}
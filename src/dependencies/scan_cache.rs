@@ -0,0 +1,330 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::RwLock;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use super::canonicalize::PathResolver;
+use super::cparse::{extract_includes, SourceWithIncludes};
+use super::error::Error;
+use super::include_path::IncludePath;
+use super::preprocessor::PreprocessorConfig;
+
+/// Cache files written by a different version are discarded, as the
+/// scan results may differ.
+const CACHE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// 64-bit FNV-1a hasher.
+///
+/// Unlike the std `DefaultHasher`, results are stable across runs, so
+/// they can be persisted.
+struct Fnv64(u64);
+
+impl Default for Fnv64 {
+    fn default() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for Fnv64 {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= u64::from(*b);
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+fn stable_hash<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = Fnv64::default();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Scan result of a single file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    /// Hash of the include path, preprocessor configuration and symlink mode
+    /// used for the scan
+    fingerprint: u64,
+
+    mtime: Option<SystemTime>,
+    size: u64,
+
+    /// Hash of the file content, used if only the mtime changed
+    content_hash: u64,
+
+    source: SourceWithIncludes,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CacheFile {
+    version: String,
+    entries: Vec<CacheEntry>,
+}
+
+/// Persistent cache of [`extract_includes`] results.
+///
+/// Entries are keyed by path and fingerprint of the search configuration
+/// (including the [`SymlinkMode`](super::canonicalize::SymlinkMode)) and
+/// are valid while the file keeps its size and modification time (or content).
+///
+/// Files that a scan result depends on (like headers that define macros for
+/// computed includes, or headers added to include directories) are not
+/// tracked. Delete the cache file if those change.
+///
+/// Only entries used by the current run are saved, so entries of files or
+/// configurations that are no longer scanned do not accumulate.
+///
+/// A default cache does not cache anything.
+#[derive(Debug, Default)]
+pub struct ScanCache {
+    /// Where the cache is stored, `None` if caching is disabled
+    path: Option<PathBuf>,
+
+    entries: RwLock<HashMap<(PathBuf, u64), CacheEntry>>,
+
+    /// Keys of `entries` that were looked up or added since loading
+    used: RwLock<HashSet<(PathBuf, u64)>>,
+
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+impl ScanCache {
+    /// Load a cache from `path`.
+    ///
+    /// A missing or unreadable cache file results in an empty cache.
+    pub fn load(path: &Path) -> Self {
+        let entries = match fs::read_to_string(path) {
+            Ok(data) => match serde_json::from_str::<CacheFile>(&data) {
+                Ok(file) if file.version == CACHE_VERSION => file.entries,
+                Ok(file) => {
+                    info!(target: "scan-cache", "Discarding cache of version {}", file.version);
+                    Vec::default()
+                }
+                Err(e) => {
+                    warn!(target: "scan-cache", "Ignoring invalid cache {:?}: {}", path, e);
+                    Vec::default()
+                }
+            },
+            Err(e) => {
+                debug!(target: "scan-cache", "No cache loaded from {:?}: {}", path, e);
+                Vec::default()
+            }
+        };
+
+        info!(target: "scan-cache", "Loaded {} entries from {:?}", entries.len(), path);
+
+        Self {
+            path: Some(path.into()),
+            entries: RwLock::new(
+                entries
+                    .into_iter()
+                    .map(|e| ((e.source.path.clone(), e.fingerprint), e))
+                    .collect(),
+            ),
+            ..Default::default()
+        }
+    }
+
+    /// Same as [`extract_includes`], however returns the cached result if
+    /// the file did not change since it was last scanned with the same
+    /// include path and preprocessor configuration.
+    pub fn extract_includes(
+        &self,
+        path: &PathBuf,
+        include_path: &IncludePath,
+        preprocessor: &PreprocessorConfig,
+        resolver: &PathResolver,
+    ) -> Result<SourceWithIncludes, Error> {
+        if self.path.is_none() {
+            return extract_includes(path, include_path, preprocessor, resolver);
+        }
+
+        let metadata = fs::metadata(path).map_err(|source| Error::FileIOError {
+            source,
+            path: path.clone(),
+            message: "metadata",
+        })?;
+        let mtime = metadata.modified().ok();
+        let key = (
+            path.clone(),
+            stable_hash(&(
+                include_path,
+                preprocessor,
+                resolver.mode(),
+                resolver.generated_roots(),
+            )),
+        );
+
+        self.used.write().unwrap().insert(key.clone());
+
+        let content_hash = {
+            let entries = self.entries.read().unwrap();
+            match entries.get(&key) {
+                Some(entry) if entry.size == metadata.len() && entry.mtime == mtime => {
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    return Ok(entry.source.clone());
+                }
+                Some(entry) if entry.size == metadata.len() => {
+                    // touched, however possibly with the same content
+                    let content_hash = Self::content_hash(path)?;
+                    if content_hash == entry.content_hash {
+                        let source = entry.source.clone();
+                        drop(entries);
+                        self.hits.fetch_add(1, Ordering::Relaxed);
+                        if let Some(entry) = self.entries.write().unwrap().get_mut(&key) {
+                            entry.mtime = mtime;
+                        }
+                        return Ok(source);
+                    }
+                    content_hash
+                }
+                _ => Self::content_hash(path)?,
+            }
+        };
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let source = extract_includes(path, include_path, preprocessor, resolver)?;
+
+        self.entries.write().unwrap().insert(
+            key.clone(),
+            CacheEntry {
+                fingerprint: key.1,
+                mtime,
+                size: metadata.len(),
+                content_hash,
+                source: source.clone(),
+            },
+        );
+
+        Ok(source)
+    }
+
+    fn content_hash(path: &Path) -> Result<u64, Error> {
+        let data = fs::read(path).map_err(|source| Error::FileIOError {
+            source,
+            path: path.into(),
+            message: "read",
+        })?;
+        let mut hasher = Fnv64::default();
+        hasher.write(&data);
+        Ok(hasher.finish())
+    }
+
+    /// Write the cache back to where it was loaded from (if caching is enabled).
+    pub fn save(&self) -> Result<(), Error> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+
+        info!(
+            target: "scan-cache",
+            "{} cache hits, {} misses",
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed)
+        );
+
+        let used = self.used.read().unwrap();
+        let mut entries = self
+            .entries
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(key, _)| used.contains(*key))
+            .map(|(_, entry)| entry.clone())
+            .collect::<Vec<_>>();
+        entries
+            .sort_by(|a, b| (&a.source.path, a.fingerprint).cmp(&(&b.source.path, b.fingerprint)));
+
+        let data = serde_json::to_string(&CacheFile {
+            version: CACHE_VERSION.into(),
+            entries,
+        })
+        .map_err(|e| Error::Internal {
+            message: format!("Failed to serialize the cache: {:?}", e),
+        })?;
+
+        fs::write(path, data).map_err(|source| Error::FileIOError {
+            source,
+            path: path.clone(),
+            message: "write",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dependencies::test_util::TempTree;
+
+    #[test]
+    fn test_fnv() {
+        // FNV-1a reference values
+        let mut hasher = Fnv64::default();
+        assert_eq!(hasher.finish(), 0xcbf29ce484222325);
+        hasher.write(b"a");
+        assert_eq!(hasher.finish(), 0xaf63dc4c8601ec8c);
+    }
+
+    #[test]
+    fn test_cache_roundtrip() {
+        let dir = TempTree::new(&[("a.h", ""), ("a.cpp", "#include \"a.h\"\n")]);
+        let source = dir.join("a.cpp");
+        let cache_file = dir.join("cache.json");
+
+        let include_path = IncludePath::default();
+        let config = PreprocessorConfig::default();
+        let resolver = PathResolver::default();
+
+        let cache = ScanCache::load(&cache_file);
+        let first = cache
+            .extract_includes(&source, &include_path, &config, &resolver)
+            .unwrap();
+        assert_eq!(cache.misses.load(Ordering::Relaxed), 1);
+        cache.save().unwrap();
+
+        let cache = ScanCache::load(&cache_file);
+        let second = cache
+            .extract_includes(&source, &include_path, &config, &resolver)
+            .unwrap();
+        assert_eq!(first, second);
+        assert_eq!(cache.hits.load(Ordering::Relaxed), 1);
+
+        // Different preprocessor configuration is a separate entry
+        let mut config = config.clone();
+        config.defines.define("FOO", "1");
+        cache
+            .extract_includes(&source, &include_path, &config, &resolver)
+            .unwrap();
+        assert_eq!(cache.misses.load(Ordering::Relaxed), 1);
+        cache.save().unwrap();
+        assert_eq!(
+            ScanCache::load(&cache_file).entries.read().unwrap().len(),
+            2
+        );
+
+        // Entries not used by a run are not saved again
+        let cache = ScanCache::load(&cache_file);
+        cache
+            .extract_includes(&source, &include_path, &config, &resolver)
+            .unwrap();
+        assert_eq!(cache.hits.load(Ordering::Relaxed), 1);
+        cache.save().unwrap();
+        assert_eq!(
+            ScanCache::load(&cache_file).entries.read().unwrap().len(),
+            1
+        );
+    }
+}
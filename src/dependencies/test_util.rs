@@ -0,0 +1,45 @@
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// A temporary directory for tests, removed with its content when dropped
+/// (including when a test panics).
+///
+/// Dereferences to the canonical path of the directory.
+pub struct TempTree {
+    _dir: tempfile::TempDir,
+    path: PathBuf,
+}
+
+impl TempTree {
+    /// Create a temporary directory containing `files` (path and content)
+    pub fn new(files: &[(&str, &str)]) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let path = std::fs::canonicalize(dir.path()).unwrap();
+        let tree = Self { _dir: dir, path };
+        for (name, content) in files {
+            tree.write(name, content);
+        }
+        tree
+    }
+
+    /// Write `content` to the file `name`, creating its parent directories
+    pub fn write(&self, name: &str, content: &str) {
+        let path = self.path.join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+}
+
+impl Deref for TempTree {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for TempTree {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
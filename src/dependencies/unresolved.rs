@@ -0,0 +1,484 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use super::cparse::SourceWithIncludes;
+
+/// C and C++ standard library headers, considered system headers by default
+const STANDARD_HEADERS: &[&str] = &[
+    // C
+    "assert.h",
+    "complex.h",
+    "ctype.h",
+    "errno.h",
+    "fenv.h",
+    "float.h",
+    "inttypes.h",
+    "iso646.h",
+    "limits.h",
+    "locale.h",
+    "math.h",
+    "setjmp.h",
+    "signal.h",
+    "stdalign.h",
+    "stdarg.h",
+    "stdatomic.h",
+    "stdbit.h",
+    "stdbool.h",
+    "stdckdint.h",
+    "stddef.h",
+    "stdint.h",
+    "stdio.h",
+    "stdlib.h",
+    "stdnoreturn.h",
+    "string.h",
+    "tgmath.h",
+    "threads.h",
+    "time.h",
+    "uchar.h",
+    "wchar.h",
+    "wctype.h",
+    // C++
+    "algorithm",
+    "any",
+    "array",
+    "atomic",
+    "barrier",
+    "bit",
+    "bitset",
+    "cassert",
+    "cctype",
+    "cerrno",
+    "cfenv",
+    "cfloat",
+    "charconv",
+    "chrono",
+    "cinttypes",
+    "climits",
+    "clocale",
+    "cmath",
+    "codecvt",
+    "compare",
+    "complex",
+    "concepts",
+    "condition_variable",
+    "coroutine",
+    "csetjmp",
+    "csignal",
+    "cstdarg",
+    "cstddef",
+    "cstdint",
+    "cstdio",
+    "cstdlib",
+    "cstring",
+    "ctime",
+    "cuchar",
+    "cwchar",
+    "cwctype",
+    "deque",
+    "exception",
+    "execution",
+    "expected",
+    "filesystem",
+    "flat_map",
+    "flat_set",
+    "format",
+    "forward_list",
+    "fstream",
+    "functional",
+    "future",
+    "generator",
+    "initializer_list",
+    "iomanip",
+    "ios",
+    "iosfwd",
+    "iostream",
+    "istream",
+    "iterator",
+    "latch",
+    "limits",
+    "list",
+    "locale",
+    "map",
+    "mdspan",
+    "memory",
+    "memory_resource",
+    "mutex",
+    "new",
+    "numbers",
+    "numeric",
+    "optional",
+    "ostream",
+    "print",
+    "queue",
+    "random",
+    "ranges",
+    "ratio",
+    "regex",
+    "scoped_allocator",
+    "semaphore",
+    "set",
+    "shared_mutex",
+    "source_location",
+    "span",
+    "spanstream",
+    "sstream",
+    "stack",
+    "stacktrace",
+    "stdexcept",
+    "stdfloat",
+    "stop_token",
+    "streambuf",
+    "string",
+    "string_view",
+    "syncstream",
+    "system_error",
+    "thread",
+    "tuple",
+    "type_traits",
+    "typeindex",
+    "typeinfo",
+    "unordered_map",
+    "unordered_set",
+    "utility",
+    "valarray",
+    "variant",
+    "vector",
+    "version",
+];
+
+/// Decides which unresolved includes are (most likely) system headers.
+///
+/// An include is a system header if its name is a known standard header,
+/// matches a configured name (or a configured directory prefix ending in `/`),
+/// or exists in any of the system include directories.
+#[derive(Debug, Default)]
+pub struct SystemHeaders {
+    names: HashSet<String>,
+    prefixes: Vec<String>,
+    directories: Vec<PathBuf>,
+}
+
+impl SystemHeaders {
+    pub fn new<N, D>(names: N, directories: D) -> Self
+    where
+        N: IntoIterator<Item = String>,
+        D: IntoIterator<Item = PathBuf>,
+    {
+        let mut result = Self {
+            names: STANDARD_HEADERS.iter().map(|s| s.to_string()).collect(),
+            directories: directories.into_iter().collect(),
+            ..Default::default()
+        };
+
+        for name in names {
+            if name.ends_with('/') {
+                result.prefixes.push(name);
+            } else {
+                result.names.insert(name);
+            }
+        }
+
+        result
+    }
+
+    pub fn is_system(&self, name: &Path) -> bool {
+        let name_str = name.to_string_lossy();
+
+        self.names.contains(name_str.as_ref())
+            || self.prefixes.iter().any(|p| name_str.starts_with(p))
+            || self.directories.iter().any(|d| d.join(name).exists())
+    }
+}
+
+/// Output format of an [`UnresolvedReport`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ReportFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UnresolvedEntry {
+    pub line: usize,
+    pub column: usize,
+
+    /// The include as written
+    pub spelling: String,
+
+    /// What the include named: the file name, or the expansion of a computed include
+    pub name: Option<String>,
+
+    pub system: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UnresolvedFile {
+    pub path: PathBuf,
+    pub includes: Vec<UnresolvedEntry>,
+}
+
+/// Includes that could not be resolved, grouped by file
+#[derive(Debug, Serialize)]
+pub struct UnresolvedReport {
+    pub files: Vec<UnresolvedFile>,
+}
+
+impl UnresolvedReport {
+    pub fn new<'a, I>(sources: I, system_headers: &SystemHeaders) -> Self
+    where
+        I: IntoIterator<Item = &'a SourceWithIncludes>,
+    {
+        let mut files = sources
+            .into_iter()
+            .map(|source| {
+                let mut includes = source
+                    .unresolved
+                    .iter()
+                    .map(|u| UnresolvedEntry {
+                        line: u.directive.line,
+                        column: u.directive.column,
+                        spelling: u.directive.spelling.clone(),
+                        name: Some(u.name.to_string_lossy().into()),
+                        system: system_headers.is_system(&u.name),
+                    })
+                    .chain(source.unresolved_computed.iter().map(|u| UnresolvedEntry {
+                        line: u.line,
+                        column: 0,
+                        spelling: format!("#include {}", u.expression),
+                        name: u.expansion.clone(),
+                        system: false,
+                    }))
+                    .collect::<Vec<_>>();
+                includes.sort_by_key(|e| (e.line, e.column));
+
+                UnresolvedFile {
+                    path: source.path.clone(),
+                    includes,
+                }
+            })
+            .filter(|f| !f.includes.is_empty())
+            .collect::<Vec<_>>();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Self { files }
+    }
+
+    /// The report as written to a file in the given `format`
+    pub fn render(&self, format: ReportFormat) -> serde_json::Result<String> {
+        match format {
+            ReportFormat::Text => Ok(self.to_string()),
+            ReportFormat::Json => serde_json::to_string_pretty(self),
+        }
+    }
+
+    fn entries(&self) -> impl Iterator<Item = &UnresolvedEntry> {
+        self.files.iter().flat_map(|f| f.includes.iter())
+    }
+}
+
+impl Display for UnresolvedReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let total = self.entries().count();
+        let system = self.entries().filter(|e| e.system).count();
+
+        writeln!(
+            f,
+            "Unresolved includes: {} ({} system headers, {} other)",
+            total,
+            system,
+            total - system
+        )?;
+
+        for file in self.files.iter() {
+            if file.includes.iter().all(|e| e.system) {
+                continue;
+            }
+            writeln!(f, "\n{}", file.path.display())?;
+            for e in file.includes.iter().filter(|e| !e.system) {
+                write!(f, "  {}:{}: {}", file.path.display(), e.line, e.spelling)?;
+                match (e.name.as_ref(), e.spelling.contains(['"', '<'])) {
+                    (Some(name), false) => writeln!(f, " (expands to {})", name)?,
+                    _ => writeln!(f)?,
+                }
+            }
+        }
+
+        if system > 0 {
+            let mut counts = BTreeMap::new();
+            for name in self.entries().filter(|e| e.system).flat_map(|e| &e.name) {
+                *counts.entry(name.as_str()).or_insert(0) += 1;
+            }
+
+            writeln!(f, "\nSystem headers not found:")?;
+            for (name, count) in counts {
+                writeln!(f, "  {} ({} includes)", name, count)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dependencies::cparse::{
+        IncludeDirective, IncludeStyle, UnresolvedComputedInclude, UnresolvedInclude,
+    };
+    use crate::dependencies::test_util::TempTree;
+
+    fn unresolved(line: usize, name: &str, quoted: bool) -> UnresolvedInclude {
+        let (spelling, style) = match quoted {
+            true => (format!("#include \"{}\"", name), IncludeStyle::Quoted),
+            false => (format!("#include <{}>", name), IncludeStyle::Angled),
+        };
+        UnresolvedInclude {
+            name: name.into(),
+            directive: IncludeDirective {
+                line,
+                column: 1,
+                spelling,
+                style,
+            },
+        }
+    }
+
+    /// A report for two sources, with headers in `dir/sys` being system headers
+    fn report(dir: &Path) -> UnresolvedReport {
+        let sources = [
+            SourceWithIncludes {
+                path: "/src/b.cpp".into(),
+                includes: vec![],
+                optional_includes: vec![],
+                unresolved: vec![
+                    unresolved(3, "local.h", true),
+                    unresolved(1, "vector", false),
+                ],
+                unresolved_computed: vec![UnresolvedComputedInclude {
+                    line: 2,
+                    expression: "PLATFORM_HEADER".into(),
+                    expansion: Some("\"platform.h\"".into()),
+                }],
+            },
+            SourceWithIncludes {
+                path: "/src/a.cpp".into(),
+                includes: vec![],
+                optional_includes: vec![],
+                unresolved: vec![
+                    unresolved(1, "vector", false),
+                    unresolved(2, "foo/bar.h", false),
+                ],
+                unresolved_computed: vec![],
+            },
+            SourceWithIncludes {
+                path: "/src/resolved.cpp".into(),
+                includes: vec![],
+                optional_includes: vec![],
+                unresolved: vec![],
+                unresolved_computed: vec![],
+            },
+        ];
+
+        UnresolvedReport::new(sources.iter(), &SystemHeaders::new([], [dir.join("sys")]))
+    }
+
+    #[test]
+    fn test_system_headers() {
+        let dir = TempTree::new(&[("sys/foo/bar.h", "")]);
+        let headers = SystemHeaders::new(
+            ["jni.h".to_string(), "linux/".to_string()],
+            [dir.join("sys")],
+        );
+
+        assert!(headers.is_system(Path::new("vector")));
+        assert!(headers.is_system(Path::new("stdio.h")));
+        assert!(headers.is_system(Path::new("jni.h")));
+        assert!(headers.is_system(Path::new("linux/types.h")));
+        assert!(headers.is_system(Path::new("foo/bar.h")));
+        assert!(!headers.is_system(Path::new("linux.h")));
+        assert!(!headers.is_system(Path::new("foo/baz.h")));
+    }
+
+    #[test]
+    fn test_render_text() {
+        let dir = TempTree::new(&[("sys/foo/bar.h", "")]);
+
+        assert_eq!(
+            report(&dir).render(ReportFormat::Text).unwrap(),
+            concat!(
+                "Unresolved includes: 5 (3 system headers, 2 other)\n",
+                "\n",
+                "/src/b.cpp\n",
+                "  /src/b.cpp:2: #include PLATFORM_HEADER (expands to \"platform.h\")\n",
+                "  /src/b.cpp:3: #include \"local.h\"\n",
+                "\n",
+                "System headers not found:\n",
+                "  foo/bar.h (1 includes)\n",
+                "  vector (2 includes)\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_render_json() {
+        let dir = TempTree::new(&[("sys/foo/bar.h", "")]);
+
+        let rendered = report(&dir).render(ReportFormat::Json).unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&rendered).unwrap(),
+            serde_json::json!({
+                "files": [
+                    {
+                        "path": "/src/a.cpp",
+                        "includes": [
+                            {
+                                "line": 1,
+                                "column": 1,
+                                "spelling": "#include <vector>",
+                                "name": "vector",
+                                "system": true
+                            },
+                            {
+                                "line": 2,
+                                "column": 1,
+                                "spelling": "#include <foo/bar.h>",
+                                "name": "foo/bar.h",
+                                "system": true
+                            }
+                        ]
+                    },
+                    {
+                        "path": "/src/b.cpp",
+                        "includes": [
+                            {
+                                "line": 1,
+                                "column": 1,
+                                "spelling": "#include <vector>",
+                                "name": "vector",
+                                "system": true
+                            },
+                            {
+                                "line": 2,
+                                "column": 0,
+                                "spelling": "#include PLATFORM_HEADER",
+                                "name": "\"platform.h\"",
+                                "system": false
+                            },
+                            {
+                                "line": 3,
+                                "column": 1,
+                                "spelling": "#include \"local.h\"",
+                                "name": "local.h",
+                                "system": false
+                            }
+                        ]
+                    }
+                ]
+            })
+        );
+    }
+}
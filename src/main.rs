@@ -1,7 +1,11 @@
 use camino::Utf8PathBuf;
 use clap::Parser;
 use color_eyre::{eyre::WrapErr, Result};
-use include_graph::dependencies::configfile::build_graph;
+use include_graph::dependencies::{
+    canonicalize::PathResolver,
+    configfile::{build_graph, BuildOptions},
+    unresolved::ReportFormat,
+};
 use tracing::level_filters::LevelFilter;
 use tracing_subscriber::{EnvFilter, FmtSubscriber};
 
@@ -16,6 +20,37 @@ struct Args {
     /// Where the dot file output should go. Defaults to stdout if not set.
     #[arg(short, long)]
     output: Option<Utf8PathBuf>,
+
+    /// How many files to parse in parallel. Defaults to the number of CPUs.
+    #[arg(short, long)]
+    jobs: Option<usize>,
+
+    /// File where scan results are cached across runs. Unchanged files are not
+    /// parsed again.
+    #[arg(long)]
+    cache: Option<Utf8PathBuf>,
+
+    /// File where includes that could not be resolved are reported, separating
+    /// system headers from the rest.
+    #[arg(long)]
+    unresolved_report: Option<Utf8PathBuf>,
+
+    /// Format of the unresolved include report
+    #[arg(long, value_enum, default_value_t)]
+    unresolved_report_format: ReportFormat,
+
+    /// Set a config variable (as `NAME=VALUE`), overriding any assignment of
+    /// it in the config files. May be repeated.
+    #[arg(short = 'D', value_name = "NAME=VALUE", value_parser = parse_variable)]
+    define: Vec<(String, String)>,
+}
+
+/// Parse a `NAME=VALUE` variable definition
+fn parse_variable(definition: &str) -> Result<(String, String), String> {
+    match definition.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.into(), value.into())),
+        _ => Err(format!("expected NAME=VALUE, got {:?}", definition)),
+    }
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -35,7 +70,18 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let data = std::fs::read_to_string(&args.config)
         .wrap_err_with(|| format!("Failed to open {:?}", &args.config))?;
-    let graph = build_graph(&data)?;
+    let graph = build_graph(
+        &data,
+        &BuildOptions {
+            jobs: args.jobs,
+            cache: args.cache.map(Into::into),
+            unresolved_report: args.unresolved_report.map(Into::into),
+            report_format: args.unresolved_report_format,
+            config_path: Some(args.config.clone().into()),
+            variables: args.define,
+        },
+        &PathResolver::default(),
+    )?;
 
     match args.output {
         Some(path) => {